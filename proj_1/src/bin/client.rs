@@ -0,0 +1,231 @@
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use clap::Parser;
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use proj_1::db_errors::MyDatabaseError;
+use proj_1::models::output_format::OutputFormat;
+use proj_1::models::utilities::quote_escaped;
+use proj_1::network::framing::{read_message, write_frame};
+use proj_1::network::udp_protocol::UdpRequester;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Interactive client for the database servers", long_about = None)]
+struct Args {
+    /// Server address
+    #[arg(long, default_value = "127.0.0.1")]
+    host: String,
+    /// Server port, 8889 for TCP and 8888 for UDP by default
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// Talk to the UDP server instead of the TCP one
+    #[arg(short, long, default_value_t = false)]
+    udp: bool,
+    /// Output format requested from the server at startup
+    #[arg(short, long, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    /// User to log in as
+    #[arg(long)]
+    user: Option<String>,
+    /// Password of the user, asked for when not given
+    #[arg(long)]
+    password: Option<String>,
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpRequester),
+}
+impl Connection {
+    fn open(args: &Args) -> Result<Self, MyDatabaseError> {
+        let port = args.port.unwrap_or(if args.udp { 8888 } else { 8889 });
+        let Some(server) = (args.host.as_str(), port).to_socket_addrs()?.next() else {
+            return Err(MyDatabaseError::InvalidCommandFormat("--host"));
+        };
+        if args.udp {
+            let bind_addr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" };
+            let socket = UdpSocket::bind(bind_addr)?;
+            Ok(Connection::Udp(UdpRequester::new(socket, server, Duration::from_millis(500), 10)?))
+        } else {
+            Ok(Connection::Tcp(TcpStream::connect(server)?))
+        }
+    }
+    fn request(&mut self, command: &str) -> Result<String, MyDatabaseError> {
+        match self {
+            Connection::Tcp(stream) => {
+                write_frame(stream, command.as_bytes())?;
+                match read_message(stream)? {
+                    Some(payload) => Ok(String::from_utf8_lossy(&payload).into_owned()),
+                    None => Err(MyDatabaseError::IoError(std::io::Error::new(std::io::ErrorKind::ConnectionAborted, "server closed the connection"))),
+                }
+            },
+            Connection::Udp(requester) => requester.request(command),
+        }
+    }
+}
+
+/// Quote of a string literal left open at the end of the text, either `"` or `'`.
+/// Inside a literal a backslash escapes the next character, as in quoted passwords
+fn open_quote(text: &str) -> Option<char> {
+    let mut quote = None;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(_), '\\') => {
+                chars.next();
+            },
+            (Some(open), c) if c == open => quote = None,
+            _ => {},
+        }
+    }
+    quote
+}
+
+/// A statement is complete when its last line ends with ';' outside of quotes
+fn is_statement_complete(statement: &str) -> bool {
+    statement.trim_end().ends_with(';') && open_quote(statement).is_none()
+}
+
+/// Adds a typed line to the statement. Lines continuing a string literal are kept as typed,
+/// so the literal gets its line breaks, other lines are joined with a space like words of one line
+fn append_line(statement: &mut String, line: &str) {
+    if statement.is_empty() {
+        statement.push_str(line.trim());
+    } else if open_quote(statement).is_some() {
+        statement.push('\n');
+        statement.push_str(line.trim_end_matches('\r'));
+    } else {
+        statement.push(' ');
+        statement.push_str(line.trim());
+    }
+}
+
+/// Statements with passwords are kept out of the history file
+fn is_secret(statement: &str) -> bool {
+    statement.starts_with("LOGIN ") || statement.starts_with("CREATE USER ")
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".proj_1_history"))
+}
+
+fn print_response(response: &str, elapsed: Duration) {
+    let response = response.trim_end();
+    if !response.is_empty() {
+        println!("{}", response);
+    }
+    println!("({:.3} s)", elapsed.as_secs_f64());
+}
+
+fn main() {
+    let args = Args::parse();
+    let mut connection = match Connection::open(&args) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Failed to connect to {}: {}", args.host, e);
+            return;
+        }
+    };
+    let mut editor = match DefaultEditor::new() {
+        Ok(e) => e,
+        Err(e) => {
+            println!("Failed to start line editor: {}", e);
+            return;
+        }
+    };
+
+    if let Some(user) = &args.user {
+        let password = match &args.password {
+            Some(p) => p.clone(),
+            None => match rpassword::prompt_password("Password: ") {
+                Ok(p) => p,
+                Err(e) => {
+                    println!("Failed to read password: {}", e);
+                    return;
+                }
+            },
+        };
+        match connection.request(&format!("LOGIN {} {}", user, quote_escaped(&password))) {
+            Ok(response) => print!("{}", response),
+            Err(e) => println!("Failed to log in: {}", e),
+        }
+    }
+    if let Err(e) = connection.request(&format!("SET FORMAT {}", args.format)) {
+        println!("Failed to set output format: {}", e);
+    }
+    let history = history_path();
+    if let Some(path) = &history {
+        let _ = editor.load_history(path); // there is no history on first run
+    }
+    println!("Connected to {}. End statements with ';', type \\q to quit", args.host);
+
+    let mut statement = String::new();
+    loop {
+        let prompt = if statement.is_empty() { "db> " } else { "..> " };
+        let line = match editor.readline(prompt) {
+            Ok(l) => l,
+            Err(ReadlineError::Interrupted) => {
+                statement.clear();
+                continue;
+            },
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("Failed to read line: {}", e);
+                break;
+            }
+        };
+        if statement.is_empty() && matches!(line.trim(), "\\q" | "exit" | "quit") {
+            break;
+        }
+        if line.trim().is_empty() && open_quote(&statement).is_none() {
+            continue;
+        }
+        append_line(&mut statement, &line);
+        if !is_statement_complete(&statement) {
+            continue;
+        }
+
+        if !is_secret(&statement) {
+            let _ = editor.add_history_entry(statement.as_str());
+        }
+        let command = statement.trim_end().trim_end_matches(';').trim().to_string();
+        statement.clear();
+        if command.is_empty() {
+            continue;
+        }
+        let started = Instant::now();
+        match connection.request(&command) {
+            Ok(response) => print_response(&response, started.elapsed()),
+            Err(e) => println!("Request failed: {}", e),
+        }
+    }
+
+    if let Some(path) = &history
+        && let Err(e) = editor.save_history(path) {
+        println!("Failed to save history: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn statements_are_built_from_several_lines() {
+        let mut statement = String::new();
+        for line in ["SELECT", "  id FROM t", "WHERE name = 'a", "  b' ;"] {
+            append_line(&mut statement, line);
+        }
+        assert_eq!(statement, "SELECT id FROM t WHERE name = 'a\n  b' ;");
+        assert!(is_statement_complete(&statement));
+
+        let mut statement = String::new();
+        for line in ["INSERT id = 1, name = \"x;", "y\" INTO t"] {
+            append_line(&mut statement, line);
+        }
+        assert_eq!(statement, "INSERT id = 1, name = \"x;\ny\" INTO t");
+        assert!(!is_statement_complete(&statement));
+    }
+}
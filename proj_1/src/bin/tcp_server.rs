@@ -0,0 +1,167 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
+use clap::{ArgGroup, Parser};
+use proj_1::models::{db_structure::{AnyDatabase, DatabaseHandle}, output_format::OutputFormat, pager::PAGE_SIZE, session::Session};
+use proj_1::models::auth::{execute_with_auth, generate_password, write_password_file, UserRegistry};
+use proj_1::network::framing::{read_frame, write_message};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None,
+group(
+        ArgGroup::new("mode")
+            .required(true)    // must have one of them
+            .args(["int", "string"]),
+    ))]
+struct Args {
+    /// Use database with integer key
+    #[arg(short, long = "Int", default_value_t=false)]
+    int: bool,
+    /// Use database with string key
+    #[arg(short, long = "String", default_value_t=false)]
+    string: bool,
+    /// Output format for query results: table, csv, json or markdown
+    #[arg(short, long, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    /// Port to listen on
+    #[arg(short, long, default_value_t = 8889)]
+    port: u16,
+    /// Seconds after which a silent client is disconnected and its session dropped
+    #[arg(long, default_value_t = 600)]
+    idle_timeout: u64,
+    /// Password of the "admin" account, a random one is generated when not given
+    #[arg(long)]
+    admin_password: Option<String>,
+    /// File the generated admin password is written to, readable only by its owner
+    #[arg(long, default_value = "admin_password.txt")]
+    admin_password_file: PathBuf,
+    /// Keep tables in this database file, it is created if it doesn't exist
+    #[arg(long)]
+    file: Option<PathBuf>,
+    /// Megabytes of database file pages cached in memory
+    #[arg(long, default_value_t = 64)]
+    cache_mb: usize,
+}
+
+/// Everything clients share. Tables and the user registry have their own locks,
+/// so clients querying different tables don't wait for each other
+#[derive(Clone)]
+struct ServerState {
+    context_db: DatabaseHandle,
+    users: Arc<RwLock<UserRegistry>>,
+}
+
+/// Serves one client until it disconnects or stays idle for too long.
+/// Every frame is one command, long responses take several frames. The session lives as long as the connection
+fn handle_client(mut stream: TcpStream, peer: SocketAddr, state: ServerState, format: OutputFormat, idle_timeout: Duration) -> io::Result<()> {
+    stream.set_read_timeout(Some(idle_timeout))?;
+    let mut session = Session::new(format);
+    loop {
+        let payload = match read_frame(&mut stream) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => {
+                println!("Client {} was idle for too long, closing its session", peer);
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        };
+        let msg = String::from_utf8_lossy(&payload);
+        if msg.trim().is_empty() {
+            write_message(&mut stream, b"")?;
+            continue;
+        }
+
+        let mut response_buf = String::new();
+        match execute_with_auth(msg.as_ref(), &state.context_db, &state.users, &mut session) {
+            Ok(output) => {
+                output.render(&session, &mut response_buf);
+                response_buf.push_str("Command executed successfully\n");
+            },
+            Err(e) => response_buf.push_str(&format!("{}\n", e)),
+        }
+        write_message(&mut stream, response_buf.as_bytes())?;
+    }
+    println!("Client {} disconnected", peer);
+    Ok(())
+}
+
+fn main() {
+    let args = Args::parse();
+    if args.int {
+        println!("Using integer key database");
+    } else if args.string {
+        println!("Using string key database");
+    } else {
+        println!("No database type specified");
+        return;
+    }
+    let cache_pages = args.cache_mb * 1024 * 1024 / PAGE_SIZE;
+    let context_db = match AnyDatabase::open(args.int, args.file.as_deref(), cache_pages) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Cannot open the database file: {}", e);
+            return;
+        },
+    };
+    let admin_password = match args.admin_password {
+        Some(password) => password,
+        None => match generate_password() {
+            Ok(password) => match write_password_file(&args.admin_password_file, &password) {
+                Ok(()) => {
+                    println!("Generated password for user admin written to {}", args.admin_password_file.display());
+                    password
+                },
+                Err(e) => {
+                    println!("Failed to write admin password: {}", e);
+                    return;
+                }
+            },
+            Err(e) => {
+                println!("Failed to generate admin password: {}", e);
+                return;
+            }
+        },
+    };
+    let mut users = UserRegistry::new();
+    if let Err(e) = users.create_user("admin", &admin_password, true) {
+        println!("Failed to create admin account: {}", e);
+        return;
+    }
+    let state = ServerState {
+        context_db: DatabaseHandle::new(context_db),
+        users: Arc::new(RwLock::new(users)),
+    };
+
+    let Ok(listener) = TcpListener::bind(("0.0.0.0", args.port)) else {
+        println!("Failed to bind to port {}", args.port);
+        return;
+    };
+    println!("Server listening on 0.0.0.0:{}", args.port);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(s) => s,
+            Err(e) => {
+                println!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let Ok(peer) = stream.peer_addr() else {
+            println!("Failed to get client address");
+            continue;
+        };
+        println!("Client {} connected", peer);
+        let state = state.clone();
+        let format = args.format;
+        let idle_timeout = Duration::from_secs(args.idle_timeout);
+        thread::spawn(move || {
+            if let Err(e) = handle_client(stream, peer, state, format, idle_timeout) {
+                println!("Connection with {} closed: {}", peer, e);
+            }
+        });
+    }
+}
@@ -1,6 +1,14 @@
-use std::net::UdpSocket;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
 use clap::{ArgGroup, Parser};
-use proj_1::models::{commands::AnyCommand, db_structure::{AnyDatabase, Database}};
+use proj_1::models::{db_structure::AnyDatabase, output_format::OutputFormat, pager::PAGE_SIZE, session::SessionManager};
+use proj_1::db_errors::MyDatabaseError;
+use proj_1::models::auth::{execute_with_auth, generate_password, generate_session_token, write_password_file, UserRegistry};
+use proj_1::network::udp_protocol::{fragment, is_protocol_datagram, split_session_token, Packet, PacketKind, Reassembler, SESSION_TOKEN_LINE};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None,
@@ -16,46 +24,317 @@ struct Args {
     /// Use database with string key
     #[arg(short, long = "String", default_value_t=false)]
     string: bool,
+    /// Output format for query results: table, csv, json or markdown
+    #[arg(short, long, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    /// Seconds after which a silent client's session (history and settings) is dropped
+    #[arg(long, default_value_t = 600)]
+    idle_timeout: u64,
+    /// Password of the "admin" account, a random one is generated when not given
+    #[arg(long)]
+    admin_password: Option<String>,
+    /// File the generated admin password is written to, readable only by its owner
+    #[arg(long, default_value = "admin_password.txt")]
+    admin_password_file: PathBuf,
+    /// Keep tables in this database file, it is created if it doesn't exist
+    #[arg(long)]
+    file: Option<PathBuf>,
+    /// Megabytes of database file pages cached in memory
+    #[arg(long, default_value_t = 64)]
+    cache_mb: usize,
 }
+/// Unfinished requests are dropped after this time
+const PENDING_REQUEST_TTL: Duration = Duration::from_secs(30);
+/// Unfinished requests kept for one address and for all of them, fragments of further ones are dropped
+const MAX_PENDING_PER_PEER: usize = 8;
+const MAX_PENDING: usize = 1024;
+/// Bytes of unfinished requests kept in total, the oldest ones are dropped to make room
+const MAX_PENDING_BYTES: usize = 16 * 1024 * 1024;
+/// Responses are kept this long, so repeated requests and resend packets can be answered
+const RESPONSE_TTL: Duration = Duration::from_secs(120);
+/// Responses and their bytes kept in total, the oldest ones are dropped to make room
+const MAX_RESPONSES: usize = 4096;
+const MAX_RESPONSE_BYTES: usize = 64 * 1024 * 1024;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+struct PendingRequest {
+    reassembler: Reassembler,
+    last_activity: Instant,
+}
+
+struct CachedResponse {
+    datagrams: Vec<Vec<u8>>,
+    bytes: usize,
+    created: Instant,
+}
+
+struct UdpServer {
+    socket: UdpSocket,
+    context_db: AnyDatabase,
+    users: RwLock<UserRegistry>,
+    sessions: SessionManager<String>, // by session token, source addresses can be spoofed
+    pending: HashMap<(SocketAddr, u64), PendingRequest>,
+    pending_bytes: usize,
+    responses: HashMap<(SocketAddr, u64), CachedResponse>,
+    response_bytes: usize,
+    last_sweep: Instant,
+}
+impl UdpServer {
+    /// LOGIN starts a new session and answers with its token, other commands need the token of a session
+    fn execute(&mut self, msg: &str) -> String {
+        let mut response_buf = String::new();
+        let (token, command) = split_session_token(msg);
+        if command.trim_start().starts_with("LOGIN ") {
+            let mut session = self.sessions.new_session();
+            let logged_in = execute_with_auth(command, &self.context_db, &self.users, &mut session)
+                .and_then(|output| Ok((output, generate_session_token()?)));
+            match logged_in {
+                Ok((output, token)) => {
+                    output.render(&session, &mut response_buf);
+                    response_buf.push_str(&format!("{}{}\n", SESSION_TOKEN_LINE, token));
+                    response_buf.push_str("Command executed successfully\n\n");
+                    self.sessions.insert(token, session);
+                },
+                Err(e) => response_buf.push_str(&format!("{}\n", e)),
+            }
+            return response_buf;
+        }
+        let Some(token) = token.map(str::to_string) else {
+            return format!("{}\n", MyDatabaseError::InvalidSessionToken);
+        };
+        let Some(session) = self.sessions.get(&token) else {
+            return format!("{}\n", MyDatabaseError::InvalidSessionToken);
+        };
+        match execute_with_auth(command, &self.context_db, &self.users, session) {
+            Ok(output) => {
+                output.render(session, &mut response_buf);
+                response_buf.push_str("Command executed successfully\n\n");
+            },
+            Err(e) => response_buf.push_str(&format!("{}\n", e)),
+        }
+        if session.user().is_none() {
+            self.sessions.remove(&token); // logged out
+        }
+        response_buf
+    }
+
+    fn send_datagrams<'a>(&self, datagrams: impl Iterator<Item = &'a Vec<u8>>, src: SocketAddr) {
+        for datagram in datagrams {
+            if !send_or_log(&self.socket, datagram, src) {
+                return;
+            }
+        }
+    }
+
+    fn handle_datagram(&mut self, datagram: &[u8], src: SocketAddr) {
+        if !is_protocol_datagram(datagram) {
+            // plain text command, e.g. from netcat - answered with a single datagram as before
+            let msg = String::from_utf8_lossy(datagram).into_owned();
+            let response = self.execute(&msg);
+            send_or_log(&self.socket, response.as_bytes(), src);
+            return;
+        }
+        let packet = match Packet::decode(datagram) {
+            Ok(p) => p,
+            Err(e) => {
+                println!("Dropping datagram from {}: {}", src, e);
+                return;
+            }
+        };
+        let key = (src, packet.request_id);
+        match packet.kind {
+            PacketKind::Request => {
+                if let Some(cached) = self.responses.get(&key) {
+                    // repeated request - answer again without executing it twice
+                    if packet.seq == 0 {
+                        self.send_datagrams(cached.datagrams.iter(), src);
+                    }
+                    return;
+                }
+                if !self.pending.contains_key(&key) && !self.has_room_for_request(src) {
+                    println!("Dropping request fragment from {}: too many unfinished requests", src);
+                    return;
+                }
+                self.drop_oldest_pending(packet.payload.len(), key);
+                let pending = self.pending.entry(key).or_insert_with(|| PendingRequest {
+                    reassembler: Reassembler::new(packet.total),
+                    last_activity: Instant::now(),
+                });
+                pending.last_activity = Instant::now();
+                let before = pending.reassembler.bytes();
+                let complete = pending.reassembler.add(packet);
+                self.pending_bytes += pending.reassembler.bytes() - before;
+                if !complete {
+                    return;
+                }
+                let Some(complete) = self.pending.remove(&key) else {
+                    return;
+                };
+                self.pending_bytes -= complete.reassembler.bytes();
+                let msg = String::from_utf8_lossy(&complete.reassembler.assemble()).into_owned();
+                let response = self.execute(&msg);
+                let datagrams: Vec<Vec<u8>> = match fragment(PacketKind::Response, key.1, response.as_bytes()) {
+                    Ok(packets) => packets.iter().map(Packet::encode).collect(),
+                    Err(e) => match fragment(PacketKind::Response, key.1, format!("{}\n", e).as_bytes()) {
+                        Ok(packets) => packets.iter().map(Packet::encode).collect(),
+                        Err(_) => return,
+                    },
+                };
+                self.send_datagrams(datagrams.iter(), src);
+                self.cache_response(key, datagrams);
+            },
+            PacketKind::Resend => {
+                let Some(cached) = self.responses.get(&key) else {
+                    return;
+                };
+                let missing = packet.missing_sequence_numbers();
+                self.send_datagrams(missing.iter().filter_map(|seq| cached.datagrams.get(*seq as usize)), src);
+            },
+            PacketKind::Response => println!("Unexpected response packet from {}", src),
+        }
+    }
+
+    fn has_room_for_request(&self, src: SocketAddr) -> bool {
+        let from_peer = self.pending.keys().filter(|(addr, _)| addr.ip() == src.ip()).count();
+        self.pending.len() < MAX_PENDING && from_peer < MAX_PENDING_PER_PEER
+    }
+
+    /// Drops the least recently active unfinished requests, other than the one of `key`, until `needed` more bytes fit
+    fn drop_oldest_pending(&mut self, needed: usize, key: (SocketAddr, u64)) {
+        while self.pending_bytes + needed > MAX_PENDING_BYTES {
+            let oldest = self.pending.iter()
+                .filter(|(pending_key, _)| **pending_key != key)
+                .min_by_key(|(_, pending)| pending.last_activity)
+                .map(|(pending_key, _)| *pending_key);
+            let Some(dropped) = oldest.and_then(|oldest| self.pending.remove(&oldest)) else {
+                return;
+            };
+            self.pending_bytes -= dropped.reassembler.bytes();
+        }
+    }
+
+    /// Keeps the response for repeated requests, dropping the oldest ones when there are too many.
+    /// A response larger than all the room is only sent
+    fn cache_response(&mut self, key: (SocketAddr, u64), datagrams: Vec<Vec<u8>>) {
+        let bytes: usize = datagrams.iter().map(Vec::len).sum();
+        if bytes > MAX_RESPONSE_BYTES {
+            return;
+        }
+        while self.responses.len() >= MAX_RESPONSES || self.response_bytes + bytes > MAX_RESPONSE_BYTES {
+            let oldest = self.responses.iter().min_by_key(|(_, response)| response.created).map(|(key, _)| *key);
+            let Some(dropped) = oldest.and_then(|oldest| self.responses.remove(&oldest)) else {
+                break;
+            };
+            self.response_bytes -= dropped.bytes;
+        }
+        self.response_bytes += bytes;
+        if let Some(replaced) = self.responses.insert(key, CachedResponse { datagrams, bytes, created: Instant::now() }) {
+            self.response_bytes -= replaced.bytes;
+        }
+    }
+
+    fn sweep(&mut self) {
+        if self.last_sweep.elapsed() < SWEEP_INTERVAL {
+            return;
+        }
+        self.pending.retain(|_, p| p.last_activity.elapsed() < PENDING_REQUEST_TTL);
+        self.pending_bytes = self.pending.values().map(|p| p.reassembler.bytes()).sum();
+        self.responses.retain(|_, r| r.created.elapsed() < RESPONSE_TTL);
+        self.response_bytes = self.responses.values().map(|r| r.bytes).sum();
+        let now = Instant::now();
+        let expired = self.sessions.expire_idle(now);
+        if expired > 0 {
+            println!("Dropped {} idle sessions", expired);
+        }
+        self.last_sweep = now;
+    }
+}
+
+fn send_or_log(socket: &UdpSocket, datagram: &[u8], src: SocketAddr) -> bool {
+    let sent = socket.send_to(datagram, src).is_ok();
+    if !sent {
+        println!("Failed to send response to {}", src);
+    }
+    sent
+}
+
 fn main() {
     let args = Args::parse();
-    let mut context_db: AnyDatabase;
     if args.int {
         println!("Using integer key database");
-        context_db = AnyDatabase::IntDatabase(Database::<i64>::new());
     } else if args.string {
         println!("Using string key database");
-        context_db = AnyDatabase::StringDatabase(Database::<String>::new());
     } else {
         println!("No database type specified");
         return;
     }
+    let cache_pages = args.cache_mb * 1024 * 1024 / PAGE_SIZE;
+    let context_db = match AnyDatabase::open(args.int, args.file.as_deref(), cache_pages) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Cannot open the database file: {}", e);
+            return;
+        },
+    };
+
+    let admin_password = match args.admin_password {
+        Some(password) => password,
+        None => match generate_password() {
+            Ok(password) => match write_password_file(&args.admin_password_file, &password) {
+                Ok(()) => {
+                    println!("Generated password for user admin written to {}", args.admin_password_file.display());
+                    password
+                },
+                Err(e) => {
+                    println!("Failed to write admin password: {}", e);
+                    return;
+                }
+            },
+            Err(e) => {
+                println!("Failed to generate admin password: {}", e);
+                return;
+            }
+        },
+    };
+    let mut users = UserRegistry::new();
+    if let Err(e) = users.create_user("admin", &admin_password, true) {
+        println!("Failed to create admin account: {}", e);
+        return;
+    }
 
     let Ok(socket) = UdpSocket::bind("0.0.0.0:8888") else {
         println!("Failed to bind to socket");
         return;
     };
+    // wakes up regularly to drop expired requests and responses
+    if socket.set_read_timeout(Some(SWEEP_INTERVAL)).is_err() {
+        println!("Failed to set socket timeout");
+        return;
+    }
     println!("Server listening on 0.0.0.0:8888");
-    let mut executed_commands = Vec::<String>::new();
+    let mut server = UdpServer {
+        socket,
+        context_db,
+        users: RwLock::new(users),
+        sessions: SessionManager::new(Duration::from_secs(args.idle_timeout), args.format),
+        pending: HashMap::new(),
+        pending_bytes: 0,
+        responses: HashMap::new(),
+        response_bytes: 0,
+        last_sweep: Instant::now(),
+    };
 
-    let mut buf = [0u8; 1024];
+    let mut buf = vec![0u8; 65536];
     loop {
-        let Ok((len, src)) = socket.recv_from(&mut buf) else {
-            println!("Failed to receive data");
-            continue;
-        };
-        let msg = String::from_utf8_lossy(&buf[..len]);
-
-        let mut response_buf = String::new();
-
-        match AnyCommand::create_and_execute(msg.as_ref(), &mut context_db, &mut executed_commands, &mut response_buf) {
-            Ok(_) => response_buf.push_str("Command executed successfully\n\n"),
-            Err(e) => response_buf.push_str(&format!("{}\n", e)),
-        }
-
-        let Ok(_) = socket.send_to(response_buf.as_bytes(), src) else {
-            println!("Failed to send response to {}", src);
-            continue;
+        server.sweep();
+        let (len, src) = match server.socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(_) => {
+                println!("Failed to receive data");
+                continue;
+            }
         };
+        server.handle_datagram(&buf[..len], src);
     }
-}
\ No newline at end of file
+}
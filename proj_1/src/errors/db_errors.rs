@@ -1,5 +1,12 @@
 use thiserror::Error;
 
+fn format_line_errors(errors: &[(usize, MyDatabaseError)]) -> String {
+    errors.iter()
+        .map(|(line, e)| format!("  line {}: {}", line, e))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 #[derive(Error, Debug)]
 pub enum MyDatabaseError {
     #[error("Given key is not valid in this type of database")]
@@ -47,6 +54,12 @@ pub enum MyDatabaseError {
     #[error("Error parsing where clause: {0}")]
     InvalidWhereClauseFormat(String),
 
+    #[error("Type error in '{0}': {1}")]
+    ClauseTypeError(String, String),
+
+    #[error("Cannot evaluate '{0}': {1}")]
+    ConstantEvaluationError(String, Box<MyDatabaseError>),
+
     #[error("Wrongly parsed clause: {0}")]
     WronglyParsedClause(String),
 
@@ -61,4 +74,88 @@ pub enum MyDatabaseError {
 
     #[error("Cannot divide by zero")]
     DivisionByZero,
+
+    #[error("Integer overflow in {0}")]
+    IntegerOverflow(String),
+
+    #[error("{0}: {1}")]
+    FunctionError(&'static str, String),
+
+    #[error("Negative exponent {0} for an Int power, use a Float base instead")]
+    NegativeExponent(i64),
+
+    #[error("Subqueries can only be used in the WHERE clause of a query")]
+    SubqueryNotAllowed,
+
+    #[error("Subquery '{0}' has to return exactly one row, returned {1}")]
+    ScalarSubqueryRows(String, usize),
+
+    #[error("Invalid window function '{0}': {1}")]
+    InvalidWindowFunction(String, String),
+
+    #[error("{0} needs queries with matching columns: {1}")]
+    IncompatibleSetOperation(&'static str, String),
+
+    #[error("Database file is corrupted: {0}")]
+    CorruptedFile(String),
+
+    #[error("Save file is corrupted, nothing was loaded: {0}")]
+    CorruptedSaveFile(String),
+
+    #[error("Record takes {0} bytes in the database file, at most {1} fit")]
+    RecordTooLarge(usize, usize),
+
+    #[error("Invalid table option: {0}")]
+    InvalidTableOption(String),
+
+    #[error("Unknown output format '{0}', expected one of: table, csv, json, markdown")]
+    InvalidOutputFormat(String),
+
+    #[error("Expected {0} fields, found {1}")]
+    FieldCountMismatch(usize, usize),
+
+    #[error("Malformed CSV: {0}")]
+    CsvError(#[from] csv::Error),
+
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(String),
+
+    #[error("Field '{0}' does not exist in the table")]
+    JsonUnknownField(String),
+
+    #[error("Field '{0}' is missing")]
+    JsonMissingField(String),
+
+    #[error("Field '{0}' expects {1} value, found {2}")]
+    JsonTypeMismatch(String, crate::models::db_structure::ValueType, String),
+
+    #[error("Invalid packet: {0}")]
+    InvalidPacket(String),
+
+    #[error("No response from server for request {0}")]
+    RequestTimedOut(u64),
+
+    #[error("Not logged in, use LOGIN <user> <password> first")]
+    NotLoggedIn,
+
+    #[error("Invalid user name or password")]
+    InvalidCredentials,
+
+    #[error("Unknown or missing session token, use LOGIN <user> <password> first")]
+    InvalidSessionToken,
+
+    #[error("Permission denied: {0}")]
+    PermissionDenied(String),
+
+    #[error("Unknown privilege '{0}', expected SELECT, INSERT, DELETE, DDL or ALL")]
+    UnknownPrivilege(String),
+
+    #[error("User '{0}' already exists")]
+    UserAlreadyExists(String),
+
+    #[error("User '{0}' not found")]
+    UserNotFound(String),
+
+    #[error("Import failed, no records were inserted:\n{}", format_line_errors(.0))]
+    ImportFailed(Vec<(usize, MyDatabaseError)>),
 }
\ No newline at end of file
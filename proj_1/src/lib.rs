@@ -1,3 +1,5 @@
 pub mod models;
 pub mod errors;
-pub use errors::db_errors;
\ No newline at end of file
+pub mod network;
+pub use errors::db_errors;
+pub use models::result_set::ResultSet;
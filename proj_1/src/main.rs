@@ -1,6 +1,7 @@
 use std::io;
+use std::path::PathBuf;
 use clap::{ArgGroup, Parser};
-use proj_1::models::{commands::AnyCommand, db_structure::*};
+use proj_1::models::{commands::AnyCommand, db_structure::*, output_format::OutputFormat, pager::PAGE_SIZE, session::Session};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None,
@@ -16,27 +17,41 @@ struct Args {
     /// Use database with string key
     #[arg(short, long = "String", default_value_t=false)]
     string: bool,
+    /// Output format for query results: table, csv, json or markdown
+    #[arg(short, long, default_value_t = OutputFormat::Table)]
+    format: OutputFormat,
+    /// Keep tables in this database file, it is created if it doesn't exist
+    #[arg(long)]
+    file: Option<PathBuf>,
+    /// Megabytes of database file pages cached in memory
+    #[arg(long, default_value_t = 64)]
+    cache_mb: usize,
 }
 fn main() {
     let args = Args::parse();
-    let mut context_db: AnyDatabase;
     if args.int {
         println!("Using integer key database");
-        context_db = AnyDatabase::IntDatabase(Database::<i64>::new());
     } else if args.string {
         println!("Using string key database");
-        context_db = AnyDatabase::StringDatabase(Database::<String>::new());
     } else {
         println!("No database type specified");
         return;
     }
+    let cache_pages = args.cache_mb * 1024 * 1024 / PAGE_SIZE;
+    let context_db = match AnyDatabase::open(args.int, args.file.as_deref(), cache_pages) {
+        Ok(db) => db,
+        Err(e) => {
+            println!("Cannot open the database file: {}", e);
+            return;
+        },
+    };
 
     let operators = ["AND", "OR", "!=", ">=", "<=", ">", "<", "=", "+", "-", "*", "/", "(", ")"];
     let check_str = "k4 <= 2.66 AND k3 = true OR k2 != \"some text with + and - inside\" AND k1 = 123";
     println!("Splitted: {:?}", proj_1::models::utilities::split_by_operators_preserving_quotes(check_str, &operators));
 
     // let mut context_db = AnyDatabase::IntDatabase(Database::<i64>::new());
-    let mut executed_commands = Vec::<String>::new();
+    let mut session = Session::new(args.format);
     println!("Give me COMMMAAAAAANDS");
 
     loop {
@@ -51,8 +66,11 @@ fn main() {
 
         let mut response_buf = String::new();
 
-        match AnyCommand::create_and_execute(input.as_str(), &mut context_db, &mut executed_commands, &mut response_buf) {
-            Ok(_) => response_buf.push_str("Command executed successfully\n"),
+        match AnyCommand::create_and_execute(input.as_str(), &context_db, &mut session) {
+            Ok(output) => {
+                output.render(&session, &mut response_buf);
+                response_buf.push_str("Command executed successfully\n");
+            },
             Err(e) => response_buf.push_str(&format!("{}\n", e)),
         }
         println!("{}", response_buf);
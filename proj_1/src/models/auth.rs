@@ -0,0 +1,398 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::RwLock;
+use sha2::Sha256;
+use crate::db_errors::MyDatabaseError;
+use crate::models::commands::{AnyCommand, CommandOutput};
+use crate::models::db_structure::{read_lock, write_lock, AnyDatabase};
+use crate::models::session::Session;
+use crate::models::utilities::split_escaped_quoted_prefix;
+
+#[cfg(not(test))]
+const PBKDF2_ROUNDS: u32 = 100_000;
+#[cfg(test)]
+const PBKDF2_ROUNDS: u32 = 1_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+/// Table name used in GRANT/REVOKE to mean every table, including ones created later
+pub const ALL_TABLES: &str = "*";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Privilege {
+    Select,
+    Insert,
+    Delete,
+    Ddl,
+}
+impl Privilege {
+    const ALL: [Privilege; 4] = [Privilege::Select, Privilege::Insert, Privilege::Delete, Privilege::Ddl];
+
+    /// Parses a comma separated list, ALL stands for every privilege
+    fn parse_list(input: &str) -> Result<Vec<Privilege>, MyDatabaseError> {
+        let mut privileges = Vec::new();
+        for name in input.split(',') {
+            match name.trim() {
+                "SELECT" => privileges.push(Privilege::Select),
+                "INSERT" => privileges.push(Privilege::Insert),
+                "DELETE" => privileges.push(Privilege::Delete),
+                "DDL" => privileges.push(Privilege::Ddl),
+                "ALL" => privileges.extend(Privilege::ALL),
+                other => return Err(MyDatabaseError::UnknownPrivilege(other.to_string())),
+            }
+        }
+        Ok(privileges)
+    }
+}
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Privilege::Select => write!(f, "SELECT"),
+            Privilege::Insert => write!(f, "INSERT"),
+            Privilege::Delete => write!(f, "DELETE"),
+            Privilege::Ddl => write!(f, "DDL"),
+        }
+    }
+}
+
+/// What a command needs from the user running it
+#[derive(Debug, Clone, PartialEq)]
+pub enum RequiredPermission {
+    None,
+    Table(Privilege, String),
+    /// The privilege on every one of the tables, for queries that read several
+    Tables(Privilege, Vec<String>),
+    /// Commands touching the server's file system
+    Admin,
+}
+
+/// Salt and PBKDF2 hash of a password, copied out of the registry so it's checked without holding its lock
+#[derive(Debug, Clone)]
+struct Credentials {
+    salt: [u8; SALT_LEN],
+    password_hash: [u8; HASH_LEN],
+}
+impl Credentials {
+    fn password_matches(&self, password: &str) -> bool {
+        let hash = hash_password(password, &self.salt);
+        // compares every byte, so the time doesn't tell how much of the hash matched
+        hash.iter().zip(self.password_hash.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+}
+
+#[derive(Debug)]
+struct UserAccount {
+    credentials: Credentials,
+    is_admin: bool,
+    privileges: HashMap<String, HashSet<Privilege>>, // table name (or ALL_TABLES) to privileges
+}
+impl UserAccount {
+    fn new(password: &str, is_admin: bool) -> Result<Self, MyDatabaseError> {
+        let mut salt = [0u8; SALT_LEN];
+        getrandom::fill(&mut salt).map_err(|e| MyDatabaseError::IoError(std::io::Error::other(e.to_string())))?;
+        Ok(UserAccount {
+            credentials: Credentials {
+                salt,
+                password_hash: hash_password(password, &salt),
+            },
+            is_admin,
+            privileges: HashMap::new(),
+        })
+    }
+    fn has_privilege(&self, privilege: Privilege, table: &str) -> bool {
+        [table, ALL_TABLES].iter()
+            .any(|t| self.privileges.get(*t).is_some_and(|p| p.contains(&privilege)))
+    }
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> [u8; HASH_LEN] {
+    let mut hash = [0u8; HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), salt, PBKDF2_ROUNDS, &mut hash);
+    hash
+}
+
+/// User accounts of a server. Passwords are only kept as salted PBKDF2 hashes
+#[derive(Debug, Default)]
+pub struct UserRegistry {
+    users: HashMap<String, UserAccount>,
+}
+impl UserRegistry {
+    pub fn new() -> Self {
+        UserRegistry {
+            users: HashMap::new(),
+        }
+    }
+    pub fn create_user(&mut self, name: &str, password: &str, is_admin: bool) -> Result<(), MyDatabaseError> {
+        if self.users.contains_key(name) {
+            return Err(MyDatabaseError::UserAlreadyExists(name.to_string()));
+        }
+        self.users.insert(name.to_string(), UserAccount::new(password, is_admin)?);
+        Ok(())
+    }
+    pub fn drop_user(&mut self, name: &str) -> Result<(), MyDatabaseError> {
+        match self.users.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(MyDatabaseError::UserNotFound(name.to_string())),
+        }
+    }
+    fn credentials(&self, name: &str) -> Option<Credentials> {
+        self.users.get(name).map(|account| account.credentials.clone())
+    }
+    pub fn grant(&mut self, name: &str, privileges: &[Privilege], table: &str) -> Result<(), MyDatabaseError> {
+        let Some(account) = self.users.get_mut(name) else {
+            return Err(MyDatabaseError::UserNotFound(name.to_string()));
+        };
+        account.privileges.entry(table.to_string()).or_default().extend(privileges);
+        Ok(())
+    }
+    pub fn revoke(&mut self, name: &str, privileges: &[Privilege], table: &str) -> Result<(), MyDatabaseError> {
+        let Some(account) = self.users.get_mut(name) else {
+            return Err(MyDatabaseError::UserNotFound(name.to_string()));
+        };
+        if let Some(granted) = account.privileges.get_mut(table) {
+            for privilege in privileges {
+                granted.remove(privilege);
+            }
+        }
+        Ok(())
+    }
+    pub fn is_admin(&self, name: &str) -> bool {
+        self.users.get(name).is_some_and(|a| a.is_admin)
+    }
+    /// Admins can do everything, other users need a privilege granted on the table or on all tables
+    pub fn check(&self, name: &str, required: &RequiredPermission) -> Result<(), MyDatabaseError> {
+        let Some(account) = self.users.get(name) else {
+            return Err(MyDatabaseError::NotLoggedIn); // user was dropped while logged in
+        };
+        if account.is_admin {
+            return Ok(());
+        }
+        match required {
+            RequiredPermission::None => Ok(()),
+            RequiredPermission::Table(privilege, table) if account.has_privilege(*privilege, table) => Ok(()),
+            RequiredPermission::Table(privilege, table) => Err(MyDatabaseError::PermissionDenied(format!("{} on {}", privilege, table))),
+            RequiredPermission::Tables(privilege, tables) => match tables.iter().find(|table| !account.has_privilege(*privilege, table)) {
+                Some(table) => Err(MyDatabaseError::PermissionDenied(format!("{} on {}", privilege, table))),
+                None => Ok(()),
+            },
+            RequiredPermission::Admin => Err(MyDatabaseError::PermissionDenied("file access is for admins only".to_string())),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum AuthCommand {
+    Login { user: String, password: String },
+    Logout,
+    CreateUser { user: String, password: String, is_admin: bool },
+    DropUser { user: String },
+    Grant { privileges: Vec<Privilege>, table: String, user: String },
+    Revoke { privileges: Vec<Privilege>, table: String, user: String },
+}
+impl AuthCommand {
+    /// Returns None for input that isn't an account command
+    fn parse(input: &str) -> Option<Result<AuthCommand, MyDatabaseError>> {
+        let input = input.trim();
+        if input == "LOGOUT" {
+            return Some(Ok(AuthCommand::Logout));
+        }
+        if let Some(rest) = input.strip_prefix("LOGIN ") {
+            return Some(Self::parse_login(rest));
+        }
+        if let Some(rest) = input.strip_prefix("CREATE USER ") {
+            return Some(Self::parse_create_user(rest));
+        }
+        if let Some(rest) = input.strip_prefix("DROP USER ") {
+            return Some(Ok(AuthCommand::DropUser { user: rest.trim().to_string() }));
+        }
+        if let Some(rest) = input.strip_prefix("GRANT ") {
+            return Some(Self::parse_privilege_change(rest, " TO ", "GRANT")
+                .map(|(privileges, table, user)| AuthCommand::Grant { privileges, table, user }));
+        }
+        if let Some(rest) = input.strip_prefix("REVOKE ") {
+            return Some(Self::parse_privilege_change(rest, " FROM ", "REVOKE")
+                .map(|(privileges, table, user)| AuthCommand::Revoke { privileges, table, user }));
+        }
+        None
+    }
+    fn parse_login(input: &str) -> Result<AuthCommand, MyDatabaseError> {
+        // LOGIN <user> <password>, password may be quoted, with \ escaping quotes inside
+        let Some((user, password)) = input.trim().split_once(' ') else {
+            return Err(MyDatabaseError::InvalidCommandFormat("LOGIN"));
+        };
+        let password = match split_escaped_quoted_prefix(password) {
+            Some((quoted, "")) => quoted,
+            _ => password.trim().to_string(),
+        };
+        Ok(AuthCommand::Login { user: user.to_string(), password })
+    }
+    fn parse_create_user(input: &str) -> Result<AuthCommand, MyDatabaseError> {
+        // CREATE USER <user> PASSWORD '<password>' [ADMIN]
+        let Some((user, rest)) = input.trim().split_once(" PASSWORD ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("CREATE USER"));
+        };
+        let Some((password, rest)) = split_escaped_quoted_prefix(rest) else {
+            return Err(MyDatabaseError::InvalidCommandFormat("CREATE USER"));
+        };
+        let is_admin = match rest {
+            "" => false,
+            "ADMIN" => true,
+            _ => return Err(MyDatabaseError::InvalidCommandFormat("CREATE USER")),
+        };
+        Ok(AuthCommand::CreateUser { user: user.trim().to_string(), password, is_admin })
+    }
+    fn parse_privilege_change(input: &str, user_separator: &str, command: &'static str) -> Result<(Vec<Privilege>, String, String), MyDatabaseError> {
+        // <privileges> ON <table|*> TO|FROM <user>
+        let Some((privileges, rest)) = input.split_once(" ON ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat(command));
+        };
+        let Some((table, user)) = rest.split_once(user_separator) else {
+            return Err(MyDatabaseError::InvalidCommandFormat(command));
+        };
+        Ok((Privilege::parse_list(privileges)?, table.trim().to_string(), user.trim().to_string()))
+    }
+
+    /// LOGIN hashes the password after the registry is unlocked, other commands lock it for writing
+    fn execute(self, users: &RwLock<UserRegistry>, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        if let AuthCommand::Login { user, password } = self {
+            let credentials = read_lock(users).credentials(&user);
+            if !credentials.is_some_and(|credentials| credentials.password_matches(&password)) {
+                return Err(MyDatabaseError::InvalidCredentials);
+            }
+            let message = format!("Logged in as {}\n", user);
+            session.login(user);
+            return Ok(CommandOutput::Message(message));
+        }
+        let Some(current_user) = session.user() else {
+            return Err(MyDatabaseError::NotLoggedIn);
+        };
+        if self == AuthCommand::Logout {
+            let message = format!("Logged out {}\n", current_user);
+            session.logout();
+            return Ok(CommandOutput::Message(message));
+        }
+        let mut users = write_lock(users);
+        if !users.is_admin(current_user) {
+            return Err(MyDatabaseError::PermissionDenied("managing users is for admins only".to_string()));
+        }
+        let message = match self {
+            AuthCommand::CreateUser { user, password, is_admin } => {
+                users.create_user(&user, &password, is_admin)?;
+                format!("User {} created\n", user)
+            },
+            AuthCommand::DropUser { user } => {
+                users.drop_user(&user)?;
+                format!("User {} dropped\n", user)
+            },
+            AuthCommand::Grant { privileges, table, user } => {
+                users.grant(&user, &privileges, &table)?;
+                format!("Privileges granted to {} on {}\n", user, table)
+            },
+            AuthCommand::Revoke { privileges, table, user } => {
+                users.revoke(&user, &privileges, &table)?;
+                format!("Privileges revoked from {} on {}\n", user, table)
+            },
+            AuthCommand::Login { .. } | AuthCommand::Logout => return Ok(CommandOutput::Done), // handled above
+        };
+        Ok(CommandOutput::Message(message))
+    }
+}
+
+/// Entry point for network servers - handles account commands and checks
+/// the logged in user's permissions before any database command is executed
+/// The registry is locked for writing only by account commands other than LOGIN, the rest just read it
+pub fn execute_with_auth(input: &str, context_db: &AnyDatabase, users: &RwLock<UserRegistry>, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+    if let Some(auth_command) = AuthCommand::parse(input) {
+        // account commands are never recorded in history, it would keep passwords
+        return auth_command?.execute(users, session);
+    }
+    let Some(user) = session.user().map(str::to_string) else {
+        return Err(MyDatabaseError::NotLoggedIn);
+    };
+    AnyCommand::create_check_and_execute(input, context_db, session, |cmd| {
+        read_lock(users).check(&user, &cmd.required_permission())
+    })
+}
+
+fn random_hex(len: usize) -> Result<String, MyDatabaseError> {
+    let mut bytes = vec![0u8; len];
+    getrandom::fill(&mut bytes).map_err(|e| MyDatabaseError::IoError(std::io::Error::other(e.to_string())))?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Random password for the admin account, when the server wasn't given one
+pub fn generate_password() -> Result<String, MyDatabaseError> {
+    random_hex(12)
+}
+
+/// Random token a connectionless client presents with every request after LOGIN
+pub fn generate_session_token() -> Result<String, MyDatabaseError> {
+    random_hex(16)
+}
+
+/// Writes a generated password to a file only its owner can read, instead of printing it
+pub fn write_password_file(path: &Path, password: &str) -> Result<(), MyDatabaseError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // the mode above only applies to new files
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    writeln!(file, "{}", password)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db_structure::Database;
+    use crate::models::test_utils::run_as;
+    use crate::models::utilities::quote_escaped;
+
+    #[test]
+    fn permissions_are_checked_before_execution() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        let mut users = UserRegistry::new();
+        users.create_user("admin", "root pw", true).unwrap();
+        let users = RwLock::new(users);
+        let mut admin = Session::default();
+        let mut bob = Session::default();
+
+        assert!(matches!(run_as(&db, &users, &mut bob, "SELECT * FROM t"), Err(MyDatabaseError::NotLoggedIn)));
+        assert!(matches!(run_as(&db, &users, &mut admin, "LOGIN admin wrong"), Err(MyDatabaseError::InvalidCredentials)));
+        run_as(&db, &users, &mut admin, "LOGIN admin \"root pw\"").unwrap();
+        run_as(&db, &users, &mut admin, "CREATE t KEY id FIELDS id: Int").unwrap();
+        run_as(&db, &users, &mut admin, "CREATE USER bob PASSWORD 'pw'").unwrap();
+        run_as(&db, &users, &mut admin, "GRANT SELECT, INSERT ON t TO bob").unwrap();
+
+        run_as(&db, &users, &mut bob, "LOGIN bob pw").unwrap();
+        run_as(&db, &users, &mut bob, "INSERT id = 1 INTO t").unwrap();
+        assert!(run_as(&db, &users, &mut bob, "SELECT * FROM t").unwrap().is_some());
+        assert!(matches!(run_as(&db, &users, &mut bob, "DELETE 1 FROM t"), Err(MyDatabaseError::PermissionDenied(_))));
+        assert!(matches!(run_as(&db, &users, &mut bob, "CREATE u KEY id FIELDS id: Int"), Err(MyDatabaseError::PermissionDenied(_))));
+        assert!(matches!(run_as(&db, &users, &mut bob, "SAVE_AS /tmp/x.txt"), Err(MyDatabaseError::PermissionDenied(_))));
+        assert!(matches!(run_as(&db, &users, &mut bob, "GRANT ALL ON * TO bob"), Err(MyDatabaseError::PermissionDenied(_))));
+
+        run_as(&db, &users, &mut admin, "CREATE secret KEY id FIELDS id: Int").unwrap();
+        assert!(matches!(run_as(&db, &users, &mut bob, "SELECT * FROM t WHERE EXISTS (SELECT id FROM secret)"), Err(MyDatabaseError::PermissionDenied(_))));
+
+        run_as(&db, &users, &mut admin, "REVOKE INSERT ON t FROM bob").unwrap();
+        assert!(matches!(run_as(&db, &users, &mut bob, "INSERT id = 2 INTO t"), Err(MyDatabaseError::PermissionDenied(_))));
+        run_as(&db, &users, &mut admin, "GRANT DDL ON * TO bob").unwrap();
+        run_as(&db, &users, &mut bob, "CREATE u KEY id FIELDS id: Int").unwrap();
+        assert!(bob.executed_commands().iter().all(|c| !c.contains("pw")));
+    }
+
+    #[test]
+    fn quoted_passwords_can_hold_quotes() {
+        let password = "a \"quoted\" \\ 'pw'";
+        let login = AuthCommand::parse(&format!("LOGIN bob {}", quote_escaped(password)));
+        assert_eq!(login.unwrap().unwrap(), AuthCommand::Login { user: "bob".to_string(), password: password.to_string() });
+        let create = AuthCommand::parse(&format!("CREATE USER bob PASSWORD {} ADMIN", quote_escaped(password)));
+        assert_eq!(create.unwrap().unwrap(), AuthCommand::CreateUser { user: "bob".to_string(), password: password.to_string(), is_admin: true });
+    }
+}
@@ -0,0 +1,527 @@
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::Bound;
+use std::sync::Arc;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{DatabaseKey, Value, ValueType};
+use crate::models::pager::{Pager, PAGE_CAPACITY};
+
+/// Biggest entry kept in a leaf, halves of a split node always fit in a page. Bigger ones go to overflow pages
+pub const MAX_ENTRY: usize = PAGE_CAPACITY / 3;
+/// Biggest encoded key, the reference to overflow pages has to fit next to it
+pub const MAX_KEY: usize = MAX_ENTRY - OVERFLOW_REF;
+
+const LEAF: u8 = 1;
+const INTERNAL: u8 = 2;
+const NO_PAGE: u64 = 0; // page 0 is the header of the pager, so no node points to it
+/// Length of an entry in a leaf that marks it as kept in overflow pages
+const OVERFLOW: u16 = u16::MAX;
+const OVERFLOW_REF: usize = 2 + 4 + 8;
+/// Bytes of an entry in an overflow page, after the next page and the length
+const OVERFLOW_DATA: usize = PAGE_CAPACITY - 8 - 2;
+
+/// B-tree of byte entries in the pages of a file, ordered by keys.
+/// The root stays on its page, so the tree can be found after it grows
+#[derive(Debug)]
+pub struct BTree<K> {
+    pager: Arc<Pager>,
+    root: u64,
+    key: PhantomData<K>,
+}
+
+#[derive(Debug)]
+enum Node<K> {
+    Leaf { entries: Vec<(K, Slot)>, next: u64 }, // next - following leaf
+    Internal { keys: Vec<K>, children: Vec<u64> }, // keys of child i+1 are at least keys[i]
+}
+
+/// Entry in a leaf, or the length and first page of a chain of overflow pages holding it
+#[derive(Debug)]
+enum Slot {
+    Inline(Vec<u8>),
+    Overflow { len: u32, first: u64 },
+}
+impl Slot {
+    fn encoded_len(&self) -> usize {
+        match self {
+            Slot::Inline(entry) => 2 + entry.len(),
+            Slot::Overflow { .. } => OVERFLOW_REF,
+        }
+    }
+}
+impl<K: DatabaseKey> Node<K> {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(PAGE_CAPACITY);
+        match self {
+            Node::Leaf { entries, next } => {
+                buf.push(LEAF);
+                buf.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&next.to_le_bytes());
+                for (key, slot) in entries {
+                    encode_value(&key.to_value(), &mut buf);
+                    match slot {
+                        Slot::Inline(entry) => {
+                            buf.extend_from_slice(&(entry.len() as u16).to_le_bytes());
+                            buf.extend_from_slice(entry);
+                        }
+                        Slot::Overflow { len, first } => {
+                            buf.extend_from_slice(&OVERFLOW.to_le_bytes());
+                            buf.extend_from_slice(&len.to_le_bytes());
+                            buf.extend_from_slice(&first.to_le_bytes());
+                        }
+                    }
+                }
+            }
+            Node::Internal { keys, children } => {
+                buf.push(INTERNAL);
+                buf.extend_from_slice(&(keys.len() as u16).to_le_bytes());
+                buf.extend_from_slice(&children[0].to_le_bytes());
+                for (key, child) in keys.iter().zip(&children[1..]) {
+                    encode_value(&key.to_value(), &mut buf);
+                    buf.extend_from_slice(&child.to_le_bytes());
+                }
+            }
+        }
+        buf
+    }
+    fn decode(page: u64, bytes: &[u8]) -> Result<Node<K>, MyDatabaseError> {
+        let mut reader = Reader::new(bytes);
+        let kind = reader.u8()?;
+        let count = reader.u16()? as usize;
+        match kind {
+            LEAF => {
+                let next = reader.u64()?;
+                let mut entries = Vec::with_capacity(count);
+                for _ in 0..count {
+                    let key = reader.key()?;
+                    let slot = match reader.u16()? {
+                        OVERFLOW => Slot::Overflow { len: reader.u32()?, first: reader.u64()? },
+                        len => Slot::Inline(reader.bytes(len as usize)?.to_vec()),
+                    };
+                    entries.push((key, slot));
+                }
+                Ok(Node::Leaf { entries, next })
+            }
+            INTERNAL => {
+                let mut keys = Vec::with_capacity(count);
+                let mut children = vec![reader.u64()?];
+                for _ in 0..count {
+                    keys.push(reader.key()?);
+                    children.push(reader.u64()?);
+                }
+                Ok(Node::Internal { keys, children })
+            }
+            kind => Err(MyDatabaseError::CorruptedFile(format!("page {} has unknown node kind {}", page, kind))),
+        }
+    }
+    /// Splits a node that doesn't fit in a page into halves of similar size, with the first key of the right one
+    fn split(self) -> (Node<K>, K, Node<K>) {
+        match self {
+            Node::Leaf { mut entries, next } => {
+                let sizes: Vec<usize> = entries.iter().map(|(key, slot)| encoded_key_len(key) + slot.encoded_len()).collect();
+                let middle = middle_index(&sizes);
+                let right = entries.split_off(middle);
+                let separator = right[0].0.clone();
+                (Node::Leaf { entries, next }, separator, Node::Leaf { entries: right, next })
+            }
+            Node::Internal { mut keys, mut children } => {
+                let sizes: Vec<usize> = keys.iter().map(|key| encoded_key_len(key) + 8).collect();
+                let middle = middle_index(&sizes);
+                let mut right_keys = keys.split_off(middle);
+                let separator = right_keys.remove(0);
+                let right_children = children.split_off(middle + 1);
+                (Node::Internal { keys, children }, separator, Node::Internal { keys: right_keys, children: right_children })
+            }
+        }
+    }
+}
+
+/// First index after which items take at least half of the size, never the first or past the last
+fn middle_index(sizes: &[usize]) -> usize {
+    let half = sizes.iter().sum::<usize>() / 2;
+    let mut taken = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        taken += size;
+        if taken >= half {
+            return (i + 1).clamp(1, sizes.len() - 1);
+        }
+    }
+    sizes.len() - 1
+}
+
+fn encoded_key_len<K: DatabaseKey>(key: &K) -> usize {
+    let mut buf = Vec::new();
+    encode_value(&key.to_value(), &mut buf);
+    buf.len()
+}
+
+impl<K: DatabaseKey> BTree<K> {
+    /// New empty tree with its root on a new page
+    pub fn create(pager: Arc<Pager>) -> Result<BTree<K>, MyDatabaseError> {
+        let root = pager.allocate()?;
+        let tree = BTree { pager, root, key: PhantomData };
+        tree.write(root, &Node::Leaf { entries: Vec::new(), next: NO_PAGE })?;
+        Ok(tree)
+    }
+    pub fn open(pager: Arc<Pager>, root: u64) -> BTree<K> {
+        BTree { pager, root, key: PhantomData }
+    }
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+    fn read(&self, page: u64) -> Result<Node<K>, MyDatabaseError> {
+        Node::decode(page, &self.pager.read(page)?)
+    }
+    fn write(&self, page: u64, node: &Node<K>) -> Result<(), MyDatabaseError> {
+        self.pager.write(page, &node.encode())
+    }
+    /// Leaf where the key belongs
+    fn find_leaf(&self, key: Bound<&K>) -> Result<u64, MyDatabaseError> {
+        let mut page = self.root;
+        loop {
+            match self.read(page)? {
+                Node::Leaf { .. } => return Ok(page),
+                Node::Internal { keys, children } => {
+                    let child = match key {
+                        Bound::Included(key) | Bound::Excluded(key) => keys.partition_point(|k| k <= key),
+                        Bound::Unbounded => 0,
+                    };
+                    page = children[child];
+                }
+            }
+        }
+    }
+    pub fn get(&self, key: &K) -> Result<Option<Vec<u8>>, MyDatabaseError> {
+        let Node::Leaf { entries, .. } = self.read(self.find_leaf(Bound::Included(key))?)? else {
+            return Err(MyDatabaseError::CorruptedFile(format!("no leaf for key {}", key.to_value())));
+        };
+        match entries.into_iter().find(|(k, _)| k == key) {
+            Some((_, slot)) => self.load(slot).map(Some),
+            None => Ok(None),
+        }
+    }
+    /// Adds or replaces the entry of the key, entries too big for a leaf are written to overflow pages
+    pub fn put(&self, key: K, entry: Vec<u8>) -> Result<(), MyDatabaseError> {
+        let key_len = encoded_key_len(&key);
+        if key_len > MAX_KEY {
+            return Err(MyDatabaseError::RecordTooLarge(key_len, MAX_KEY));
+        }
+        let slot = if key_len + 2 + entry.len() > MAX_ENTRY {
+            self.write_overflow(&entry)?
+        } else {
+            Slot::Inline(entry)
+        };
+        self.put_below(self.root, key, slot)?;
+        Ok(())
+    }
+    /// Puts the entry in the subtree, returns the separator and page of a new right sibling when the node splits
+    fn put_below(&self, page: u64, key: K, slot: Slot) -> Result<Option<(K, u64)>, MyDatabaseError> {
+        let node = match self.read(page)? {
+            Node::Leaf { mut entries, next } => {
+                match entries.binary_search_by(|(k, _)| k.cmp(&key)) {
+                    Ok(i) => {
+                        let old = mem::replace(&mut entries[i].1, slot);
+                        self.free_overflow(old)?;
+                    }
+                    Err(i) => entries.insert(i, (key, slot)),
+                }
+                Node::Leaf { entries, next }
+            }
+            Node::Internal { mut keys, mut children } => {
+                let child = keys.partition_point(|k| *k <= key);
+                match self.put_below(children[child], key, slot)? {
+                    Some((separator, right)) => {
+                        keys.insert(child, separator);
+                        children.insert(child + 1, right);
+                        Node::Internal { keys, children }
+                    }
+                    None => return Ok(None),
+                }
+            }
+        };
+        let encoded = node.encode();
+        if encoded.len() <= PAGE_CAPACITY {
+            self.pager.write(page, &encoded)?;
+            return Ok(None);
+        }
+        let (mut left, separator, mut right) = node.split();
+        let right_page = self.pager.allocate()?;
+        if page == self.root {
+            // the root keeps its page and points to both halves
+            let left_page = self.pager.allocate()?;
+            if let Node::Leaf { next, .. } = &mut left {
+                *next = right_page;
+            }
+            self.write(left_page, &left)?;
+            self.write(right_page, &right)?;
+            self.write(page, &Node::Internal { keys: vec![separator], children: vec![left_page, right_page] })?;
+            return Ok(None);
+        }
+        if let (Node::Leaf { next, .. }, Node::Leaf { next: right_next, .. }) = (&mut left, &mut right) {
+            *right_next = *next;
+            *next = right_page;
+        }
+        self.write(page, &left)?;
+        self.write(right_page, &right)?;
+        Ok(Some((separator, right_page)))
+    }
+    /// Removes the entry of the key. Nodes aren't merged, emptied leaves stay in the tree
+    pub fn remove(&self, key: &K) -> Result<(), MyDatabaseError> {
+        let page = self.find_leaf(Bound::Included(key))?;
+        if let Node::Leaf { mut entries, next } = self.read(page)?
+            && let Ok(i) = entries.binary_search_by(|(k, _)| k.cmp(key)) {
+            let (_, slot) = entries.remove(i);
+            self.write(page, &Node::Leaf { entries, next })?;
+            self.free_overflow(slot)?;
+        }
+        Ok(())
+    }
+    /// Writes the entry to a chain of new overflow pages
+    fn write_overflow(&self, entry: &[u8]) -> Result<Slot, MyDatabaseError> {
+        let chunks: Vec<&[u8]> = entry.chunks(OVERFLOW_DATA).collect();
+        let pages = chunks.iter().map(|_| self.pager.allocate()).collect::<Result<Vec<u64>, MyDatabaseError>>()?;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(NO_PAGE);
+            let mut buf = Vec::with_capacity(10 + chunk.len());
+            buf.extend_from_slice(&next.to_le_bytes());
+            buf.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            buf.extend_from_slice(chunk);
+            self.pager.write(pages[i], &buf)?;
+        }
+        Ok(Slot::Overflow { len: entry.len() as u32, first: pages.first().copied().unwrap_or(NO_PAGE) })
+    }
+    /// Bytes of the entry, read from its overflow pages if needed
+    fn load(&self, slot: Slot) -> Result<Vec<u8>, MyDatabaseError> {
+        let (len, mut page) = match slot {
+            Slot::Inline(entry) => return Ok(entry),
+            Slot::Overflow { len, first } => (len as usize, first),
+        };
+        let mut entry = Vec::new();
+        while page != NO_PAGE && entry.len() < len {
+            let bytes = self.pager.read(page)?;
+            let mut reader = Reader::new(&bytes);
+            let next = reader.u64()?;
+            let chunk_len = reader.u16()? as usize;
+            entry.extend_from_slice(reader.bytes(chunk_len)?);
+            page = next;
+        }
+        if entry.len() != len || page != NO_PAGE {
+            return Err(MyDatabaseError::CorruptedFile(format!("overflow pages hold {} of {} bytes of an entry", entry.len(), len)));
+        }
+        Ok(entry)
+    }
+    /// Frees the overflow pages of a replaced or removed entry
+    fn free_overflow(&self, slot: Slot) -> Result<(), MyDatabaseError> {
+        let Slot::Overflow { len, first } = slot else {
+            return Ok(());
+        };
+        let mut page = first;
+        for _ in 0..(len as usize).div_ceil(OVERFLOW_DATA) {
+            let next = Reader::new(&self.pager.read(page)?).u64()?;
+            self.pager.free(page)?;
+            page = next;
+        }
+        Ok(())
+    }
+    /// Entries with keys in the range in order, reading one leaf at a time
+    pub fn range(&self, lower: Bound<&K>, upper: Bound<&K>) -> BTreeRange<'_, K> {
+        BTreeRange {
+            tree: self,
+            lower: Some(lower.cloned()),
+            upper: upper.cloned(),
+            entries: Vec::new().into_iter(),
+            next: NO_PAGE,
+        }
+    }
+}
+
+/// Iterator over entries of a key range of a B-tree
+pub struct BTreeRange<'a, K> {
+    tree: &'a BTree<K>,
+    lower: Option<Bound<K>>, // until the first leaf is read
+    upper: Bound<K>,
+    entries: std::vec::IntoIter<(K, Slot)>,
+    next: u64,
+}
+impl<K: DatabaseKey> BTreeRange<'_, K> {
+    fn load(&mut self, page: u64) -> Result<(), MyDatabaseError> {
+        match self.tree.read(page)? {
+            Node::Leaf { mut entries, next } => {
+                if let Some(lower) = self.lower.take() {
+                    entries.retain(|(key, _)| match &lower {
+                        Bound::Included(lower) => key >= lower,
+                        Bound::Excluded(lower) => key > lower,
+                        Bound::Unbounded => true,
+                    });
+                }
+                self.entries = entries.into_iter();
+                self.next = next;
+                Ok(())
+            }
+            Node::Internal { .. } => Err(MyDatabaseError::CorruptedFile(format!("page {} is not a leaf", page))),
+        }
+    }
+}
+impl<K: DatabaseKey> Iterator for BTreeRange<'_, K> {
+    type Item = Result<(K, Vec<u8>), MyDatabaseError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, slot)) = self.entries.next() {
+                let in_range = match &self.upper {
+                    Bound::Included(upper) => key <= *upper,
+                    Bound::Excluded(upper) => key < *upper,
+                    Bound::Unbounded => true,
+                };
+                if !in_range {
+                    self.next = NO_PAGE;
+                    return None;
+                }
+                return Some(self.tree.load(slot).map(|entry| (key, entry)));
+            }
+            let page = match &self.lower {
+                Some(lower) => self.tree.find_leaf(lower.as_ref()),
+                None if self.next == NO_PAGE => return None,
+                None => Ok(self.next),
+            };
+            if let Err(e) = page.and_then(|page| self.load(page)) {
+                self.lower = None;
+                self.next = NO_PAGE;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Appends a value with a tag of its type
+pub fn encode_value(value: &Value, buf: &mut Vec<u8>) {
+    buf.push(type_tag(value.get_type()));
+    match value {
+        Value::Bool(b) => buf.push(u8::from(*b)),
+        Value::Int(i) => buf.extend_from_slice(&i.to_le_bytes()),
+        Value::Float(f) => buf.extend_from_slice(&f.to_bits().to_le_bytes()),
+        Value::String(s) => {
+            buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+            buf.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+pub fn type_tag(value_type: ValueType) -> u8 {
+    match value_type {
+        ValueType::Bool => 0,
+        ValueType::Int => 1,
+        ValueType::Float => 2,
+        ValueType::String => 3,
+    }
+}
+
+/// Reads numbers and values from a page, running past its end means the file is corrupted
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, position: 0 }
+    }
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], MyDatabaseError> {
+        let end = self.position + len;
+        let Some(bytes) = self.bytes.get(self.position..end) else {
+            return Err(MyDatabaseError::CorruptedFile(format!("data ends before byte {}", end)));
+        };
+        self.position = end;
+        Ok(bytes)
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], MyDatabaseError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+    pub fn u8(&mut self) -> Result<u8, MyDatabaseError> {
+        Ok(self.bytes(1)?[0])
+    }
+    pub fn u16(&mut self) -> Result<u16, MyDatabaseError> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+    pub fn u32(&mut self) -> Result<u32, MyDatabaseError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    pub fn u64(&mut self) -> Result<u64, MyDatabaseError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+    pub fn value_type(&mut self) -> Result<ValueType, MyDatabaseError> {
+        match self.u8()? {
+            0 => Ok(ValueType::Bool),
+            1 => Ok(ValueType::Int),
+            2 => Ok(ValueType::Float),
+            3 => Ok(ValueType::String),
+            tag => Err(MyDatabaseError::CorruptedFile(format!("unknown type tag {}", tag))),
+        }
+    }
+    pub fn value(&mut self) -> Result<Value, MyDatabaseError> {
+        match self.value_type()? {
+            ValueType::Bool => Ok(Value::Bool(self.u8()? != 0)),
+            ValueType::Int => Ok(Value::Int(self.u64()? as i64)),
+            ValueType::Float => Ok(Value::Float(f64::from_bits(self.u64()?))),
+            ValueType::String => {
+                let len = self.u32()? as usize;
+                let bytes = self.bytes(len)?;
+                String::from_utf8(bytes.to_vec())
+                    .map(Value::String)
+                    .map_err(|_| MyDatabaseError::CorruptedFile("string is not UTF-8".to_string()))
+            }
+        }
+    }
+    pub fn key<K: DatabaseKey>(&mut self) -> Result<K, MyDatabaseError> {
+        let value = self.value()?;
+        K::get_from_value(&value).ok_or_else(|| MyDatabaseError::CorruptedFile(format!("{} is not a valid key", value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_splits_pages_and_scans_ranges_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("btree.db");
+        // a tiny pool, so pages are evicted and read back
+        let pager = Arc::new(Pager::open(&path, 4).unwrap());
+        pager.begin().unwrap();
+        let tree = BTree::<i64>::create(Arc::clone(&pager)).unwrap();
+        for i in (0..3000).rev() {
+            tree.put(i * 2, vec![(i % 256) as u8; 100]).unwrap();
+        }
+        pager.commit().unwrap();
+        pager.begin().unwrap();
+        tree.put(10, vec![7; 3]).unwrap();
+        tree.remove(&12).unwrap();
+        assert!(pager.page_count() > 30);
+
+        // big entries are kept in overflow pages, which are reused after the entry is replaced
+        let big: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        tree.put(1, big.clone()).unwrap();
+        tree.put(3, vec![1; MAX_ENTRY]).unwrap();
+        let pages = pager.page_count();
+        tree.put(1, vec![2; 10]).unwrap();
+        tree.put(5, big.clone()).unwrap();
+        assert_eq!(pager.page_count(), pages);
+        assert_eq!(tree.get(&5).unwrap(), Some(big));
+        assert_eq!(tree.get(&3).unwrap(), Some(vec![1; MAX_ENTRY]));
+        tree.remove(&5).unwrap();
+        tree.remove(&3).unwrap();
+        tree.remove(&1).unwrap();
+
+        assert_eq!(tree.get(&10).unwrap(), Some(vec![7; 3]));
+        assert_eq!(tree.get(&11).unwrap(), None);
+        assert_eq!(tree.get(&5998).unwrap(), Some(vec![(2999 % 256) as u8; 100]));
+        let keys: Vec<i64> = tree.range(Bound::Excluded(&8), Bound::Included(&16)).map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, [10, 14, 16]);
+        assert_eq!(tree.range(Bound::Unbounded, Bound::Unbounded).count(), 2999);
+
+        pager.commit().unwrap();
+        let reopened = BTree::<i64>::open(Arc::new(Pager::open(&path, 4).unwrap()), tree.root());
+        let keys: Vec<i64> = reopened.range(Bound::Included(&5990), Bound::Unbounded).map(|entry| entry.unwrap().0).collect();
+        assert_eq!(keys, [5990, 5992, 5994, 5996, 5998]);
+    }
+}
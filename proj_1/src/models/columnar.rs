@@ -0,0 +1,250 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::iter;
+use std::ops::Bound;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Record, Value, ValueType, SCAN_CHUNK};
+use crate::models::storage::{delete_version, drop_old_versions, undo_versions, visible_version, Rows, Storage, Version};
+use crate::models::where_parsing::{ColumnBatch, WhereClause};
+
+/// Values of one column in slots shared by all columns of the table
+#[derive(Debug)]
+pub enum ColumnVector {
+    Bool(Vec<bool>),
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    String(Vec<String>),
+}
+impl ColumnVector {
+    fn new(value_type: ValueType) -> Self {
+        match value_type {
+            ValueType::Bool => ColumnVector::Bool(Vec::new()),
+            ValueType::Int => ColumnVector::Int(Vec::new()),
+            ValueType::Float => ColumnVector::Float(Vec::new()),
+            ValueType::String => ColumnVector::String(Vec::new()),
+        }
+    }
+    fn accepts(&self, value: &Value) -> bool {
+        matches!((self, value), (ColumnVector::Bool(_), Value::Bool(_)) | (ColumnVector::Int(_), Value::Int(_))
+            | (ColumnVector::Float(_), Value::Float(_)) | (ColumnVector::String(_), Value::String(_)))
+    }
+    /// Puts the value in the slot, or after the last one. Types are checked by accepts
+    fn set(&mut self, slot: usize, value: Value) {
+        fn put<T>(values: &mut Vec<T>, slot: usize, value: T) {
+            match values.get_mut(slot) {
+                Some(old) => *old = value,
+                None => values.push(value),
+            }
+        }
+        match (self, value) {
+            (ColumnVector::Bool(values), Value::Bool(b)) => put(values, slot, b),
+            (ColumnVector::Int(values), Value::Int(i)) => put(values, slot, i),
+            (ColumnVector::Float(values), Value::Float(f)) => put(values, slot, f),
+            (ColumnVector::String(values), Value::String(s)) => put(values, slot, s),
+            _ => {}
+        }
+    }
+    pub(crate) fn get(&self, slot: usize) -> Value {
+        match self {
+            ColumnVector::Bool(values) => Value::Bool(values[slot]),
+            ColumnVector::Int(values) => Value::Int(values[slot]),
+            ColumnVector::Float(values) => Value::Float(values[slot]),
+            ColumnVector::String(values) => Value::String(values[slot].clone()),
+        }
+    }
+    /// Values of the slots, strings are borrowed
+    fn gather(&self, slots: &[usize]) -> ColumnBatch<'_> {
+        match self {
+            ColumnVector::Bool(values) => ColumnBatch::Bool(slots.iter().map(|slot| values[*slot]).collect()),
+            ColumnVector::Int(values) => ColumnBatch::Int(slots.iter().map(|slot| values[*slot]).collect()),
+            ColumnVector::Float(values) => ColumnBatch::Float(slots.iter().map(|slot| values[*slot]).collect()),
+            ColumnVector::String(values) => ColumnBatch::String(slots.iter().map(|slot| values[*slot].as_str()).collect()),
+        }
+    }
+}
+
+/// Records kept by column, every version takes a slot in the vectors of all columns.
+/// Scans with a condition read only the columns it needs and evaluate it for a chunk of rows at once
+#[derive(Debug)]
+pub struct ColumnarStorage<K> {
+    columns: Vec<String>,
+    positions: HashMap<String, usize>,
+    vectors: Vec<ColumnVector>, // in the order of columns
+    records: BTreeMap<K, Vec<Version<usize>>>, // slots of versions of a key, oldest first
+    slot_count: usize,
+    free: Vec<usize>, // slots of dropped versions
+    changed: Option<Vec<(K, u64)>>, // keys changed since begin with the timestamps, to undo them
+}
+impl<K> ColumnarStorage<K> {
+    pub fn new(columns: &[(String, ValueType)]) -> Self {
+        ColumnarStorage {
+            columns: columns.iter().map(|(column, _)| column.clone()).collect(),
+            positions: columns.iter().enumerate().map(|(position, (column, _))| (column.clone(), position)).collect(),
+            vectors: columns.iter().map(|(_, value_type)| ColumnVector::new(*value_type)).collect(),
+            records: BTreeMap::new(),
+            slot_count: 0,
+            free: Vec::new(),
+            changed: None,
+        }
+    }
+    /// Record reading its values from the vectors when a query asks for them
+    fn record(&self, slot: usize) -> Record<'_> {
+        Record::Columnar(&self.vectors, slot, &self.positions)
+    }
+    /// Hides records of the chunk that don't meet the condition
+    fn filter_chunk<'a>(&'a self, chunk: Vec<(K, Option<usize>)>, condition: &WhereClause, columns: &Option<Vec<&str>>) -> Vec<Result<(K, Option<Record<'a>>), MyDatabaseError>> {
+        let Some(columns) = columns else {
+            return chunk.into_iter()
+                .map(|(key, slot)| match slot.map(|slot| self.record(slot)) {
+                    Some(record) if !condition.evaluate_for_record(&record)? => Ok((key, None)),
+                    record => Ok((key, record)),
+                })
+                .collect();
+        };
+        let slots: Vec<usize> = chunk.iter().filter_map(|(_, slot)| *slot).collect();
+        let batch: HashMap<&str, ColumnBatch> = columns.iter()
+            .filter_map(|column| self.positions.get(*column).map(|position| (*column, self.vectors[*position].gather(&slots))))
+            .collect();
+        let mask = match condition.evaluate_for_columns(&batch, slots.len()) {
+            Ok(mask) => mask,
+            Err(e) => return vec![Err(e)],
+        };
+        let mut mask = mask.into_iter();
+        chunk.into_iter()
+            .map(|(key, slot)| {
+                let met = slot.is_some() && mask.next().unwrap_or(false);
+                Ok((key, slot.filter(|_| met).map(|slot| self.record(slot))))
+            })
+            .collect()
+    }
+}
+impl<K: Ord + Clone + Debug + Send + Sync> Storage<K> for ColumnarStorage<K> {
+    fn check_row(&self, values: &HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+        let fits = self.columns.iter().zip(&self.vectors)
+            .all(|(column, vector)| values.get(column).is_some_and(|value| vector.accepts(value)));
+        if !fits || values.len() != self.columns.len() {
+            return Err(MyDatabaseError::KeysMismatch);
+        }
+        Ok(())
+    }
+    fn insert(&mut self, key: K, mut values: HashMap<String, Value>, timestamp: u64) -> Result<(), MyDatabaseError> {
+        self.check_row(&values)?;
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                self.slot_count += 1;
+                self.slot_count - 1
+            }
+        };
+        for (column, vector) in self.columns.iter().zip(&mut self.vectors) {
+            if let Some(value) = values.remove(column) {
+                vector.set(slot, value);
+            }
+        }
+        let version = Version {
+            created: timestamp,
+            deleted: None,
+            row: slot,
+        };
+        if let Some(changed) = &mut self.changed {
+            changed.push((key.clone(), timestamp));
+        }
+        self.records.entry(key).or_default().push(version);
+        Ok(())
+    }
+    fn delete(&mut self, key: &K, timestamp: u64) -> Result<bool, MyDatabaseError> {
+        let deleted = self.records.get_mut(key).is_some_and(|versions| delete_version(versions, timestamp));
+        if deleted && let Some(changed) = &mut self.changed {
+            changed.push((key.clone(), timestamp));
+        }
+        Ok(deleted)
+    }
+    fn get(&self, key: &K, timestamp: u64) -> Result<Option<Record<'_>>, MyDatabaseError> {
+        let version = self.records.get(key).and_then(|versions| visible_version(versions, timestamp));
+        Ok(version.map(|version| self.record(version.row)))
+    }
+    fn range<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64) -> Rows<'a, K> {
+        Box::new(self.records.range((lower, upper)).map(move |(key, versions)| {
+            let version = visible_version(versions, timestamp);
+            Ok((key.clone(), version.map(|version| self.record(version.row))))
+        }))
+    }
+    /// Evaluates the condition for chunks of rows, column by column when it only compares values
+    fn range_where<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64, condition: &'a WhereClause) -> Rows<'a, K> where K: 'a {
+        let mut entries = self.records.range((lower, upper))
+            .map(move |(key, versions)| (key.clone(), visible_version(versions, timestamp).map(|version| version.row)));
+        let chunks = iter::from_fn(move || {
+            let chunk: Vec<(K, Option<usize>)> = entries.by_ref().take(SCAN_CHUNK).collect();
+            (!chunk.is_empty()).then_some(chunk)
+        });
+        let columns = condition.batch_columns();
+        Box::new(chunks.flat_map(move |chunk| self.filter_chunk(chunk, condition, &columns)))
+    }
+    fn collect_garbage(&mut self, key: &K, oldest_snapshot: Option<u64>) -> Result<bool, MyDatabaseError> {
+        let Some(versions) = self.records.get_mut(key) else {
+            return Ok(false);
+        };
+        let slots: Vec<usize> = versions.iter().map(|version| version.row).collect();
+        let deleted_left = drop_old_versions(versions, oldest_snapshot);
+        self.free.extend(slots.into_iter().filter(|slot| !versions.iter().any(|version| version.row == *slot)));
+        if versions.is_empty() {
+            self.records.remove(key);
+        }
+        Ok(deleted_left)
+    }
+    fn version_count(&self, key: &K) -> Result<usize, MyDatabaseError> {
+        Ok(self.records.get(key).map_or(0, Vec::len))
+    }
+    fn begin(&mut self) -> Result<(), MyDatabaseError> {
+        self.changed = Some(Vec::new());
+        Ok(())
+    }
+    fn commit(&mut self, _clock: u64) -> Result<(), MyDatabaseError> {
+        self.changed = None;
+        Ok(())
+    }
+    /// Slots of undone versions are freed, values left in them are overwritten by the next insert
+    fn rollback(&mut self) {
+        for (key, timestamp) in self.changed.take().unwrap_or_default().into_iter().rev() {
+            if let Some(versions) = self.records.get_mut(&key) {
+                self.free.extend(undo_versions(versions, timestamp));
+                if versions.is_empty() {
+                    self.records.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db_structure::{AnyDatabase, Database};
+    use crate::models::test_utils::run;
+
+    #[test]
+    fn columnar_scans_match_row_tables() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE rows KEY id FIELDS id: Int, qty: Int, price: Float, name: String, ok: Bool").unwrap();
+        run(&db, "CREATE cols KEY id FIELDS id: Int, qty: Int, price: Float, name: String, ok: Bool WITH (storage = columnar)").unwrap();
+        for table in ["rows", "cols"] {
+            for id in 0..700 {
+                run(&db, &format!("INSERT id = {}, qty = {}, price = {}.5, name = \"n{}\", ok = {} INTO {}", id, id % 7, id % 11, id % 5, id % 3 == 0, table)).unwrap();
+            }
+            for id in (0..700).step_by(4) {
+                run(&db, &format!("DELETE {} FROM {}", id, table)).unwrap();
+            }
+        }
+        for condition in ["qty > 3 AND name = \"n2\"", "price >= 5 OR ok = true", "qty * 2 > price", "id > 300 AND id <= 650 AND qty != 1", "ok = false AND qty < price"] {
+            let rows = run(&db, &format!("SELECT * FROM rows WHERE {}", condition)).unwrap().unwrap();
+            let cols = run(&db, &format!("SELECT * FROM cols WHERE {}", condition)).unwrap().unwrap();
+            assert!(!cols.is_empty());
+            assert_eq!(rows, cols, "{}", condition);
+        }
+        assert!(run(&db, "INSERT id = 1000, qty = 1.5, price = 1.5, name = \"x\", ok = true INTO cols").is_err());
+        let Err(MyDatabaseError::CommandParseError(e)) = run(&db, "CREATE t KEY id FIELDS id: Int WITH (storage = heap)") else {
+            panic!("unknown storage was accepted");
+        };
+        assert!(matches!(*e, MyDatabaseError::InvalidTableOption(_)));
+    }
+}
@@ -1,49 +1,104 @@
 use crate::db_errors::MyDatabaseError;
 use crate::models::db_structure::*;
-use crate::models::utilities::{split_once_skipping_outside_quotes, split_preserving_quote_insides};
-use crate::models::where_parsing::WhereClause;
+use crate::models::utilities::{split_at_keywords_outside_brackets, split_once_skipping_outside_quotes, split_outside_brackets, split_preserving_quote_insides, split_quoted_prefix};
+use crate::models::data_files::{self, DataFileFormat};
+use crate::models::auth::{Privilege, RequiredPermission};
+use crate::models::where_parsing::{ProjectedColumn, SubqueryParser, SubquerySelect, WhereClause};
+use crate::models::result_set::ResultSet;
+use crate::models::set_operations::{remove_duplicates, SetOperator};
+use crate::models::session::Session;
+use crate::models::storage::StorageKind;
+use crate::models::save_file;
+use crate::models::output_format::OutputFormat;
 use std::collections::HashMap;
-use std::fs::{self, File};
-use std::io::{self, Write, BufRead};
 use std::path::Path;
+/// What an executed command gives back
+#[derive(Debug)]
+pub enum CommandOutput {
+    /// Rows of a query, rendered by the caller
+    Rows(ResultSet),
+    /// Text telling what the command did
+    Message(String),
+    Done,
+}
+impl CommandOutput {
+    /// Rows of a query, None for other commands
+    pub fn into_rows(self) -> Option<ResultSet> {
+        match self {
+            CommandOutput::Rows(result) => Some(result),
+            _ => None,
+        }
+    }
+    /// Appends the rows in the output format of the session, or the message
+    pub fn render(&self, session: &Session, response_buf: &mut String) {
+        match self {
+            CommandOutput::Rows(result) => session.render(result, response_buf),
+            CommandOutput::Message(message) => response_buf.push_str(message),
+            CommandOutput::Done => {},
+        }
+    }
+}
 pub trait Command<'b> {
-    fn execute(self, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError>;
-    fn parse_input<'a>(input: &'a str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized;
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError>;
+    fn parse_input<'a>(input: &'a str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized;
 }
 #[derive(Debug)]
 pub enum AnyCommand<'b> {
     CreateTable(CreateTableCmd<'b>),
-    InsertRecord(InsertRecordCmd<'b>),
-    DeleteRecord(DeleteRecordCmd<'b>),
-    Select(SelectCmd<'b>),
+    InsertRecord(InsertRecordCmd),
+    DeleteRecord(DeleteRecordCmd),
+    Select(SelectCmd),
     SaveAs(SaveAsCmd),
     ReadFrom(ReadFromCmd<'b>),
+    Set(SetCmd),
+    Import(ImportCmd),
+    Export(ExportCmd),
+    Explain(ExplainCmd),
 }
 impl<'b> AnyCommand<'b> {
-    pub fn create_and_execute(input: &str, context_db: &'b mut AnyDatabase, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
+    /// Parses and executes a single command. Queries return their result set and other commands a message, the caller renders them
+    pub fn create_and_execute(input: &str, context_db: &'b AnyDatabase, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        AnyCommand::create_check_and_execute(input, context_db, session, |_| Ok(()))
+    }
+    /// Same as create_and_execute, but the parsed command has to pass the check before it is executed
+    pub fn create_check_and_execute<F>(input: &str, context_db: &'b AnyDatabase, session: &mut Session, check: F) -> Result<CommandOutput, MyDatabaseError>
+    where F: FnOnce(&AnyCommand) -> Result<(), MyDatabaseError> {
         match AnyCommand::parse_input(input, context_db) {
             Ok(cmd) => {
-                if let Err(e) = cmd.execute(executed_commands, response_buf) {
-                    return Err(MyDatabaseError::CommandExecuteError(Box::new(e)));
-                }
-            }
-            Err(e) => return Err(MyDatabaseError::CommandParseError(Box::new(e))),
+                check(&cmd)?;
+                cmd.execute(session).map_err(|e| MyDatabaseError::CommandExecuteError(Box::new(e)))
+            },
+            Err(e) => Err(MyDatabaseError::CommandParseError(Box::new(e))),
+        }
+    }
+    pub fn required_permission(&self) -> RequiredPermission {
+        match self {
+            AnyCommand::CreateTable(cmd) => RequiredPermission::Table(Privilege::Ddl, cmd.name.clone()),
+            AnyCommand::InsertRecord(cmd) => RequiredPermission::Table(Privilege::Insert, cmd.table_name.clone()),
+            AnyCommand::DeleteRecord(cmd) => RequiredPermission::Table(Privilege::Delete, cmd.table_name.clone()),
+            AnyCommand::Select(cmd) => RequiredPermission::Tables(Privilege::Select, cmd.tables_read()),
+            AnyCommand::Explain(cmd) => RequiredPermission::Tables(Privilege::Select, cmd.select.tables_read()),
+            AnyCommand::SaveAs(_) | AnyCommand::ReadFrom(_) | AnyCommand::Import(_) | AnyCommand::Export(_) => RequiredPermission::Admin,
+            AnyCommand::Set(_) => RequiredPermission::None,
         }
-        Ok(())
     }
 }
 impl<'b> Command<'b> for AnyCommand<'b> {
-    fn execute(self, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
         match self {
-            AnyCommand::CreateTable(cmd) => cmd.execute(executed_commands, response_buf),
-            AnyCommand::InsertRecord(cmd) => cmd.execute(executed_commands, response_buf),
-            AnyCommand::DeleteRecord(cmd) => cmd.execute(executed_commands, response_buf),
-            AnyCommand::Select(cmd) => cmd.execute(executed_commands, response_buf),
-            AnyCommand::SaveAs(cmd) => cmd.execute(executed_commands, response_buf),
-            AnyCommand::ReadFrom(cmd) => cmd.execute(executed_commands, response_buf),
+            AnyCommand::CreateTable(cmd) => cmd.execute(session),
+            AnyCommand::InsertRecord(cmd) => cmd.execute(session),
+            AnyCommand::DeleteRecord(cmd) => cmd.execute(session),
+            AnyCommand::Select(cmd) => cmd.execute(session),
+            AnyCommand::SaveAs(cmd) => cmd.execute(session),
+            AnyCommand::ReadFrom(cmd) => cmd.execute(session),
+            AnyCommand::Set(cmd) => cmd.execute(session),
+            AnyCommand::Import(cmd) => cmd.execute(session),
+            AnyCommand::Export(cmd) => cmd.execute(session),
+            AnyCommand::Explain(cmd) => cmd.execute(session),
         }
     }
-    fn parse_input<'a>(input: &'a str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input<'a>(input: &'a str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
         let Some((command_type, rest)) = input.trim().split_once(" ") else {
             return Err(MyDatabaseError::InvalidCommandFormat("UNKNOWN"));
         };
@@ -72,6 +127,22 @@ impl<'b> Command<'b> for AnyCommand<'b> {
                 let cmd = ReadFromCmd::parse_input(rest, context_db)?;
                 Ok(AnyCommand::ReadFrom(cmd))
             },
+            "SET" => {
+                let cmd = SetCmd::parse_input(rest, context_db)?;
+                Ok(AnyCommand::Set(cmd))
+            },
+            "IMPORT" => {
+                let cmd = ImportCmd::parse_input(rest, context_db)?;
+                Ok(AnyCommand::Import(cmd))
+            },
+            "EXPORT" => {
+                let cmd = ExportCmd::parse_input(rest, context_db)?;
+                Ok(AnyCommand::Export(cmd))
+            },
+            "EXPLAIN" => {
+                let cmd = ExplainCmd::parse_input(rest, context_db)?;
+                Ok(AnyCommand::Explain(cmd))
+            },
             _ => Err(MyDatabaseError::InvalidCommandFormat("UNKNOWN")),
         }
     }
@@ -80,42 +151,43 @@ impl<'b> Command<'b> for AnyCommand<'b> {
 #[derive(Debug)]
 pub struct CreateTableCmd<'a> {
     original_string: String,
-    db: &'a mut AnyDatabase,
+    db: &'a AnyDatabase,
     name: String,
     key_name: String,
-    fields: HashMap<String, ValueType>
+    fields: Vec<(String, ValueType)>,
+    storage: StorageKind,
 }
 impl<'b> Command<'b> for CreateTableCmd<'b> {
-    fn execute(self, executed_commands: &mut Vec<String>, _response_buf: &mut String) -> Result<(), MyDatabaseError> {
-        match self.db.create_table(&self.name, &self.key_name, self.fields) {
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        match self.db.create_table(&self.name, &self.key_name, self.fields, self.storage) {
             Ok(_) => {
-                executed_commands.push(self.original_string);
-                Ok(())
+                session.record_command(self.original_string);
+                Ok(CommandOutput::Done)
             }
             Err(e) => Err(e),
         }
     }
-    fn parse_input<'a>(input: &'a str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input<'a>(input: &'a str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
         let Some((name, rest)) = input.split_once("KEY") else {
             return Err(MyDatabaseError::InvalidCommandFormat("CREATE"));
         };
         let Some((key_name, fields_str)) = rest.trim().split_once("FIELDS") else {
             return Err(MyDatabaseError::InvalidCommandFormat("CREATE"));
         };
+        let (fields_str, storage) = match fields_str.trim_end().strip_suffix(')').and_then(|rest| rest.rsplit_once(" WITH ")) {
+            Some((fields_str, options)) => (fields_str, parse_table_options(options)?),
+            None => (fields_str, StorageKind::default()),
+        };
 
-        let mut fields: HashMap<String, ValueType> = HashMap::new();
+        let mut fields: Vec<(String, ValueType)> = Vec::new();
         for field in fields_str.trim().split(",") {
             let Some((field_name, field_type_str)) = field.trim().split_once(":") else {
                 return Err(MyDatabaseError::InvalidCommandFormat("CREATE"));
             };
-            let field_type = match field_type_str.trim() {
-                "Bool" => ValueType::Bool,
-                "String" => ValueType::String,
-                "Int" => ValueType::Int,
-                "Float" => ValueType::Float,
-                _ => return Err(MyDatabaseError::InvalidFieldType),
+            let Some(field_type) = ValueType::from_name(field_type_str) else {
+                return Err(MyDatabaseError::InvalidFieldType);
             };
-            fields.insert(field_name.trim().to_string(), field_type);
+            fields.push((field_name.trim().to_string(), field_type));
         };
 
         Ok(CreateTableCmd::<'b> {
@@ -124,27 +196,47 @@ impl<'b> Command<'b> for CreateTableCmd<'b> {
             name: name.trim().to_string(),
             key_name: key_name.trim().to_string(),
             fields,
+            storage,
         })
     }
 }
 
+/// Options after WITH, `(storage = row|columnar)` without the closing bracket
+fn parse_table_options(options: &str) -> Result<StorageKind, MyDatabaseError> {
+    let Some(options) = options.trim().strip_prefix('(') else {
+        return Err(MyDatabaseError::InvalidTableOption(options.trim().to_string()));
+    };
+    let mut storage = StorageKind::default();
+    for option in options.split(',') {
+        match option.split_once('=').map(|(name, value)| (name.trim(), value.trim())) {
+            Some((name, value)) if name.eq_ignore_ascii_case("storage") => {
+                storage = StorageKind::from_name(value)
+                    .ok_or_else(|| MyDatabaseError::InvalidTableOption(format!("unknown storage '{}', expected row or columnar", value)))?;
+            }
+            _ => return Err(MyDatabaseError::InvalidTableOption(option.trim().to_string())),
+        }
+    }
+    Ok(storage)
+}
+
 #[derive(Debug)]
-pub struct InsertRecordCmd<'a> {
+pub struct InsertRecordCmd {
     original_string: String,
-    table: AnyTableRef<'a>,
+    table_name: String,
+    table: AnyTableRef,
     values: HashMap<String, Value>
 }
-impl<'b> Command<'b> for InsertRecordCmd<'b> {
-    fn execute(mut self, executed_commands: &mut Vec<String>, _response_buf: &mut String) -> Result<(), MyDatabaseError> {
+impl<'b> Command<'b> for InsertRecordCmd {
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
         match self.table.insert_values(self.values) {
             Ok(_) => {
-                executed_commands.push(self.original_string);
-                Ok(())
+                session.record_command(self.original_string);
+                Ok(CommandOutput::Done)
             }
             Err(e) => Err(e),
         }
     }
-    fn parse_input(input: &str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
         let Some((values, table_name)) = input.split_once("INTO") else {
             return Err(MyDatabaseError::InvalidCommandFormat("INSERT"));
         };
@@ -164,6 +256,7 @@ impl<'b> Command<'b> for InsertRecordCmd<'b> {
         };
         Ok(InsertRecordCmd {
             original_string: format!("INSERT {}", input),
+            table_name: table_name.trim().to_string(),
             table,
             values: values_map,
         })
@@ -171,22 +264,23 @@ impl<'b> Command<'b> for InsertRecordCmd<'b> {
 }
 
 #[derive(Debug)]
-pub struct DeleteRecordCmd<'a> {
+pub struct DeleteRecordCmd {
     original_string: String,
-    table: AnyTableRef<'a>,
+    table_name: String,
+    table: AnyTableRef,
     key_as_string: String,
 }
-impl<'b> Command<'b> for DeleteRecordCmd<'b> {
-    fn execute(mut self, executed_commands: &mut Vec<String>, _response_buf: &mut String) -> Result<(), MyDatabaseError> {
+impl<'b> Command<'b> for DeleteRecordCmd {
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
         match self.table.delete_key(self.key_as_string) {
             Ok(_) => {
-                executed_commands.push(self.original_string);
-                Ok(())
+                session.record_command(self.original_string);
+                Ok(CommandOutput::Done)
             }
             Err(e) => Err(e),
         }
     }
-    fn parse_input(input: &str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
         let Some((mut key_str, table_name)) = input.split_once("FROM") else {
             return Err(MyDatabaseError::InvalidCommandFormat("DELETE"));
         };
@@ -197,6 +291,7 @@ impl<'b> Command<'b> for DeleteRecordCmd<'b> {
         }
         Ok(DeleteRecordCmd {
             original_string: format!("DELETE {}", input),
+            table_name: table_name.trim().to_string(),
             table,
             key_as_string: key_str.to_string(),
         })
@@ -204,24 +299,73 @@ impl<'b> Command<'b> for DeleteRecordCmd<'b> {
 }
 
 #[derive(Debug)]
-pub struct SelectCmd<'a> {
+pub struct SelectCmd {
     original_string: String,
-    table: AnyTableRef<'a>,
-    values_to_select: Vec<String>,
+    table_name: String,
+    table: AnyTableRef,
+    projection: Vec<ProjectedColumn>,
+    distinct: bool,
     condition: Option<WhereClause>,
+    combined: Vec<(SetOperator, SelectCmd)>, // queries joined by UNION, INTERSECT or EXCEPT
 }
 
-impl<'b> Command<'b> for SelectCmd<'b> {
-    fn execute(mut self, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
-        match self.table.select_and_display(&self.values_to_select, &self.condition, response_buf) {
-            Ok(_) => {
-                executed_commands.push(self.original_string);
-                Ok(())
+impl SelectCmd {
+    /// Runs the query and the ones combined with it on one snapshot taken now
+    pub fn run(&mut self) -> Result<ResultSet, MyDatabaseError> {
+        let snapshot = self.table.snapshot();
+        self.run_at(&snapshot)
+    }
+    /// Runs the query and the ones combined with it. INTERSECT binds tighter than UNION and EXCEPT, like in SQL
+    fn run_at(&mut self, snapshot: &Snapshot) -> Result<ResultSet, MyDatabaseError> {
+        let mut terms = vec![self.run_single(snapshot)?];
+        let mut operators = Vec::new();
+        for (operator, select) in &mut self.combined {
+            let result = select.run_single(snapshot)?;
+            if *operator == SetOperator::Intersect
+                && let Some(last) = terms.pop() {
+                terms.push(SetOperator::Intersect.combine(last, result)?);
+            } else {
+                operators.push(*operator);
+                terms.push(result);
             }
-            Err(e) => Err(e),
         }
+        let mut terms = terms.into_iter();
+        let Some(mut result) = terms.next() else {
+            return Err(MyDatabaseError::InvalidCommandFormat("SELECT"));
+        };
+        for (operator, term) in operators.into_iter().zip(terms) {
+            result = operator.combine(result, term)?;
+        }
+        Ok(result)
+    }
+    /// Runs this query alone, subqueries of its WHERE clause are run first
+    fn run_single(&mut self, snapshot: &Snapshot) -> Result<ResultSet, MyDatabaseError> {
+        if let Some(condition) = &mut self.condition {
+            condition.run_subqueries(snapshot)?;
+        }
+        let result = self.table.select_at(snapshot, &self.projection, &self.condition)?;
+        Ok(if self.distinct { remove_duplicates(result) } else { result })
     }
-    fn parse_input(input: &str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    pub fn column_types(&self) -> Vec<ValueType> {
+        let mut types: Vec<ValueType> = self.projection.iter().map(ProjectedColumn::value_type).collect();
+        for (operator, select) in &self.combined {
+            // the queries were checked to fit when they were parsed
+            types = operator.combined_types(&types, &select.column_types()).unwrap_or(types);
+        }
+        types
+    }
+    /// The queried tables and tables of their subqueries
+    pub fn tables_read(&self) -> Vec<String> {
+        let mut tables = vec![self.table_name.clone()];
+        if let Some(condition) = &self.condition {
+            tables.extend(condition.subquery_tables());
+        }
+        for (_, select) in &self.combined {
+            tables.extend(select.tables_read());
+        }
+        tables
+    }
+    fn parse_single(input: &str, context_db: &AnyDatabase) -> Result<SelectCmd, MyDatabaseError> {
         let Some((fields, rest)) = input.split_once("FROM") else {
             return Err(MyDatabaseError::InvalidCommandFormat("SELECT"));
         };
@@ -231,99 +375,305 @@ impl<'b> Command<'b> for SelectCmd<'b> {
             (rest.trim(), None)
         };
         let table = context_db.get_table_by_name(table_name)?;
-        let mut values_to_select: Vec<String> = Vec::new();
+        let structure = table.get_structure();
+        let (distinct, fields) = match fields.trim().strip_prefix("DISTINCT ") {
+            Some(fields) => (true, fields),
+            None => (false, fields),
+        };
+        let mut projection = Vec::new();
         if fields.trim() == "*" {
-            values_to_select = table.get_all_columns();
+            projection = table.get_all_columns_projection();
         }
         else {
-            for field in split_preserving_quote_insides(fields, ',') {
-                values_to_select.push(field.trim().to_string());
+            for field in split_outside_brackets(fields, ',') {
+                projection.push(ProjectedColumn::parse(field, &structure)?);
             }
         }
 
         let condition = if let Some(cond_str) = condition {
-            Some(WhereClause::create_from_string(cond_str, table.get_structure())?)
+            Some(WhereClause::create_with_subqueries(cond_str, &structure, context_db)?)
         } else {
             None
         };
 
-        println!("Where condition: {:?}", condition);
-        
         Ok(SelectCmd {
             original_string: format!("SELECT {}", input),
+            table_name: table_name.to_string(),
             table,
-            values_to_select,
+            projection,
+            distinct,
             condition,
+            combined: Vec::new(),
         })
     }
 }
 
+impl<'b> Command<'b> for SelectCmd {
+    fn execute(mut self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        match self.run() {
+            Ok(result) => {
+                session.record_command(self.original_string);
+                Ok(CommandOutput::Rows(result))
+            }
+            Err(e) => Err(e),
+        }
+    }
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        let mut parts = split_at_keywords_outside_brackets(input, &SetOperator::KEYWORDS).into_iter();
+        let Some((_, first)) = parts.next() else {
+            return Err(MyDatabaseError::InvalidCommandFormat("SELECT"));
+        };
+        let mut select = SelectCmd::parse_single(first, context_db)?;
+        for (keyword, part) in parts {
+            let (Some(operator), Some(("SELECT", part))) = (keyword.and_then(SetOperator::from_keyword), part.split_once(' ')) else {
+                return Err(MyDatabaseError::InvalidCommandFormat("SELECT"));
+            };
+            let other = SelectCmd::parse_single(part, context_db)?;
+            operator.combined_types(&select.column_types(), &other.column_types())?;
+            select.combined.push((operator, other));
+        }
+        select.original_string = format!("SELECT {}", input);
+        Ok(select)
+    }
+}
+
+impl SubquerySelect for SelectCmd {
+    fn column_types(&self) -> Vec<ValueType> {
+        SelectCmd::column_types(self)
+    }
+    fn tables_read(&self) -> Vec<String> {
+        SelectCmd::tables_read(self)
+    }
+    fn run(&mut self, snapshot: &Snapshot) -> Result<Vec<Value>, MyDatabaseError> {
+        let result = self.run_at(snapshot)?;
+        Ok(result.into_rows().into_iter().filter_map(|row| row.into_iter().next()).collect())
+    }
+}
+
+/// Subqueries of a clause are SELECTs on tables of the database
+impl SubqueryParser for AnyDatabase {
+    fn parse_select(&self, query: &str) -> Result<Box<dyn SubquerySelect>, MyDatabaseError> {
+        Ok(Box::new(SelectCmd::parse_input(query, self)?))
+    }
+}
+
+/// `SAVE_AS <file> [COMPRESSED]`. Rows loaded by IMPORT are not saved, the file has to be imported again after READ_FROM
 #[derive(Debug)]
 pub struct SaveAsCmd {
     filename: String,
+    compress: bool,
 }
 impl<'b> Command<'b> for SaveAsCmd {
-    fn execute(self, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
-        let path = Path::new(&self.filename);
-        if let Some(parent) = path.parent() {
-            if let Err(e) = fs::create_dir_all(parent) {
-                return Err(MyDatabaseError::IoError(e));
-            }
-        }
-        let mut file: fs::File = match fs::File::create(&self.filename) {
-            Ok(f) => f,
-            Err(e) => return Err(MyDatabaseError::IoError(e)),
-        };
-        for command in executed_commands {
-            if let Err(e) = writeln!(file, "{}", command) {
-                return Err(MyDatabaseError::IoError(e));
-            }
-        }
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        save_file::save(Path::new(&self.filename), session.executed_commands(), self.compress)?;
         // println!("Commands saved to {}", self.filename);
-        response_buf.push_str(&format!("Commands saved to {}\n", self.filename));
-        Ok(())
+        Ok(CommandOutput::Message(format!("Commands saved to {}\n", self.filename)))
     }
-    fn parse_input(input: &str, _context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input(input: &str, _context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        let input = input.trim();
+        let (filename, compress) = match input.strip_suffix("COMPRESSED") {
+            Some(filename) if filename.ends_with(char::is_whitespace) => (filename.trim(), true),
+            _ => (input, false),
+        };
+        if filename.is_empty() {
+            return Err(MyDatabaseError::InvalidCommandFormat("SAVE_AS"));
+        }
         Ok(SaveAsCmd {
-            filename: input.trim().to_string(),
+            filename: filename.to_string(),
+            compress,
         })
     }
 }
 
 #[derive(Debug)]
 pub struct ReadFromCmd<'a> {
-    db: &'a mut AnyDatabase,
+    db: &'a AnyDatabase,
     filename: String,
 }
 impl<'b> Command<'b> for ReadFromCmd<'b> {
-    fn execute(self, executed_commands: &mut Vec<String>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
-        let path = Path::new(&self.filename);
-        let file = match File::open(path) {
-            Ok(f) => f,
-            Err(e) => return Err(MyDatabaseError::IoError(e)),
-        };
-        let reader = io::BufReader::new(file);
-        response_buf.push_str("Reading and executing commands below:\n");
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        // the whole file is verified first, so a damaged one changes nothing
+        let commands = save_file::load(Path::new(&self.filename))?;
+        let mut output = String::from("Reading and executing commands below:\n");
         // println!("Reading and executing commands below:\n");
-        for line_result in reader.lines() {
-            match line_result {
-                Ok(l) => {
-                    if l.trim().is_empty() {
-                        continue;
-                    }
-                    response_buf.push_str(l.as_str());
-                    response_buf.push('\n');
-                    AnyCommand::create_and_execute(l.as_str(), self.db, executed_commands, response_buf)?;
-                }
-                Err(e) => return Err(MyDatabaseError::IoError(e)),
-            };
+        for command in commands {
+            output.push_str(command.as_str());
+            output.push('\n');
+            AnyCommand::create_and_execute(command.as_str(), self.db, session)?.render(session, &mut output);
         }
-        Ok(())
+        Ok(CommandOutput::Message(output))
     }
-    fn parse_input(input: &str, context_db: &'b mut AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
         Ok(ReadFromCmd {
             db: context_db,
             filename: input.trim().to_string(),
         })
     }
-}
\ No newline at end of file
+}
+#[derive(Debug)]
+pub enum SetCmd {
+    Format(OutputFormat),
+}
+impl<'b> Command<'b> for SetCmd {
+    fn execute(self, session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        // session settings are not data changes, so they are not recorded for SAVE_AS
+        match self {
+            SetCmd::Format(format) => {
+                session.set_output_format(format);
+                Ok(CommandOutput::Message(format!("Output format set to {}\n", format)))
+            }
+        }
+    }
+    fn parse_input(input: &str, _context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        let Some((setting, value)) = input.trim().split_once(" ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("SET"));
+        };
+        match setting {
+            "FORMAT" => Ok(SetCmd::Format(value.parse()?)),
+            _ => Err(MyDatabaseError::InvalidCommandFormat("SET")),
+        }
+    }
+}
+
+/// IMPORT is not recorded for SAVE_AS, replaying it would read a data file that may have moved or changed since
+#[derive(Debug)]
+pub struct ImportCmd {
+    table: AnyTableRef,
+    format: DataFileFormat,
+    filename: String,
+    has_header: bool,
+}
+impl<'b> Command<'b> for ImportCmd {
+    fn execute(self, _session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        let path = Path::new(&self.filename);
+        let rows = match self.format {
+            DataFileFormat::Csv => data_files::read_csv_rows(path, &self.table, self.has_header)?,
+            DataFileFormat::Jsonl => data_files::read_jsonl_rows(path, &self.table)?,
+        };
+        let inserted = self.table.insert_batch(rows)?;
+        Ok(CommandOutput::Message(format!("Imported {} records from {}\n", inserted, self.filename)))
+    }
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        // IMPORT <format> '<file>' INTO <table> [HEADER]
+        let Some((format_str, rest)) = input.trim().split_once(" ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("IMPORT"));
+        };
+        let Some(format) = DataFileFormat::from_keyword(format_str) else {
+            return Err(MyDatabaseError::InvalidCommandFormat("IMPORT"));
+        };
+        let Some((filename, rest)) = split_quoted_prefix(rest) else {
+            return Err(MyDatabaseError::InvalidCommandFormat("IMPORT"));
+        };
+        let Some(rest) = rest.strip_prefix("INTO") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("IMPORT"));
+        };
+        let (table_name, has_header) = match rest.split_whitespace().collect::<Vec<&str>>()[..] {
+            [table_name] => (table_name, false),
+            [table_name, "HEADER"] => (table_name, true),
+            _ => return Err(MyDatabaseError::InvalidCommandFormat("IMPORT")),
+        };
+        if has_header && format != DataFileFormat::Csv {
+            return Err(MyDatabaseError::InvalidCommandFormat("IMPORT. HEADER is only valid for CSV"));
+        }
+        let table = context_db.get_table_by_name(table_name)?;
+        Ok(ImportCmd {
+            table,
+            format,
+            filename: filename.to_string(),
+            has_header,
+        })
+    }
+}
+
+/// EXPORT only reads the table, so it is not recorded for SAVE_AS
+#[derive(Debug)]
+pub struct ExportCmd {
+    table: AnyTableRef,
+    format: DataFileFormat,
+    filename: String,
+}
+impl<'b> Command<'b> for ExportCmd {
+    fn execute(self, _session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        let path = Path::new(&self.filename);
+        let result = self.table.select(&self.table.get_all_columns_projection(), &None)?;
+        let written = match self.format {
+            DataFileFormat::Csv => data_files::write_csv(path, &result)?,
+            DataFileFormat::Jsonl => data_files::write_jsonl(path, &result)?,
+        };
+        Ok(CommandOutput::Message(format!("Exported {} records to {}\n", written, self.filename)))
+    }
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        // EXPORT <table> TO <format> '<file>'
+        let Some((table_name, rest)) = input.split_once(" TO ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("EXPORT"));
+        };
+        let Some((format_str, rest)) = rest.trim().split_once(" ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("EXPORT"));
+        };
+        let Some(format) = DataFileFormat::from_keyword(format_str) else {
+            return Err(MyDatabaseError::InvalidCommandFormat("EXPORT"));
+        };
+        let Some((filename, "")) = split_quoted_prefix(rest) else {
+            return Err(MyDatabaseError::InvalidCommandFormat("EXPORT"));
+        };
+        let table = context_db.get_table_by_name(table_name.trim())?;
+        Ok(ExportCmd {
+            table,
+            format,
+            filename: filename.to_string(),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ExplainCmd {
+    select: SelectCmd,
+}
+impl ExplainCmd {
+    fn describe(select: &SelectCmd, response_buf: &mut String) -> Result<(), MyDatabaseError> {
+        let key_name = select.table.get_key_name();
+        let projection: Vec<String> = select.projection.iter().map(ProjectedColumn::to_string).collect();
+        let distinct = if select.distinct { "DISTINCT " } else { "" };
+        response_buf.push_str(&format!("Projection: {}{}\n", distinct, projection.join(", ")));
+        let access_path = match select.condition.as_ref().and_then(|cond| cond.key_range(&key_name)) {
+            Some(range) if range.is_single_key() => format!("key lookup on {} ({})", select.table_name, range.describe(&key_name)),
+            Some(range) => format!("key range scan on {} ({})", select.table_name, range.describe(&key_name)),
+            None => format!("full scan of {}", select.table_name),
+        };
+        response_buf.push_str(&format!("Access path: {}\n", access_path));
+        match &select.condition {
+            Some(cond) => {
+                let mut lines = cond.explain()?.into_iter();
+                if let Some(infix) = lines.next() {
+                    response_buf.push_str(&format!("Filter: {}\n", infix));
+                }
+                for line in lines {
+                    response_buf.push_str(&format!("  {}\n", line));
+                }
+            },
+            None => response_buf.push_str("Filter: none\n"),
+        }
+        Ok(())
+    }
+}
+impl<'b> Command<'b> for ExplainCmd {
+    fn execute(self, _session: &mut Session) -> Result<CommandOutput, MyDatabaseError> {
+        // the query is only described, so there is nothing to record for SAVE_AS
+        let mut output = String::new();
+        ExplainCmd::describe(&self.select, &mut output)?;
+        for (operator, select) in &self.select.combined {
+            output.push_str(&format!("{}\n", operator.keyword()));
+            ExplainCmd::describe(select, &mut output)?;
+        }
+        Ok(CommandOutput::Message(output))
+    }
+    fn parse_input(input: &str, context_db: &'b AnyDatabase) -> Result<Self, MyDatabaseError> where Self: Sized {
+        // EXPLAIN SELECT ...
+        let Some(("SELECT", rest)) = input.trim().split_once(" ") else {
+            return Err(MyDatabaseError::InvalidCommandFormat("EXPLAIN"));
+        };
+        Ok(ExplainCmd {
+            select: SelectCmd::parse_input(rest, context_db)?,
+        })
+    }
+}
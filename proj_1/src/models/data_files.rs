@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{AnyTableRef, NumberedRow, Value, ValueType};
+use crate::models::output_format::{csv_line, json_object_line};
+use crate::models::result_set::ResultSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DataFileFormat {
+    Csv,
+    Jsonl,
+}
+impl DataFileFormat {
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword.trim() {
+            "CSV" => Some(DataFileFormat::Csv),
+            "JSONL" => Some(DataFileFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+/// Translates byte offsets to 1-based line numbers, offsets have to be asked for in increasing order
+struct LineCounter<'a> {
+    content: &'a [u8],
+    offset: usize,
+    line: usize,
+}
+impl<'a> LineCounter<'a> {
+    fn new(content: &'a [u8]) -> Self {
+        LineCounter { content, offset: 0, line: 1 }
+    }
+    fn line_at(&mut self, byte: usize) -> usize {
+        // for CRLF the reported offset can point at the line break before the record
+        let mut byte = byte.min(self.content.len());
+        while byte < self.content.len() && matches!(self.content[byte], b'\r' | b'\n') {
+            byte += 1;
+        }
+        if byte > self.offset {
+            self.line += self.content[self.offset..byte].iter().filter(|b| **b == b'\n').count();
+            self.offset = byte;
+        }
+        self.line
+    }
+}
+
+/// Reads a CSV file (RFC 4180) and converts its records to typed table rows.
+/// Without a header the fields are expected in the order the table columns were declared.
+/// All conversion errors are collected, each with the line the record starts on.
+pub fn read_csv_rows(path: &Path, table: &AnyTableRef, has_header: bool) -> Result<Vec<NumberedRow>, MyDatabaseError> {
+    let content = fs::read(path)?;
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(content.as_slice());
+    // csv reports wrong line numbers for CRLF files, so lines are counted from byte offsets
+    let mut line_counter = LineCounter::new(&content);
+    let structure = table.get_structure();
+
+    let mut records = reader.records();
+    let columns: Vec<String> = if has_header {
+        let Some(header) = records.next() else {
+            return Ok(Vec::new());
+        };
+        let header = header?;
+        let mut seen = HashSet::new();
+        let mut columns = Vec::new();
+        for name in header.iter() {
+            let name = name.trim();
+            if !structure.contains_key(name) {
+                return Err(MyDatabaseError::ImportFailed(vec![(1, MyDatabaseError::InvalidFieldName)]));
+            }
+            if !seen.insert(name.to_string()) {
+                return Err(MyDatabaseError::ImportFailed(vec![(1, MyDatabaseError::DuplicateColumnName)]));
+            }
+            columns.push(name.to_string());
+        }
+        columns
+    } else {
+        table.get_all_columns()
+    };
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for record in records {
+        let record = match record {
+            Ok(r) => r,
+            Err(e) => {
+                let line = e.position().map(|p| line_counter.line_at(p.byte() as usize)).unwrap_or(0);
+                errors.push((line, MyDatabaseError::CsvError(e)));
+                continue;
+            }
+        };
+        let line = record.position().map(|p| line_counter.line_at(p.byte() as usize)).unwrap_or(0);
+        if record.len() != columns.len() {
+            errors.push((line, MyDatabaseError::FieldCountMismatch(columns.len(), record.len())));
+            continue;
+        }
+        let mut values = HashMap::new();
+        for (column, field) in columns.iter().zip(record.iter()) {
+            let Some(field_type) = structure.get(column) else {
+                return Err(MyDatabaseError::InvalidFieldName); // columns were checked above
+            };
+            match field_type.get_value(field) {
+                Ok(value) => {
+                    values.insert(column.clone(), value);
+                },
+                Err(e) => {
+                    errors.push((line, e));
+                    break;
+                }
+            }
+        }
+        if values.len() == columns.len() {
+            rows.push((line, values));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(MyDatabaseError::ImportFailed(errors));
+    }
+    Ok(rows)
+}
+
+/// Writes the header and all rows as CSV, returns number of written records
+pub fn write_csv(path: &Path, result: &ResultSet) -> Result<usize, MyDatabaseError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(csv_line(result.columns()).as_bytes())?;
+    for row in result.rows() {
+        let fields: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+        writer.write_all(csv_line(&fields).as_bytes())?;
+    }
+    writer.flush()?;
+    Ok(result.len())
+}
+
+/// Converts a JSON value to a column value, integers are accepted for Float columns,
+/// as well as the strings "NaN", "inf" and "-inf" written for floats JSON can't represent
+fn json_to_value(column: &str, json: &serde_json::Value, expected: ValueType) -> Result<Value, MyDatabaseError> {
+    let value = match (expected, json) {
+        (ValueType::Bool, serde_json::Value::Bool(b)) => Some(Value::Bool(*b)),
+        (ValueType::Int, serde_json::Value::Number(n)) => n.as_i64().map(Value::Int),
+        (ValueType::Float, serde_json::Value::Number(n)) => n.as_f64().map(Value::Float),
+        (ValueType::Float, serde_json::Value::String(s)) => match s.as_str() {
+            "NaN" => Some(Value::Float(f64::NAN)),
+            "inf" => Some(Value::Float(f64::INFINITY)),
+            "-inf" => Some(Value::Float(f64::NEG_INFINITY)),
+            _ => None,
+        },
+        (ValueType::String, serde_json::Value::String(s)) => Some(Value::String(s.clone())),
+        _ => None,
+    };
+    value.ok_or_else(|| MyDatabaseError::JsonTypeMismatch(column.to_string(), expected, json.to_string()))
+}
+
+/// Reads one JSON object per line, keys have to match the table columns exactly
+pub fn read_jsonl_rows(path: &Path, table: &AnyTableRef) -> Result<Vec<NumberedRow>, MyDatabaseError> {
+    let content = fs::read_to_string(path)?;
+    let columns = table.get_all_columns();
+    let structure = table.get_structure();
+
+    let mut rows = Vec::new();
+    let mut errors = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line_number = index + 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let object = match serde_json::from_str::<serde_json::Value>(line) {
+            Ok(serde_json::Value::Object(object)) => object,
+            Ok(_) => {
+                errors.push((line_number, MyDatabaseError::InvalidJson("expected an object".to_string())));
+                continue;
+            },
+            Err(e) => {
+                errors.push((line_number, MyDatabaseError::InvalidJson(e.to_string())));
+                continue;
+            }
+        };
+
+        let mut values = HashMap::new();
+        let mut line_ok = true;
+        for (field, json) in &object {
+            let Some(field_type) = structure.get(field) else {
+                errors.push((line_number, MyDatabaseError::JsonUnknownField(field.clone())));
+                line_ok = false;
+                break;
+            };
+            match json_to_value(field, json, *field_type) {
+                Ok(value) => {
+                    values.insert(field.clone(), value);
+                },
+                Err(e) => {
+                    errors.push((line_number, e));
+                    line_ok = false;
+                    break;
+                }
+            }
+        }
+        if !line_ok {
+            continue;
+        }
+        if let Some(missing) = columns.iter().find(|c| !object.contains_key(*c)) {
+            errors.push((line_number, MyDatabaseError::JsonMissingField(missing.clone())));
+            continue;
+        }
+        rows.push((line_number, values));
+    }
+    if !errors.is_empty() {
+        return Err(MyDatabaseError::ImportFailed(errors));
+    }
+    Ok(rows)
+}
+
+/// Writes every row as a JSON object on its own line, returns number of written records
+pub fn write_jsonl(path: &Path, result: &ResultSet) -> Result<usize, MyDatabaseError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    for row in result.rows() {
+        writer.write_all(json_object_line(result.columns(), row).as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()?;
+    Ok(result.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::db_structure::{AnyDatabase, Database, Value, ValueType};
+    use crate::models::commands::AnyCommand;
+    use crate::models::session::Session;
+    use crate::models::test_utils::run;
+    use crate::db_errors::MyDatabaseError;
+
+    #[test]
+    fn csv_export_and_import_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("round_trip.csv");
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE t KEY id FIELDS id: Int, name: String, ok: Bool").unwrap();
+        run(&db, "INSERT id = 1, name = \"a, \"\"b\"\"\", ok = true INTO t").unwrap();
+        run(&db, "INSERT id = 2, name = \"plain\", ok = false INTO t").unwrap();
+        run(&db, &format!("EXPORT t TO CSV '{}'", path.display())).unwrap();
+
+        run(&db, "CREATE copy KEY id FIELDS id: Int, name: String, ok: Bool").unwrap();
+        run(&db, &format!("IMPORT CSV '{}' INTO copy HEADER", path.display())).unwrap();
+        let original = run(&db, "SELECT * FROM t").unwrap().unwrap();
+        let copied = run(&db, "SELECT * FROM copy").unwrap().unwrap();
+        assert_eq!(original.rows(), copied.rows());
+    }
+
+    #[test]
+    fn jsonl_import_checks_types_against_structure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("import.jsonl");
+        let db = AnyDatabase::StringDatabase(Database::<String>::new());
+        run(&db, "CREATE t KEY id FIELDS id: String, qty: Int, price: Float, ok: Bool").unwrap();
+
+        std::fs::write(&path, "{\"id\":\"a\",\"qty\":1,\"price\":2,\"ok\":true}\n\n{\"id\":\"b\",\"qty\":1.5,\"price\":2.5,\"ok\":false}\n").unwrap();
+        let Err(MyDatabaseError::CommandExecuteError(e)) = run(&db, &format!("IMPORT JSONL '{}' INTO t", path.display())) else {
+            panic!("import should fail");
+        };
+        let MyDatabaseError::ImportFailed(errors) = *e else {
+            panic!("expected per line errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 3);
+        assert!(matches!(&errors[0].1, MyDatabaseError::JsonTypeMismatch(column, ValueType::Int, _) if column == "qty"));
+
+        std::fs::write(&path, "{\"id\":\"a\",\"qty\":1,\"price\":2,\"ok\":true}\n").unwrap();
+        run(&db, &format!("IMPORT JSONL '{}' INTO t", path.display())).unwrap();
+        run(&db, &format!("EXPORT t TO JSONL '{}'", path.display())).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "{\"id\":\"a\",\"qty\":1,\"price\":2.0,\"ok\":true}\n");
+
+        // floats without a JSON number survive the round trip as strings
+        run(&db, "CREATE special KEY id FIELDS id: String, x: Float").unwrap();
+        std::fs::write(&path, "{\"id\":\"nan\",\"x\":\"NaN\"}\n{\"id\":\"pos\",\"x\":\"inf\"}\n{\"id\":\"neg\",\"x\":\"-inf\"}\n").unwrap();
+        run(&db, &format!("IMPORT JSONL '{}' INTO special", path.display())).unwrap();
+        run(&db, &format!("EXPORT special TO JSONL '{}'", path.display())).unwrap();
+        let exported = std::fs::read_to_string(&path).unwrap();
+        assert!(exported.contains("{\"id\":\"nan\",\"x\":\"NaN\"}") && exported.contains("\"x\":\"inf\"") && exported.contains("\"x\":\"-inf\""));
+        std::fs::write(&path, "{\"id\":\"bad\",\"x\":\"infinity\"}\n").unwrap();
+        assert!(run(&db, &format!("IMPORT JSONL '{}' INTO special", path.display())).is_err());
+    }
+
+    #[test]
+    fn failed_csv_import_inserts_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failed_import.csv");
+        std::fs::write(&path, "1,\"first\"\r\n2,\"multi\nline\"\r\nthree,\"bad key\"\r\n").unwrap();
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE t KEY id FIELDS id: Int, name: String").unwrap();
+
+        let Err(MyDatabaseError::CommandExecuteError(e)) = run(&db, &format!("IMPORT CSV '{}' INTO t", path.display())) else {
+            panic!("import should fail");
+        };
+        let MyDatabaseError::ImportFailed(errors) = *e else {
+            panic!("expected per line errors");
+        };
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, 4);
+        assert!(run(&db, "SELECT * FROM t").unwrap().unwrap().is_empty());
+
+        std::fs::write(&path, " 1 ,\" first \"\r\n2,\"multi\nline\"\r\n").unwrap();
+        run(&db, &format!("IMPORT CSV '{}' INTO t", path.display())).unwrap();
+        let result = run(&db, "SELECT name FROM t").unwrap().unwrap();
+        assert_eq!(result.rows()[0][0], Value::String(" first ".to_string()));
+        assert_eq!(result.rows()[1][0], Value::String("multi\nline".to_string()));
+    }
+
+    #[test]
+    fn imports_and_exports_are_not_recorded_for_save_as() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("import.csv");
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        std::fs::write(&path, "1,a\n").unwrap();
+        let mut session = Session::default();
+        AnyCommand::create_and_execute("CREATE t KEY id FIELDS id: Int, name: String", &db, &mut session).unwrap();
+        AnyCommand::create_and_execute(&format!("IMPORT CSV '{}' INTO t", path.display()), &db, &mut session).unwrap();
+        AnyCommand::create_and_execute(&format!("EXPORT t TO JSONL '{}'", dir.path().join("export.jsonl").display()), &db, &mut session).unwrap();
+        assert_eq!(session.executed_commands(), ["CREATE t KEY id FIELDS id: Int, name: String"]);
+    }
+}
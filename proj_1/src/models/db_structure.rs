@@ -1,14 +1,32 @@
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::ops::{Bound, Deref};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use crate::db_errors::MyDatabaseError;
-use crate::models::where_parsing::WhereClause;
-pub trait DatabaseKey {
+use crate::models::where_parsing::{Expression, KeyRange, ProjectedColumn, WhereClause};
+use crate::models::result_set::ResultSet;
+use crate::models::storage::{CompactStorage, Storage, StorageKind, LATEST};
+use crate::models::columnar::ColumnarStorage;
+use crate::models::page_file::PageFile;
+use crate::models::columnar::ColumnVector;
+pub trait DatabaseKey: Ord + Clone + fmt::Debug + Send + Sync + 'static {
+    fn to_value(&self) -> Value;
     fn equals(&self, other: &Self) -> bool;
     fn validate_value_type(s: &ValueType) -> bool;
     fn get_from_value(val: &Value) -> Option<Self> where Self: Sized;
     fn get_from_string(s: String) -> Result<Self, MyDatabaseError> where Self: Sized;
+    /// Key a constant from a WHERE clause stands for, when it's compared with the key column
+    fn get_bound_from_value(val: &Value) -> Option<Self> where Self: Sized {
+        Self::get_from_value(val)
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Value {
     Bool(bool),
     String(String),
@@ -16,25 +34,113 @@ pub enum Value {
     Float(f64),
 }
 impl Value {
+    pub fn get_type(&self) -> ValueType {
+        match self {
+            Value::Bool(_) => ValueType::Bool,
+            Value::String(_) => ValueType::String,
+            Value::Int(_) => ValueType::Int,
+            Value::Float(_) => ValueType::Float,
+        }
+    }
+    /// Order of two values of comparable types. Among floats NaN equals NaN and is bigger than
+    /// any other number, infinities are ordinary values on both ends
+    pub fn compare(&self, other: &Value) -> Result<Ordering, MyDatabaseError> {
+        self.borrowed().compare(other.borrowed()).ok_or(MyDatabaseError::CannotCompareValues)
+    }
+    pub(crate) fn borrowed(&self) -> ValueRef<'_> {
+        match self {
+            Value::Bool(b) => ValueRef::Bool(*b),
+            Value::String(s) => ValueRef::String(s),
+            Value::Int(i) => ValueRef::Int(*i),
+            Value::Float(f) => ValueRef::Float(*f),
+        }
+    }
     pub fn is_bigger_than(&self, other: &Value) -> Result<bool, MyDatabaseError> {
-        match (self, other) {
-            (Value::Int(i1), Value::Int(i2)) => Ok(i1 > i2),
-            (Value::Float(f1), Value::Float(f2)) => Ok(f1 > f2),
-            (Value::Int(i1), Value::Float(f2)) => Ok((*i1 as f64) > *f2),
-            (Value::Float(f1), Value::Int(i2)) => Ok(*f1 > (*i2 as f64)),
-            (Value::String(s1), Value::String(s2)) => Ok(s1 > s2),
-            _ => Err(MyDatabaseError::CannotCompareValues),
+        if matches!(self, Value::Bool(_)) || matches!(other, Value::Bool(_)) {
+            return Err(MyDatabaseError::CannotCompareValues);
         }
+        Ok(self.compare(other)? == Ordering::Greater)
     }
     pub fn is_equal_to(&self, other: &Value) -> bool {
+        self.compare(other).is_ok_and(|ordering| ordering == Ordering::Equal)
+    }
+}
+
+/// Value with a borrowed string, so values kept outside of Value can be compared without copies
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ValueRef<'a> {
+    Bool(bool),
+    String(&'a str),
+    Int(i64),
+    Float(f64),
+}
+impl ValueRef<'_> {
+    /// Order used by Value::compare, None for types that can't be compared
+    pub(crate) fn compare(self, other: ValueRef) -> Option<Ordering> {
+        match (self, other) {
+            (ValueRef::Int(i1), ValueRef::Int(i2)) => Some(i1.cmp(&i2)),
+            (ValueRef::Float(f1), ValueRef::Float(f2)) => Some(compare_floats(f1, f2)),
+            (ValueRef::Int(i1), ValueRef::Float(f2)) => Some(compare_int_float(i1, f2)),
+            (ValueRef::Float(f1), ValueRef::Int(i2)) => Some(compare_int_float(i2, f1).reverse()),
+            (ValueRef::String(s1), ValueRef::String(s2)) => Some(s1.cmp(s2)),
+            (ValueRef::Bool(b1), ValueRef::Bool(b2)) => Some(b1.cmp(&b2)),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn compare_floats(f1: f64, f2: f64) -> Ordering {
+    f1.partial_cmp(&f2).unwrap_or_else(|| f1.is_nan().cmp(&f2.is_nan()))
+}
+
+/// Exact comparison, converting the Int to f64 would round values above 2^53
+pub(crate) fn compare_int_float(i: i64, f: f64) -> Ordering {
+    if f.is_nan() {
+        return Ordering::Less;
+    }
+    // 2^63 is exactly representable, every i64 is below it
+    if f >= 9_223_372_036_854_775_808.0 {
+        return Ordering::Less;
+    }
+    if f < -9_223_372_036_854_775_808.0 {
+        return Ordering::Greater;
+    }
+    let whole = f.trunc();
+    match i.cmp(&(whole as i64)) {
+        Ordering::Equal => compare_floats(0.0, f - whole),
+        ordering => ordering,
+    }
+}
+
+/// Equality of DISTINCT and hashing. Floats are equal when compare says so, so NaN equals NaN and 0.0 equals -0.0.
+/// Unlike is_equal_to, values of different types are never equal
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
         match (self, other) {
-            (Value::Int(i1), Value::Float(f2)) => (*i1 as f64) == *f2,
-            (Value::Float(f1), Value::Int(i2)) => *f1 == (*i2 as f64),
-            _ => self == other,
+            (Value::Float(f1), Value::Float(f2)) => compare_floats(*f1, *f2) == Ordering::Equal,
+            (Value::Int(i1), Value::Int(i2)) => i1 == i2,
+            (Value::String(s1), Value::String(s2)) => s1 == s2,
+            (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
+            _ => false,
+        }
+    }
+}
+impl Eq for Value {}
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Value::Bool(b) => b.hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Int(i) => i.hash(state),
+            // every NaN and both zeros hash alike, as they are equal
+            Value::Float(f) if f.is_nan() => f64::NAN.to_bits().hash(state),
+            Value::Float(f) if *f == 0.0 => 0u64.hash(state),
+            Value::Float(f) => f.to_bits().hash(state),
         }
     }
 }
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum ValueType {
     Bool,
     String,
@@ -42,6 +148,16 @@ pub enum ValueType {
     Float,
 }
 impl ValueType {
+    /// Type from its name as written in CREATE and CAST
+    pub fn from_name(name: &str) -> Option<ValueType> {
+        match name.trim() {
+            "Bool" => Some(ValueType::Bool),
+            "String" => Some(ValueType::String),
+            "Int" => Some(ValueType::Int),
+            "Float" => Some(ValueType::Float),
+            _ => None,
+        }
+    }
     pub fn get_value(&self, s: &str) -> Result<Value, MyDatabaseError> {
         match *self {
                 ValueType::Bool => {
@@ -63,31 +179,67 @@ impl ValueType {
                         Err(_) => Err(MyDatabaseError::InvalidFieldValue),
                     }
                 },
+                // spaces are part of a string, only other types ignore them
                 ValueType::String => {
-                    Ok(Value::String(s.trim().to_string()))
+                    Ok(Value::String(s.to_string()))
                 },
             }
     }
 }
 
-#[derive(Debug)]
-pub struct Record {
-    values: HashMap<String, Value>,   
+/// Column values of one record, tagged with the line of the file they were read from
+pub type NumberedRow = (usize, HashMap<String, Value>);
+
+/// Column values of one record, borrowed from the storage of its table or read from its file
+#[derive(Debug, Clone)]
+pub enum Record<'a> {
+    Named(&'a HashMap<String, Value>),
+    Positional(&'a [Value], &'a HashMap<String, usize>), // values and positions of columns
+    Owned(Vec<Value>, &'a HashMap<String, usize>),
+    Columnar(&'a [ColumnVector], usize, &'a HashMap<String, usize>), // vectors of a columnar table and the slot of the record
 }
-impl Record {
-    pub fn get_value_for_column(&self, column_name: &str) -> Option<&Value> {
-        self.values.get(column_name)
+impl Record<'_> {
+    /// Value of the column, records of columnar tables make only the values that are read
+    pub fn get_value_for_column(&self, column_name: &str) -> Option<Cow<'_, Value>> {
+        match self {
+            Record::Named(values) => values.get(column_name).map(Cow::Borrowed),
+            Record::Positional(values, positions) => positions.get(column_name).and_then(|position| values.get(*position)).map(Cow::Borrowed),
+            Record::Owned(values, positions) => positions.get(column_name).and_then(|position| values.get(*position)).map(Cow::Borrowed),
+            Record::Columnar(vectors, slot, positions) => positions.get(column_name).and_then(|position| vectors.get(*position)).map(|vector| Cow::Owned(vector.get(*slot))),
+        }
     }
 }
 
+/// Number of keys a scan reads before it lets writers in
+pub(crate) const SCAN_CHUNK: usize = 256;
+
 #[derive(Debug)]
 pub struct Table<K: DatabaseKey + Ord> {
     key_name: String,
     structure: HashMap<String, ValueType>, // column name to type
-    records: BTreeMap<K, Record>,
+    column_order: Vec<String>, // columns in the order they were declared
+    storage: Box<dyn Storage<K>>,
+    clock: Arc<Clock>, // shared by the tables of the database
+    last_change: u64, // timestamp of the last change of this table
+    garbage: BTreeSet<K>, // keys with deleted versions
 }
 impl<K: DatabaseKey + Ord> Table<K> {
-    fn insert_values(&mut self, values: HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+    fn new(key_name: String, structure: HashMap<String, ValueType>, column_order: Vec<String>, storage: Box<dyn Storage<K>>, clock: Arc<Clock>, last_change: u64) -> Self {
+        Table {
+            key_name,
+            structure,
+            column_order,
+            storage,
+            clock,
+            last_change,
+            garbage: BTreeSet::new(),
+        }
+    }
+    fn has_live_record(&self, key: &K) -> Result<bool, MyDatabaseError> {
+        Ok(self.storage.get(key, LATEST)?.is_some())
+    }
+    /// Checks that values fit the table and their key is free, returns the key
+    fn check_values(&self, values: &HashMap<String, Value>) -> Result<K, MyDatabaseError> {
         let table_keys: HashSet<&String> = self.structure.keys().collect();
         let value_keys: HashSet<&String> = values.keys().collect();
 
@@ -102,131 +254,460 @@ impl<K: DatabaseKey + Ord> Table<K> {
             return Err(MyDatabaseError::KeysMismatch); // shouldn't happen due to earlier check
         };
 
-        if self.records.contains_key(&key) {
+        if self.has_live_record(&key)? {
             return Err(MyDatabaseError::RecordAlreadyExists);
         }
-        let record = Record {
-            values,
+        self.storage.check_row(values)?;
+        Ok(key)
+    }
+    /// Runs the changes in a storage transaction with the next timestamp of the database clock,
+    /// snapshots see them once they are committed. Failed changes are rolled back, a failed commit rolls back by itself
+    fn write<T>(&mut self, changes: impl FnOnce(&mut Self, u64) -> Result<T, MyDatabaseError>) -> Result<T, MyDatabaseError> {
+        self.storage.begin()?;
+        let garbage = self.garbage.clone();
+        let timestamp = self.clock.begin_write();
+        let result = match changes(self, timestamp) {
+            Ok(result) => match self.storage.commit(timestamp) {
+                Ok(()) => {
+                    self.last_change = timestamp;
+                    Ok(result)
+                }
+                Err(e) => {
+                    self.garbage = garbage;
+                    Err(e)
+                }
+            },
+            Err(e) => {
+                self.storage.rollback();
+                self.garbage = garbage;
+                Err(e)
+            }
         };
-        self.records.insert(key, record); // returns None because of earlier check
-        Ok(())
+        self.clock.end_write(timestamp);
+        result
+    }
+    fn insert_values(&mut self, values: HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+        let key = self.check_values(&values)?;
+        self.write(|table, timestamp| table.storage.insert(key, values, timestamp))
+    }
+    /// Inserts all rows or none of them. Rows are tagged with their line number for error reporting.
+    /// All rows get the same timestamp, so queries see either the whole batch or nothing.
+    /// They are written in one transaction, a failed insert rolls back the ones before it and a file is synced once
+    fn insert_batch(&mut self, rows: Vec<NumberedRow>) -> Result<usize, MyDatabaseError> {
+        let mut errors = Vec::new();
+        let mut new_records: BTreeMap<K, HashMap<String, Value>> = BTreeMap::new();
+        for (line, values) in rows {
+            match self.check_values(&values) {
+                Ok(key) if new_records.contains_key(&key) => errors.push((line, MyDatabaseError::RecordAlreadyExists)),
+                Ok(key) => {
+                    new_records.insert(key, values);
+                },
+                Err(e) => errors.push((line, e)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(MyDatabaseError::ImportFailed(errors));
+        }
+        let inserted = new_records.len();
+        self.write(|table, timestamp| {
+            for (key, values) in new_records {
+                table.storage.insert(key, values, timestamp)?;
+            }
+            Ok(inserted)
+        })
     }
+    /// Marks the live version as deleted, queries that started earlier still see it
     fn delete_key(&mut self, key_as_string: String) -> Result<(), MyDatabaseError> {
         let key = K::get_from_string(key_as_string)?;
-        match self.records.remove(&key) {
-            Some(_) => Ok(()),
-            None => Err(MyDatabaseError::KeyNotFound),
+        self.write(|table, timestamp| {
+            if !table.storage.delete(&key, timestamp)? {
+                return Err(MyDatabaseError::KeyNotFound);
+            }
+            table.garbage.insert(key);
+            table.drop_garbage()
+        })
+    }
+    /// Drops deleted versions no running query can see anymore, in a transaction of its own.
+    /// When it fails, the keys are tried again next time
+    fn collect_garbage(&mut self) {
+        if self.storage.begin().is_err() {
+            return;
+        }
+        let garbage = self.garbage.clone();
+        let collected = self.drop_garbage();
+        if collected.is_err() {
+            self.storage.rollback();
+            self.garbage = garbage;
+        } else if self.storage.commit(self.last_change).is_err() {
+            self.garbage = garbage;
         }
     }
-    fn select_and_display(&mut self, values_to_select: &Vec<String>, condition: &Option<WhereClause>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
-        for value_name in values_to_select {
-            if !self.structure.contains_key(value_name) {
-                return Err(MyDatabaseError::InvalidFieldName);
+    /// Drops deleted versions of the keys in garbage that no running query can see anymore.
+    /// On failure the callers restore the garbage they started with
+    fn drop_garbage(&mut self) -> Result<(), MyDatabaseError> {
+        let oldest_snapshot = self.clock.oldest_snapshot();
+        for key in mem::take(&mut self.garbage) {
+            if self.storage.collect_garbage(&key, Some(oldest_snapshot))? {
+                self.garbage.insert(key);
             }
         }
-
-        for value_name in values_to_select {
-            response_buf.push_str(&format!("{}\t", value_name));
-            // print!("{}\t", value_name);
-        }
-        // println!();
-        response_buf.push('\n');
-        for record in self.records.values() {
-            if let Some(cond) = condition {
-                let condition_met = cond.evaluate_for_record(record)?;
-                if !condition_met {
+        Ok(())
+    }
+}
+impl<K: DatabaseKey + Ord + Clone> Table<K> {
+    /// Runs the query on a snapshot taken when it starts
+    fn select(table: &Arc<RwLock<Table<K>>>, projection: &[ProjectedColumn], condition: &Option<WhereClause>) -> Result<ResultSet, MyDatabaseError> {
+        let clock = Arc::clone(&read_lock(table).clock);
+        let snapshot = Snapshot::begin(&clock);
+        Table::select_at(table, &snapshot, projection, condition)
+    }
+    /// Reads the table in chunks, locking it only while a chunk is read, so long queries don't block writers.
+    /// Changes made in between are not visible, because they are newer than the snapshot
+    fn select_at(table: &Arc<RwLock<Table<K>>>, snapshot: &Snapshot, projection: &[ProjectedColumn], condition: &Option<WhereClause>) -> Result<ResultSet, MyDatabaseError> {
+        snapshot.reads(Arc::clone(table) as Arc<dyn CollectGarbage>);
+        let columns = projection.iter().map(|column| column.name.clone()).collect();
+        let column_types = projection.iter().map(ProjectedColumn::value_type).collect();
+        let inputs: Vec<&Expression> = projection.iter().flat_map(ProjectedColumn::inputs).collect();
+        let mut rows = Vec::new();
+        let (lower, upper) = match condition.as_ref().and_then(|cond| cond.key_range(&read_lock(table).key_name)) {
+            Some(range) => key_bounds(&range),
+            None => (Bound::Unbounded, Bound::Unbounded),
+        };
+        let mut last_key: Option<K> = None;
+        loop {
+            let table = read_lock(table);
+            let start_key = last_key.take();
+            let start = match &start_key {
+                Some(key) => Bound::Excluded(key),
+                None => lower.as_ref(),
+            };
+            if is_empty_range(start, upper.as_ref()) {
+                break;
+            }
+            let mut scanned = 0;
+            let chunk = match condition {
+                Some(cond) => table.storage.range_where(start, upper.as_ref(), snapshot.timestamp, cond),
+                None => table.storage.range(start, upper.as_ref(), snapshot.timestamp),
+            };
+            for row in chunk.take(SCAN_CHUNK) {
+                let (key, record) = row?;
+                scanned += 1;
+                last_key = Some(key);
+                let Some(record) = record else {
                     continue;
+                };
+                let mut row = Vec::with_capacity(inputs.len());
+                for input in &inputs {
+                    row.push(input.evaluate_for_record(&record)?);
                 }
+                rows.push(row);
             }
-            for value_name in values_to_select {
-                let Some(value) = record.values.get(value_name) else {
-                    return Err(MyDatabaseError::InvalidFieldName); // shouldn't happen due to earlier check
-                };
-                match value {
-                    Value::Bool(b) => response_buf.push_str(&format!("{}\t", b)),
-                    Value::Int(i) => response_buf.push_str(&format!("{}\t", i)),
-                    Value::Float(f) => response_buf.push_str(&format!("{}\t", f)),
-                    Value::String(s) => response_buf.push_str(&format!("{}\t", s)),
+            if scanned < SCAN_CHUNK {
+                break;
+            }
+        }
+        let mut result = ResultSet::new(columns, column_types);
+        for row in ProjectedColumn::finish_rows(projection, rows)? {
+            result.push_row(row);
+        }
+        Ok(result)
+    }
+}
+
+/// Bounds of a key range converted to keys, constants that aren't valid keys don't narrow the scan
+fn key_bounds<K: DatabaseKey>(range: &KeyRange) -> (Bound<K>, Bound<K>) {
+    let convert = |bound: &Bound<Value>| match bound {
+        Bound::Included(value) => K::get_bound_from_value(value).map_or(Bound::Unbounded, Bound::Included),
+        Bound::Excluded(value) => K::get_bound_from_value(value).map_or(Bound::Unbounded, Bound::Excluded),
+        Bound::Unbounded => Bound::Unbounded,
+    };
+    (convert(&range.lower), convert(&range.upper))
+}
+
+/// BTreeMap::range panics for ranges that end before they start
+fn is_empty_range<K: Ord>(start: Bound<&K>, end: Bound<&K>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start) | Bound::Excluded(start), Bound::Included(end) | Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+/// Timestamps shared by the tables of a database, so a statement reads all of them at one moment.
+/// Writers take the next timestamp, snapshots see changes up to the oldest one still being written
+#[derive(Debug, Default)]
+struct Clock {
+    state: Mutex<ClockState>,
+}
+#[derive(Debug, Default)]
+struct ClockState {
+    last: u64, // newest timestamp given to a writer
+    writing: BTreeSet<u64>, // timestamps of changes not committed or rolled back yet
+    snapshots: BTreeMap<u64, usize>, // timestamps of running statements, with their count
+}
+impl ClockState {
+    /// Newest timestamp all changes up to which are finished
+    fn visible(&self) -> u64 {
+        self.writing.first().map_or(self.last, |first| first - 1)
+    }
+}
+impl Clock {
+    fn starting_at(last: u64) -> Self {
+        Clock {
+            state: Mutex::new(ClockState { last, ..ClockState::default() }),
+        }
+    }
+    fn lock(&self) -> MutexGuard<'_, ClockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    fn begin_write(&self) -> u64 {
+        let mut state = self.lock();
+        state.last += 1;
+        let timestamp = state.last;
+        state.writing.insert(timestamp);
+        timestamp
+    }
+    fn end_write(&self, timestamp: u64) {
+        self.lock().writing.remove(&timestamp);
+    }
+    /// Oldest timestamp a running or a future snapshot reads at, older deleted versions can be dropped
+    fn oldest_snapshot(&self) -> u64 {
+        let state = self.lock();
+        let visible = state.visible();
+        state.snapshots.keys().next().map_or(visible, |oldest| visible.min(*oldest))
+    }
+}
+
+/// Table that drops versions no snapshot needs when it's not busy, after a snapshot that read it ends
+trait CollectGarbage: Send + Sync {
+    fn collect_if_idle(&self);
+}
+impl<K: DatabaseKey + Ord> CollectGarbage for RwLock<Table<K>> {
+    fn collect_if_idle(&self) {
+        let has_garbage = !read_lock(self).garbage.is_empty();
+        // when a writer is busy, the next write does it
+        if has_garbage
+            && let Ok(mut table) = self.try_write() {
+            table.collect_garbage();
+        }
+    }
+}
+
+/// Point in time a statement reads every table at. Versions it can see are kept until it is dropped
+pub struct Snapshot {
+    clock: Arc<Clock>,
+    timestamp: u64,
+    tables: Mutex<Vec<Arc<dyn CollectGarbage>>>, // tables read, they clean up when it ends
+}
+impl Snapshot {
+    fn begin(clock: &Arc<Clock>) -> Self {
+        // registered under the clock lock, so garbage collection sees it or the versions it reads are newer
+        let mut state = clock.lock();
+        let timestamp = state.visible();
+        *state.snapshots.entry(timestamp).or_default() += 1;
+        Snapshot {
+            clock: Arc::clone(clock),
+            timestamp,
+            tables: Mutex::new(Vec::new()),
+        }
+    }
+    fn reads(&self, table: Arc<dyn CollectGarbage>) {
+        let mut tables = self.tables.lock().unwrap_or_else(PoisonError::into_inner);
+        if !tables.iter().any(|read| Arc::ptr_eq(read, &table)) {
+            tables.push(table);
+        }
+    }
+}
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Snapshot({})", self.timestamp)
+    }
+}
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        {
+            let mut state = self.clock.lock();
+            if let Some(count) = state.snapshots.get_mut(&self.timestamp) {
+                *count -= 1;
+                if *count == 0 {
+                    state.snapshots.remove(&self.timestamp);
                 }
             }
-            response_buf.push('\n');
         }
-        Ok(())
+        // the last reader of old versions cleans them up
+        for table in mem::take(self.tables.get_mut().unwrap_or_else(PoisonError::into_inner)) {
+            table.collect_if_idle();
+        }
     }
 }
 
-#[derive(Debug)]
-pub enum AnyTableRef<'a> {
-    StringKeyTable(&'a mut Table<String>),
-    IntKeyTable(&'a mut Table<i64>),
+/// Locks for reading. A panic in another thread doesn't make the data unusable, every change is done in one step
+pub(crate) fn read_lock<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+pub(crate) fn write_lock<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Shared reference to a table. Queries lock the table for reading, so they run in parallel,
+/// changes lock it for writing
+#[derive(Debug, Clone)]
+pub enum AnyTableRef {
+    StringKeyTable(Arc<RwLock<Table<String>>>),
+    IntKeyTable(Arc<RwLock<Table<i64>>>),
 }
-impl<'a> AnyTableRef<'a> {
-    pub fn get_type_for_name(&self, name: &str) -> Option<&ValueType> {
+impl AnyTableRef {
+    pub fn get_type_for_name(&self, name: &str) -> Option<ValueType> {
         match self {
-            AnyTableRef::StringKeyTable(table) => table.structure.get(name),
-            AnyTableRef::IntKeyTable(table) => table.structure.get(name),
+            AnyTableRef::StringKeyTable(table) => read_lock(table).structure.get(name).copied(),
+            AnyTableRef::IntKeyTable(table) => read_lock(table).structure.get(name).copied(),
+        }
+    }
+    pub fn get_key_name(&self) -> String {
+        match self {
+            AnyTableRef::StringKeyTable(table) => read_lock(table).key_name.clone(),
+            AnyTableRef::IntKeyTable(table) => read_lock(table).key_name.clone(),
         }
     }
     pub fn get_all_columns(&self) -> Vec<String> {
         match self {
-            AnyTableRef::StringKeyTable(table) => table.structure.keys().cloned().collect(),
-            AnyTableRef::IntKeyTable(table) => table.structure.keys().cloned().collect(),
+            AnyTableRef::StringKeyTable(table) => read_lock(table).column_order.clone(),
+            AnyTableRef::IntKeyTable(table) => read_lock(table).column_order.clone(),
+        }
+    }
+    /// Every column in declared order, like SELECT *
+    pub fn get_all_columns_projection(&self) -> Vec<ProjectedColumn> {
+        let structure = self.get_structure();
+        self.get_all_columns().into_iter()
+            .filter_map(|name| structure.get(&name).map(|value_type| ProjectedColumn::column(name.clone(), *value_type)))
+            .collect()
+    }
+    pub fn insert_values(&self, values: HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+        match self {
+            AnyTableRef::StringKeyTable(table) => write_lock(table).insert_values(values),
+            AnyTableRef::IntKeyTable(table) => write_lock(table).insert_values(values),
         }
     }
-    pub fn insert_values(&mut self, values: HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+    pub fn insert_batch(&self, rows: Vec<NumberedRow>) -> Result<usize, MyDatabaseError> {
         match self {
-            AnyTableRef::StringKeyTable(table) => table.insert_values(values),
-            AnyTableRef::IntKeyTable(table) => table.insert_values(values),
+            AnyTableRef::StringKeyTable(table) => write_lock(table).insert_batch(rows),
+            AnyTableRef::IntKeyTable(table) => write_lock(table).insert_batch(rows),
         }
     }
-    pub fn delete_key(&mut self, key_as_string: String) -> Result<(), MyDatabaseError> {
+    pub fn delete_key(&self, key_as_string: String) -> Result<(), MyDatabaseError> {
         match self {
-            AnyTableRef::StringKeyTable(table) => table.delete_key(key_as_string),
-            AnyTableRef::IntKeyTable(table) => table.delete_key(key_as_string),
+            AnyTableRef::StringKeyTable(table) => write_lock(table).delete_key(key_as_string),
+            AnyTableRef::IntKeyTable(table) => write_lock(table).delete_key(key_as_string),
         }
     }
-    pub fn select_and_display(&mut self, values_to_select: &Vec<String>, condition: &Option<WhereClause>, response_buf: &mut String) -> Result<(), MyDatabaseError> {
+    /// Runs the query on a snapshot of its own
+    pub fn select(&self, projection: &[ProjectedColumn], condition: &Option<WhereClause>) -> Result<ResultSet, MyDatabaseError> {
         match self {
-            AnyTableRef::StringKeyTable(table) => table.select_and_display(values_to_select, condition, response_buf),
-            AnyTableRef::IntKeyTable(table) => table.select_and_display(values_to_select, condition, response_buf),
+            AnyTableRef::StringKeyTable(table) => Table::select(table, projection, condition),
+            AnyTableRef::IntKeyTable(table) => Table::select(table, projection, condition),
         }
     }
-    pub fn get_structure(&self) -> &HashMap<String, ValueType> {
+    /// Runs the query on a snapshot of the statement, so all its queries see the same moment
+    pub fn select_at(&self, snapshot: &Snapshot, projection: &[ProjectedColumn], condition: &Option<WhereClause>) -> Result<ResultSet, MyDatabaseError> {
         match self {
-            AnyTableRef::StringKeyTable(table) => &table.structure,
-            AnyTableRef::IntKeyTable(table) => &table.structure,
+            AnyTableRef::StringKeyTable(table) => Table::select_at(table, snapshot, projection, condition),
+            AnyTableRef::IntKeyTable(table) => Table::select_at(table, snapshot, projection, condition),
+        }
+    }
+    /// Snapshot of the database the table belongs to, taken now
+    pub fn snapshot(&self) -> Snapshot {
+        match self {
+            AnyTableRef::StringKeyTable(table) => Snapshot::begin(&read_lock(table).clock),
+            AnyTableRef::IntKeyTable(table) => Snapshot::begin(&read_lock(table).clock),
+        }
+    }
+    pub fn get_structure(&self) -> HashMap<String, ValueType> {
+        match self {
+            AnyTableRef::StringKeyTable(table) => read_lock(table).structure.clone(),
+            AnyTableRef::IntKeyTable(table) => read_lock(table).structure.clone(),
         }
     }
 }
 
 #[derive(Debug)]
 pub struct Database<K: DatabaseKey + Ord> {
-    tables: HashMap<String, Table<K>>,
+    tables: RwLock<HashMap<String, Arc<RwLock<Table<K>>>>>,
+    clock: Arc<Clock>,
+    file: Option<Arc<PageFile>>, // tables are kept in memory without it
     // executed_commands: Vec<String>,
 }
+impl<K: DatabaseKey + Ord> Default for Database<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl<K: DatabaseKey + Ord> Database<K> {
     pub fn new() -> Self {
         Database::<K> {
-            tables: HashMap::new(),
+            tables: RwLock::new(HashMap::new()),
+            clock: Arc::new(Clock::default()),
+            file: None,
             // executed_commands: Vec::new(),
         }
     }
-    fn create_table(&mut self, name: &str, key_name: &str, fields: HashMap<String, ValueType>) -> Result<(), MyDatabaseError> {
-        let Some(key_type) = fields.get(key_name) else {
+    /// Database kept in a file, which is created if it doesn't exist. Tables saved in it are opened,
+    /// pages of their B-trees are read when queries need them, at most `cache_pages` of them stay in memory
+    pub fn open(path: &Path, cache_pages: usize) -> Result<Self, MyDatabaseError> {
+        let file = PageFile::open(path, cache_pages)?;
+        let mut tables = HashMap::new();
+        let entries = file.tables()?;
+        // the clock continues after the newest change of any table
+        let clock = Arc::new(Clock::starting_at(entries.iter().map(|(_, entry)| entry.clock).max().unwrap_or(0)));
+        for (name, entry) in entries {
+            let key_type = entry.columns.iter().find(|(column, _)| *column == entry.key_name).map(|(_, value_type)| *value_type);
+            if !key_type.is_some_and(|key_type| K::validate_value_type(&key_type)) {
+                return Err(MyDatabaseError::InvalidKeyType);
+            }
+            let key_name = entry.key_name.clone();
+            let column_order = entry.columns.iter().map(|(column, _)| column.clone()).collect();
+            let structure = entry.columns.iter().cloned().collect();
+            let last_change = entry.clock;
+            let storage = Box::new(file.storage::<K>(&name, entry));
+            let table = Table::new(key_name, structure, column_order, storage, Arc::clone(&clock), last_change);
+            tables.insert(name, Arc::new(RwLock::new(table)));
+        }
+        Ok(Database::<K> {
+            tables: RwLock::new(tables),
+            clock,
+            file: Some(file),
+        })
+    }
+    fn get_table(&self, name: &str) -> Result<Arc<RwLock<Table<K>>>, MyDatabaseError> {
+        match read_lock(&self.tables).get(name) {
+            Some(table) => Ok(Arc::clone(table)),
+            None => Err(MyDatabaseError::TableNotFound(name.to_string())),
+        }
+    }
+    fn create_table(&self, name: &str, key_name: &str, fields: Vec<(String, ValueType)>, kind: StorageKind) -> Result<(), MyDatabaseError> {
+        let columns = fields.clone();
+        let column_order: Vec<String> = fields.iter().map(|(field_name, _)| field_name.clone()).collect();
+        let structure: HashMap<String, ValueType> = fields.into_iter().collect();
+        if structure.len() != column_order.len() {
+            return Err(MyDatabaseError::DuplicateColumnName);
+        }
+        let Some(key_type) = structure.get(key_name) else {
             return Err(MyDatabaseError::InvalidCommandFormat("CREATE. Key was not in fields"));
         };
         if !K::validate_value_type(key_type) {
             return Err(MyDatabaseError::InvalidKeyType);
         }
-        if self.tables.contains_key(name) {
+        let mut tables = write_lock(&self.tables);
+        if tables.contains_key(name) {
             return Err(MyDatabaseError::TableAlreadyExists(name.to_string()));
         }
-        let table = Table::<K> {
-            key_name: key_name.to_string(),
-            structure: fields,
-            records: BTreeMap::new(),
+        let storage: Box<dyn Storage<K>> = match (&self.file, kind) {
+            (Some(file), StorageKind::Row) => Box::new(file.create_table::<K>(name, key_name, columns)?),
+            (Some(_), StorageKind::Columnar) => return Err(MyDatabaseError::InvalidTableOption("columnar storage is only available in memory".to_string())),
+            (None, StorageKind::Row) => Box::new(CompactStorage::new(&column_order)),
+            (None, StorageKind::Columnar) => Box::new(ColumnarStorage::new(&columns)),
         };
-        self.tables.insert(name.to_string(), table); // checked earlier that it has to return Some, couldn't match, because insert changes found values
+        let table = Table::<K>::new(key_name.to_string(), structure, column_order, storage, Arc::clone(&self.clock), 0);
+        tables.insert(name.to_string(), Arc::new(RwLock::new(table))); // checked earlier that it has to return Some, couldn't match, because insert changes found values
         Ok(())
     }
 }
@@ -237,26 +718,211 @@ pub enum AnyDatabase {
     IntDatabase(Database<i64>),
 }
 impl AnyDatabase {
-    pub fn get_table_by_name(&mut self, name: &str) -> Result<AnyTableRef<'_>, MyDatabaseError> {
+    /// Database with integer or string keys, kept in `file` when it's given
+    pub fn open(int_keys: bool, file: Option<&Path>, cache_pages: usize) -> Result<AnyDatabase, MyDatabaseError> {
+        Ok(match (int_keys, file) {
+            (true, Some(path)) => AnyDatabase::IntDatabase(Database::open(path, cache_pages)?),
+            (true, None) => AnyDatabase::IntDatabase(Database::new()),
+            (false, Some(path)) => AnyDatabase::StringDatabase(Database::open(path, cache_pages)?),
+            (false, None) => AnyDatabase::StringDatabase(Database::new()),
+        })
+    }
+    pub fn get_table_by_name(&self, name: &str) -> Result<AnyTableRef, MyDatabaseError> {
         match self {
-            AnyDatabase::StringDatabase(db) => {
-                match db.tables.get_mut(name) {
-                    Some(table) => Ok(AnyTableRef::StringKeyTable(table)),
-                    None => Err(MyDatabaseError::TableNotFound(name.to_string())),
+            AnyDatabase::StringDatabase(db) => Ok(AnyTableRef::StringKeyTable(db.get_table(name)?)),
+            AnyDatabase::IntDatabase(db) => Ok(AnyTableRef::IntKeyTable(db.get_table(name)?)),
+        }
+    }
+    pub fn create_table(&self, name: &str, key_name: &str, fields: Vec<(String, ValueType)>, kind: StorageKind) -> Result<(), MyDatabaseError> {
+        match self {
+            AnyDatabase::StringDatabase(db) => db.create_table(name, key_name, fields, kind),
+            AnyDatabase::IntDatabase(db) => db.create_table(name, key_name, fields, kind),
+        }
+    }
+}
+
+/// Cloneable handle to a database shared between threads.
+/// Table lookups and creation lock the table map, everything else locks single tables
+#[derive(Debug, Clone)]
+pub struct DatabaseHandle {
+    db: Arc<AnyDatabase>,
+}
+impl DatabaseHandle {
+    pub fn new(db: AnyDatabase) -> Self {
+        DatabaseHandle {
+            db: Arc::new(db),
+        }
+    }
+}
+impl Deref for DatabaseHandle {
+    type Target = AnyDatabase;
+    fn deref(&self) -> &AnyDatabase {
+        &self.db
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use crate::models::test_utils::run;
+
+    #[test]
+    fn concurrent_writers_lose_no_updates() {
+        const WRITERS: i64 = 8;
+        const PER_WRITER: i64 = 200;
+        const CONTENDED: i64 = 50;
+        let db = DatabaseHandle::new(AnyDatabase::IntDatabase(Database::<i64>::new()));
+        run(&db, "CREATE t KEY id FIELDS id: Int, writer: Int").unwrap();
+        run(&db, "CREATE other KEY id FIELDS id: Int").unwrap();
+
+        let writers: Vec<_> = (0..WRITERS).map(|writer| {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut won = 0;
+                for i in 0..PER_WRITER {
+                    run(&db, &format!("INSERT id = {}, writer = {} INTO t", writer * PER_WRITER + i, writer)).unwrap();
+                    // every writer races for the same keys, exactly one insert of each may succeed
+                    if i < CONTENDED && run(&db, &format!("INSERT id = {}, writer = {} INTO t", -1 - i, writer)).is_ok() {
+                        won += 1;
+                    }
+                    run(&db, &format!("INSERT id = {} INTO other", writer * PER_WRITER + i)).unwrap();
                 }
-            },
-            AnyDatabase::IntDatabase(db) => {
-                match db.tables.get_mut(name) {
-                    Some(table) => Ok(AnyTableRef::IntKeyTable(table)),
-                    None => Err(MyDatabaseError::TableNotFound(name.to_string())),
+                won
+            })
+        }).collect();
+        let readers: Vec<_> = (0..4).map(|_| {
+            let db = db.clone();
+            thread::spawn(move || {
+                let mut last_len = 0;
+                for _ in 0..100 {
+                    let len = run(&db, "SELECT id FROM t").unwrap().unwrap().len();
+                    assert!(len >= last_len);
+                    last_len = len;
                 }
-            },
+            })
+        }).collect();
+
+        let won: i64 = writers.into_iter().map(|w| w.join().unwrap()).sum();
+        for reader in readers {
+            reader.join().unwrap();
         }
+        assert_eq!(won, CONTENDED);
+        let all = run(&db, "SELECT id FROM t").unwrap().unwrap();
+        assert_eq!(all.len() as i64, WRITERS * PER_WRITER + CONTENDED);
+        let other = run(&db, "SELECT id FROM other").unwrap().unwrap();
+        assert_eq!(other.len() as i64, WRITERS * PER_WRITER);
     }
-    pub fn create_table(&mut self, name: &str, key_name: &str, fields: HashMap<String, ValueType>) -> Result<(), MyDatabaseError> {
-        match self {
-            AnyDatabase::StringDatabase(db) => db.create_table(name, key_name, fields),
-            AnyDatabase::IntDatabase(db) => db.create_table(name, key_name, fields),
+
+    #[test]
+    fn snapshot_sees_state_from_its_start() {
+        let db = Database::<i64>::new();
+        db.create_table("t", "id", vec![("id".to_string(), ValueType::Int), ("n".to_string(), ValueType::Int)], StorageKind::Row).unwrap();
+        let table = db.get_table("t").unwrap();
+        let insert = |id: i64, n: i64| write_lock(&table).insert_values(HashMap::from([
+            ("id".to_string(), Value::Int(id)),
+            ("n".to_string(), Value::Int(n)),
+        ]));
+        for id in 0..600 {
+            insert(id, 0).unwrap();
         }
+        let columns = vec![ProjectedColumn::column("id".to_string(), ValueType::Int), ProjectedColumn::column("n".to_string(), ValueType::Int)];
+
+        let snapshot = Snapshot::begin(&db.clock);
+        write_lock(&table).delete_key("1".to_string()).unwrap();
+        insert(1, 1).unwrap();
+        write_lock(&table).delete_key("2".to_string()).unwrap();
+        insert(1000, 0).unwrap();
+        assert!(insert(1, 2).is_err());
+
+        let old = Table::select_at(&table, &snapshot, &columns, &None).unwrap();
+        assert_eq!(old.len(), 600);
+        assert_eq!(old.get_value(1, "n"), Some(&Value::Int(0)));
+        assert_eq!(old.get_value(2, "id"), Some(&Value::Int(2)));
+        let new = Table::select(&table, &columns, &None).unwrap();
+        assert_eq!(new.len(), 600);
+        assert_eq!(new.get_value(1, "n"), Some(&Value::Int(1)));
+        assert_eq!(new.get_value(2, "id"), Some(&Value::Int(3)));
+        assert_eq!(read_lock(&table).storage.version_count(&1).unwrap(), 2);
+
+        drop(snapshot);
+        let table = read_lock(&table);
+        assert_eq!(table.storage.version_count(&1).unwrap(), 1);
+        assert_eq!(table.storage.version_count(&2).unwrap(), 0);
+        assert!(table.garbage.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn snapshot_reads_every_table_at_one_moment() {
+        let db = Database::<i64>::new();
+        let columns = vec![ProjectedColumn::column("id".to_string(), ValueType::Int)];
+        for name in ["a", "b"] {
+            db.create_table(name, "id", vec![("id".to_string(), ValueType::Int)], StorageKind::Row).unwrap();
+        }
+        let (a, b) = (db.get_table("a").unwrap(), db.get_table("b").unwrap());
+        let insert = |table: &RwLock<Table<i64>>, id: i64| write_lock(table).insert_values(HashMap::from([("id".to_string(), Value::Int(id))]));
+        insert(&a, 1).unwrap();
+        insert(&b, 1).unwrap();
+
+        let snapshot = Snapshot::begin(&db.clock);
+        assert_eq!(Table::select_at(&a, &snapshot, &columns, &None).unwrap().len(), 1);
+        insert(&a, 2).unwrap();
+        insert(&b, 2).unwrap();
+        // b is read only after both changes, the snapshot still hides them
+        assert_eq!(Table::select_at(&b, &snapshot, &columns, &None).unwrap().len(), 1);
+        assert_eq!(Table::select_at(&a, &snapshot, &columns, &None).unwrap().len(), 1);
+        assert_eq!(Table::select(&b, &columns, &None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn file_database_is_opened_with_its_tables() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file_db.db");
+        {
+            let db = AnyDatabase::open(true, Some(&path), 8).unwrap();
+            run(&db, "CREATE t KEY id FIELDS id: Int, name: String").unwrap();
+            for id in 0..2000 {
+                run(&db, &format!("INSERT id = {}, name = \"row {}\" INTO t", id, id)).unwrap();
+            }
+            run(&db, "DELETE 7 FROM t").unwrap();
+        }
+        assert!(matches!(AnyDatabase::open(false, Some(&path), 8), Err(MyDatabaseError::InvalidKeyType)));
+        let db = AnyDatabase::open(true, Some(&path), 8).unwrap();
+        run(&db, "INSERT id = 7, name = \"again\" INTO t").unwrap();
+        let result = run(&db, "SELECT name FROM t WHERE id >= 6 AND id < 9").unwrap().unwrap();
+        let names: Vec<String> = result.rows().iter().map(|row| row[0].to_string()).collect();
+        assert_eq!(names, ["row 6", "again", "row 8"]);
+        assert_eq!(run(&db, "SELECT id FROM t").unwrap().unwrap().len(), 2000);
+    }
+
+    #[test]
+    fn file_table_keeps_long_rows_and_versions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("versions.db");
+        let columns = vec![("id".to_string(), ValueType::Int), ("name".to_string(), ValueType::String)];
+        let projection = vec![ProjectedColumn::column("name".to_string(), ValueType::String)];
+        {
+            let db = Database::<i64>::open(&path, 8).unwrap();
+            db.create_table("t", "id", columns, StorageKind::Row).unwrap();
+            let table = db.get_table("t").unwrap();
+            let insert = |name: String| write_lock(&table).insert_values(HashMap::from([
+                ("id".to_string(), Value::Int(1)),
+                ("name".to_string(), Value::String(name)),
+            ]));
+            // a long query keeps every version of the key, together they take many pages
+            let snapshot = Snapshot::begin(&db.clock);
+            assert!(Table::select_at(&table, &snapshot, &projection, &None).unwrap().is_empty());
+            for version in 0..300 {
+                insert(format!("{} {}", version, "x".repeat(100))).unwrap();
+                write_lock(&table).delete_key("1".to_string()).unwrap();
+            }
+            insert("y".repeat(20_000)).unwrap();
+            assert_eq!(read_lock(&table).storage.version_count(&1).unwrap(), 301);
+            drop(snapshot);
+            assert_eq!(read_lock(&table).storage.version_count(&1).unwrap(), 1);
+        }
+        let db = Database::<i64>::open(&path, 8).unwrap();
+        let result = Table::select(&db.get_table("t").unwrap(), &projection, &None).unwrap();
+        assert_eq!(result.get_value(0, "name"), Some(&Value::String("y".repeat(20_000))));
+    }
+}
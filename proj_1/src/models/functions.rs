@@ -0,0 +1,268 @@
+use std::fmt;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Value, ValueType};
+
+/// Type a function argument has to have
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamType {
+    Exactly(ValueType),
+    Numeric,
+    Any,
+    LikeFirst, // same type as the first argument
+}
+impl ParamType {
+    fn accepts(&self, arg: ValueType, first: ValueType) -> bool {
+        match self {
+            ParamType::Exactly(value_type) => arg == *value_type,
+            ParamType::Numeric => matches!(arg, ValueType::Int | ValueType::Float),
+            ParamType::Any => true,
+            ParamType::LikeFirst => arg == first,
+        }
+    }
+}
+impl fmt::Display for ParamType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParamType::Exactly(value_type) => write!(f, "{}", value_type),
+            ParamType::Numeric => write!(f, "Int or Float"),
+            ParamType::Any => write!(f, "any type"),
+            ParamType::LikeFirst => write!(f, "type of the first argument"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReturnType {
+    Exactly(ValueType),
+    LikeFirst,
+}
+
+/// Built-in function, its signature is checked when the clause is parsed
+pub struct ScalarFunction {
+    pub name: &'static str,
+    pub params: &'static [ParamType],
+    pub optional_params: usize, // trailing params that can be left out
+    pub variadic: bool, // last param can be repeated
+    pub returns: ReturnType,
+    evaluate: fn(&[Value]) -> Result<Value, MyDatabaseError>,
+}
+impl ScalarFunction {
+    /// Type of the result for given argument types, Err with the reason when they don't fit
+    pub fn return_type(&self, args: &[ValueType]) -> Result<ValueType, String> {
+        let min_args = self.params.len() - self.optional_params;
+        if args.len() < min_args || (!self.variadic && args.len() > self.params.len()) {
+            let expected = match (self.variadic, self.optional_params) {
+                (true, _) => format!("at least {}", min_args),
+                (false, 0) => min_args.to_string(),
+                (false, _) => format!("{} to {}", min_args, self.params.len()),
+            };
+            return Err(format!("{} expects {} arguments, found {}", self.name, expected, args.len()));
+        }
+        let Some(first) = args.first() else {
+            return Err(format!("{} expects arguments", self.name));
+        };
+        for (index, arg) in args.iter().enumerate() {
+            let param = self.params[index.min(self.params.len() - 1)];
+            if !param.accepts(*arg, *first) {
+                return Err(format!("argument {} of {} has to be {}, found {}", index + 1, self.name, param, arg));
+            }
+        }
+        match self.returns {
+            ReturnType::Exactly(value_type) => Ok(value_type),
+            ReturnType::LikeFirst => Ok(*first),
+        }
+    }
+    pub fn evaluate(&self, args: &[Value]) -> Result<Value, MyDatabaseError> {
+        (self.evaluate)(args)
+    }
+}
+impl fmt::Debug for ScalarFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ScalarFunction({})", self.name)
+    }
+}
+impl PartialEq for ScalarFunction {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+static FUNCTIONS: [ScalarFunction; 6] = [
+    ScalarFunction { name: "ABS", params: &[ParamType::Numeric], optional_params: 0, variadic: false, returns: ReturnType::LikeFirst, evaluate: abs },
+    ScalarFunction { name: "ROUND", params: &[ParamType::Numeric, ParamType::Exactly(ValueType::Int)], optional_params: 1, variadic: false, returns: ReturnType::LikeFirst, evaluate: round },
+    ScalarFunction { name: "FLOOR", params: &[ParamType::Numeric], optional_params: 0, variadic: false, returns: ReturnType::LikeFirst, evaluate: floor },
+    ScalarFunction { name: "CEIL", params: &[ParamType::Numeric], optional_params: 0, variadic: false, returns: ReturnType::LikeFirst, evaluate: ceil },
+    ScalarFunction { name: "SQRT", params: &[ParamType::Numeric], optional_params: 0, variadic: false, returns: ReturnType::Exactly(ValueType::Float), evaluate: sqrt },
+    ScalarFunction { name: "COALESCE", params: &[ParamType::Any, ParamType::LikeFirst], optional_params: 1, variadic: true, returns: ReturnType::LikeFirst, evaluate: coalesce },
+];
+
+/// Looks up a built-in function, names are case insensitive
+pub fn find_function(name: &str) -> Option<&'static ScalarFunction> {
+    FUNCTIONS.iter().find(|function| function.name.eq_ignore_ascii_case(name))
+}
+
+fn invalid_arguments(name: &'static str, args: &[Value]) -> MyDatabaseError {
+    let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+    MyDatabaseError::FunctionError(name, format!("invalid arguments ({})", args.join(", ")))
+}
+
+fn abs(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    match args {
+        [Value::Int(i)] => i.checked_abs().map(Value::Int).ok_or_else(|| MyDatabaseError::IntegerOverflow(format!("ABS({})", i))),
+        [Value::Float(f)] => Ok(Value::Float(f.abs())),
+        _ => Err(invalid_arguments("ABS", args)),
+    }
+}
+
+/// Rounds half away from zero, negative digits round to tens, hundreds and so on
+fn round(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    let digits = match args.get(1) {
+        Some(Value::Int(digits)) => *digits,
+        None => 0,
+        Some(_) => return Err(invalid_arguments("ROUND", args)),
+    };
+    match args.first() {
+        // more digits than a float has change nothing, results that don't fit leave the value as it was
+        Some(Value::Float(f)) if !f.is_finite() || digits > 17 => Ok(Value::Float(*f)),
+        Some(Value::Float(f)) => {
+            let rounded = if digits >= 0 {
+                let factor = 10f64.powi(digits as i32);
+                (f * factor).round() / factor
+            } else {
+                let factor = 10f64.powi(digits.saturating_neg().min(400) as i32);
+                if factor.is_finite() { (f / factor).round() * factor } else { 0.0 * f.signum() }
+            };
+            Ok(Value::Float(if rounded.is_finite() { rounded } else { *f }))
+        },
+        Some(Value::Int(i)) if digits >= 0 => Ok(Value::Int(*i)),
+        Some(Value::Int(i)) => {
+            // rounds the magnitude, so it can't overflow on the way, only the result can be too big
+            let magnitude = i.unsigned_abs();
+            let factor = u32::try_from(-digits).ok().and_then(|exponent| 10u64.checked_pow(exponent));
+            let rounded = match factor {
+                Some(factor) => {
+                    let remainder = magnitude.rem_euclid(factor);
+                    let down = magnitude - remainder;
+                    if remainder >= factor - remainder { down.checked_add(factor) } else { Some(down) }
+                },
+                None => Some(0), // 10^20 doesn't fit, every Int rounds to 0 then
+            };
+            rounded
+                .and_then(|rounded| i64::try_from(if *i < 0 { -i128::from(rounded) } else { i128::from(rounded) }).ok())
+                .map(Value::Int)
+                .ok_or_else(|| MyDatabaseError::IntegerOverflow(format!("ROUND({}, {})", i, digits)))
+        },
+        _ => Err(invalid_arguments("ROUND", args)),
+    }
+}
+
+fn floor(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    match args {
+        [Value::Int(i)] => Ok(Value::Int(*i)),
+        [Value::Float(f)] => Ok(Value::Float(f.floor())),
+        _ => Err(invalid_arguments("FLOOR", args)),
+    }
+}
+
+fn ceil(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    match args {
+        [Value::Int(i)] => Ok(Value::Int(*i)),
+        [Value::Float(f)] => Ok(Value::Float(f.ceil())),
+        _ => Err(invalid_arguments("CEIL", args)),
+    }
+}
+
+fn sqrt(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    let x = match args {
+        [Value::Int(i)] => *i as f64,
+        [Value::Float(f)] => *f,
+        _ => return Err(invalid_arguments("SQRT", args)),
+    };
+    if x < 0.0 {
+        return Err(MyDatabaseError::FunctionError("SQRT", format!("cannot take the square root of {}", x)));
+    }
+    Ok(Value::Float(x.sqrt()))
+}
+
+/// Columns always have a value, so this is the first argument. Kept for compatibility with other databases
+fn coalesce(args: &[Value]) -> Result<Value, MyDatabaseError> {
+    match args.first() {
+        Some(value) => Ok(value.clone()),
+        None => Err(invalid_arguments("COALESCE", args)),
+    }
+}
+
+/// Whether CAST(x AS to) is allowed for x of type from. Strings are checked only when converted
+pub fn can_cast(from: ValueType, to: ValueType) -> bool {
+    from == to || from == ValueType::String || to == ValueType::String || matches!(
+        (from, to),
+        (ValueType::Int, ValueType::Float) | (ValueType::Float, ValueType::Int) | (ValueType::Int, ValueType::Bool) | (ValueType::Bool, ValueType::Int)
+    )
+}
+
+/// Converts a value, Floats are rounded to the nearest Int
+pub fn cast(value: &Value, to: ValueType) -> Result<Value, MyDatabaseError> {
+    match (value, to) {
+        (_, _) if value.get_type() == to => Ok(value.clone()),
+        (_, ValueType::String) => Ok(Value::String(value.to_string())),
+        (Value::String(s), _) => to.get_value(s)
+            .map_err(|_| MyDatabaseError::FunctionError("CAST", format!("cannot convert \"{}\" to {}", s, to))),
+        (Value::Int(i), ValueType::Float) => Ok(Value::Float(*i as f64)),
+        (Value::Float(f), ValueType::Int) => {
+            let rounded = f.round();
+            // -2^63 and 2^63 are exactly representable, NaN is in no range
+            if !(-9_223_372_036_854_775_808.0..9_223_372_036_854_775_808.0).contains(&rounded) {
+                return Err(MyDatabaseError::FunctionError("CAST", format!("{} is out of Int range", f)));
+            }
+            Ok(Value::Int(rounded as i64))
+        },
+        (Value::Int(i), ValueType::Bool) => Ok(Value::Bool(*i != 0)),
+        (Value::Bool(b), ValueType::Int) => Ok(Value::Int(*b as i64)),
+        _ => Err(MyDatabaseError::FunctionError("CAST", format!("cannot convert {} to {}", value.get_type(), to))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use crate::models::where_parsing::WhereClause;
+
+    fn folded(clause: &str) -> Result<String, MyDatabaseError> {
+        let columns = HashMap::from([("id".to_string(), ValueType::Int), ("price".to_string(), ValueType::Float), ("name".to_string(), ValueType::String)]);
+        Ok(WhereClause::create_from_string(clause.to_string(), &columns)?.explain()?.remove(0))
+    }
+
+    #[test]
+    fn functions_are_checked_and_folded() {
+        assert_eq!(folded("ABS(id - 10) < ROUND(2.567, 2) * 100").unwrap(), "(ABS((id - 10)) < 257)");
+        assert_eq!(folded("id = ROUND(1250, 0 - 2) AND id = FLOOR(3) + CEIL(2) AND price > SQRT(16)").unwrap(), "(((id = 1300) AND (id = 5)) AND (price > 4))");
+        assert_eq!(folded("CAST(name AS Int) = CAST(\"7\" AS Int) + CAST(2.5 AS Int)").unwrap(), "(CAST(name AS Int) = 10)");
+        assert_eq!(folded("COALESCE(price, 1.5, 2.0) > ABS(CAST(id AS Float))").unwrap(), "(COALESCE(price, 1.5, 2) > ABS(CAST(id AS Float)))");
+
+        let error = |clause: &str| folded(clause).unwrap_err().to_string();
+        assert_eq!(error("ABS(name) = 1"), "Type error in 'ABS(name)': argument 1 of ABS has to be Int or Float, found String");
+        assert_eq!(error("ROUND(price, 1, 2) = 1"), "Type error in 'ROUND(price, 1, 2)': ROUND expects 1 to 2 arguments, found 3");
+        assert_eq!(error("COALESCE(id, name) = 1"), "Type error in 'COALESCE(id, name)': argument 2 of COALESCE has to be type of the first argument, found String");
+        assert_eq!(error("SQRT(0 - 4) > 1"), "Cannot evaluate 'SQRT((0 - 4))': SQRT: cannot take the square root of -4");
+        assert_eq!(error("CAST(\"x\" AS Int) = 1"), "Cannot evaluate 'CAST(\"x\" AS Int)': CAST: cannot convert \"x\" to Int");
+        assert_eq!(error("CAST(id AS Bool AS Int)"), "Error parsing where clause: AS outside of CAST");
+        assert!(folded("NOPE(id) = 1").is_err());
+    }
+
+    #[test]
+    fn round_handles_extreme_digits() {
+        let round_to = |value: Value, digits: i64| round(&[value, Value::Int(digits)]);
+        assert!(matches!(round_to(Value::Float(1.25), 400), Ok(Value::Float(f)) if f == 1.25));
+        assert!(matches!(round_to(Value::Float(1e300), 15), Ok(Value::Float(f)) if f == 1e300));
+        assert!(matches!(round_to(Value::Float(-1234.5), -2), Ok(Value::Float(f)) if f == -1200.0));
+        assert!(matches!(round_to(Value::Float(1e300), -400), Ok(Value::Float(f)) if f == 0.0));
+        assert!(matches!(round_to(Value::Int(i64::MAX), -1), Err(MyDatabaseError::IntegerOverflow(_))));
+        assert!(matches!(round_to(Value::Int(i64::MAX - 10), -1), Ok(Value::Int(i)) if i == i64::MAX - 7));
+        assert!(matches!(round_to(Value::Int(i64::MIN), -3), Err(MyDatabaseError::IntegerOverflow(_))));
+        assert!(matches!(round_to(Value::Int(i64::MIN + 8), -1), Ok(Value::Int(i)) if i == i64::MIN + 8));
+        assert!(matches!(round_to(Value::Int(-1250), -2), Ok(Value::Int(-1300))));
+        assert!(matches!(round_to(Value::Int(4_999_999_999_999_999_999), -19), Ok(Value::Int(0))));
+        assert!(matches!(round_to(Value::Int(-5_000_000_000_000_000_000), -19), Err(MyDatabaseError::IntegerOverflow(_))));
+    }
+}
@@ -1,6 +1,10 @@
+use std::fmt;
 use crate::models::db_structure::*;
 
 impl DatabaseKey for i64{
+    fn to_value(&self) -> Value {
+        Value::Int(*self)
+    }
     fn equals(&self, other: &Self) -> bool {
         self == other
     }
@@ -20,8 +24,18 @@ impl DatabaseKey for i64{
             Err(_) => Err(crate::db_errors::MyDatabaseError::InvalidFieldValue),
         }
     }
+    fn get_bound_from_value(val: &Value) -> Option<Self> where Self: Sized {
+        match val {
+            Value::Int(num) => Some(*num),
+            Value::Float(num) if num.fract() == 0.0 && num.abs() < i64::MAX as f64 => Some(*num as i64),
+            _ => None,
+        }
+    }
 }
 impl DatabaseKey for String {
+    fn to_value(&self) -> Value {
+        Value::String(self.clone())
+    }
     fn equals(&self, other: &Self) -> bool {
         self == other
     }
@@ -40,3 +54,24 @@ impl DatabaseKey for String {
     }
 }
 
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(fl) => write!(f, "{}", fl),
+            Value::String(s) => write!(f, "{}", s),
+        }
+    }
+}
+impl fmt::Display for ValueType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueType::Bool => write!(f, "Bool"),
+            ValueType::String => write!(f, "String"),
+            ValueType::Int => write!(f, "Int"),
+            ValueType::Float => write!(f, "Float"),
+        }
+    }
+}
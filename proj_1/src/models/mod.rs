@@ -3,4 +3,20 @@ pub mod db_structure;
 pub mod cmd_impls;
 pub mod commands;
 pub mod where_parsing;
-pub mod utilities;
\ No newline at end of file
+pub mod utilities;
+pub mod result_set;
+pub mod output_format;
+pub mod session;
+pub mod data_files;
+pub mod auth;
+pub mod functions;
+pub mod window;
+pub mod set_operations;
+pub mod storage;
+pub mod pager;
+pub mod btree;
+pub mod page_file;
+pub mod columnar;
+pub mod save_file;
+#[cfg(test)]
+mod test_utils;
\ No newline at end of file
@@ -0,0 +1,240 @@
+use std::fmt;
+use std::str::FromStr;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Value, ValueType};
+use crate::models::result_set::ResultSet;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Table,
+    Csv,
+    Json,
+    Markdown,
+}
+impl OutputFormat {
+    pub fn render(&self, result: &ResultSet, response_buf: &mut String) {
+        match self {
+            OutputFormat::Table => render_table(result, response_buf),
+            OutputFormat::Csv => render_csv(result, response_buf),
+            OutputFormat::Json => render_json(result, response_buf),
+            OutputFormat::Markdown => render_markdown(result, response_buf),
+        }
+    }
+}
+impl FromStr for OutputFormat {
+    type Err = MyDatabaseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "table" => Ok(OutputFormat::Table),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "markdown" => Ok(OutputFormat::Markdown),
+            other => Err(MyDatabaseError::InvalidOutputFormat(other.to_string())),
+        }
+    }
+}
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputFormat::Table => write!(f, "table"),
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Json => write!(f, "json"),
+            OutputFormat::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+
+/// Makes control characters visible, so a single value always stays in its own cell
+fn escape_control_chars(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Quotes the field according to RFC 4180 when it contains a separator, quote or line break
+pub fn escape_csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+pub fn csv_line(fields: &[String]) -> String {
+    let escaped: Vec<String> = fields.iter().map(|f| escape_csv_field(f)).collect();
+    format!("{}\r\n", escaped.join(","))
+}
+
+fn escape_markdown_cell(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace(['\n', '\r'], "<br>")
+}
+
+/// Typed JSON representation - floats that JSON can't represent become strings "NaN", "inf" and "-inf"
+pub fn value_to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(b) => serde_json::Value::Bool(*b),
+        Value::Int(i) => serde_json::Value::from(*i),
+        Value::Float(f) => serde_json::Number::from_f64(*f)
+            .map(serde_json::Value::Number)
+            .unwrap_or_else(|| serde_json::Value::String(f.to_string())),
+        Value::String(s) => serde_json::Value::String(s.clone()),
+    }
+}
+
+pub fn json_object_line(columns: &[String], row: &[Value]) -> String {
+    let fields: Vec<String> = columns.iter().zip(row)
+        .map(|(column, value)| format!("{}:{}", serde_json::Value::String(column.clone()), value_to_json(value)))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
+
+fn render_table(result: &ResultSet, response_buf: &mut String) {
+    let header: Vec<String> = result.columns().iter().map(|c| escape_control_chars(c)).collect();
+    let cells: Vec<Vec<String>> = result.rows().iter()
+        .map(|row| row.iter().map(|v| escape_control_chars(&v.to_string())).collect())
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.chars().count()).collect();
+    for row in &cells {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+    let right_aligned: Vec<bool> = result.column_types().iter()
+        .map(|t| matches!(t, ValueType::Int | ValueType::Float))
+        .collect();
+
+    let format_line = |line: &[String], response_buf: &mut String| {
+        let padded: Vec<String> = line.iter().enumerate()
+            .map(|(i, cell)| {
+                if right_aligned[i] {
+                    format!("{:>width$}", cell, width = widths[i])
+                } else {
+                    format!("{:<width$}", cell, width = widths[i])
+                }
+            })
+            .collect();
+        response_buf.push_str(padded.join(" | ").trim_end());
+        response_buf.push('\n');
+    };
+
+    format_line(&header, response_buf);
+    let separator: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+    response_buf.push_str(&separator.join("-+-"));
+    response_buf.push('\n');
+    for row in &cells {
+        format_line(row, response_buf);
+    }
+    let row_word = if result.len() == 1 { "row" } else { "rows" };
+    response_buf.push_str(&format!("({} {})\n", result.len(), row_word));
+}
+
+fn render_csv(result: &ResultSet, response_buf: &mut String) {
+    response_buf.push_str(&csv_line(result.columns()));
+    for row in result.rows() {
+        let fields: Vec<String> = row.iter().map(|v| v.to_string()).collect();
+        response_buf.push_str(&csv_line(&fields));
+    }
+}
+
+fn render_json(result: &ResultSet, response_buf: &mut String) {
+    let objects: Vec<String> = result.rows().iter()
+        .map(|row| json_object_line(result.columns(), row))
+        .collect();
+    response_buf.push('[');
+    response_buf.push_str(&objects.join(",\n"));
+    response_buf.push_str("]\n");
+}
+
+fn render_markdown(result: &ResultSet, response_buf: &mut String) {
+    let header: Vec<String> = result.columns().iter().map(|c| escape_markdown_cell(c)).collect();
+    response_buf.push_str(&format!("| {} |\n", header.join(" | ")));
+    let alignment: Vec<&str> = result.column_types().iter()
+        .map(|t| match t {
+            ValueType::Int | ValueType::Float => "---:",
+            _ => "---",
+        })
+        .collect();
+    response_buf.push_str(&format!("| {} |\n", alignment.join(" | ")));
+    for row in result.rows() {
+        let cells: Vec<String> = row.iter().map(|v| escape_markdown_cell(&v.to_string())).collect();
+        response_buf.push_str(&format!("| {} |\n", cells.join(" | ")));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ResultSet {
+        let mut result = ResultSet::new(
+            vec!["id".to_string(), "name".to_string(), "score".to_string(), "ok".to_string()],
+            vec![ValueType::Int, ValueType::String, ValueType::Float, ValueType::Bool],
+        );
+        result.push_row(vec![Value::Int(1), Value::String("tab\there, \"q\"".to_string()), Value::Float(2.5), Value::Bool(true)]);
+        result.push_row(vec![Value::Int(20), Value::String("line\nbreak|pipe".to_string()), Value::Float(f64::NAN), Value::Bool(false)]);
+        result
+    }
+
+    fn render(format: OutputFormat) -> String {
+        let mut buf = String::new();
+        format.render(&sample(), &mut buf);
+        buf
+    }
+
+    #[test]
+    fn table_is_aligned_and_escaped() {
+        let out = render(OutputFormat::Table);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "id | name             | score | ok");
+        assert_eq!(lines[1], "---+------------------+-------+------");
+        assert_eq!(lines[2], " 1 | tab\\there, \"q\"   |   2.5 | true");
+        assert_eq!(lines[3], "20 | line\\nbreak|pipe |   NaN | false");
+        assert_eq!(lines[4], "(2 rows)");
+    }
+
+    #[test]
+    fn csv_follows_rfc_4180() {
+        let out = render(OutputFormat::Csv);
+        assert_eq!(out, "id,name,score,ok\r\n1,\"tab\there, \"\"q\"\"\",2.5,true\r\n20,\"line\nbreak|pipe\",NaN,false\r\n");
+    }
+
+    #[test]
+    fn json_values_are_typed() {
+        let out = render(OutputFormat::Json);
+        let parsed: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(parsed[0]["id"], serde_json::json!(1));
+        assert_eq!(parsed[0]["name"], serde_json::json!("tab\there, \"q\""));
+        assert_eq!(parsed[0]["score"], serde_json::json!(2.5));
+        assert_eq!(parsed[0]["ok"], serde_json::json!(true));
+        assert_eq!(parsed[1]["score"], serde_json::json!("NaN"));
+    }
+
+    #[test]
+    fn markdown_escapes_pipes_and_newlines() {
+        let out = render(OutputFormat::Markdown);
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], "| id | name | score | ok |");
+        assert_eq!(lines[1], "| ---: | --- | ---: | --- |");
+        assert_eq!(lines[3], "| 20 | line<br>break\\|pipe | NaN | false |");
+    }
+
+    #[test]
+    fn format_names_are_parsed() {
+        assert_eq!("CSV".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+        assert_eq!("markdown".parse::<OutputFormat>().unwrap(), OutputFormat::Markdown);
+        assert!("xml".parse::<OutputFormat>().is_err());
+    }
+}
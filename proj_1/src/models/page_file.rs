@@ -0,0 +1,265 @@
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError};
+use crate::db_errors::MyDatabaseError;
+use crate::models::btree::{encode_value, type_tag, BTree, Reader, MAX_KEY};
+use crate::models::db_structure::{DatabaseKey, Record, Value, ValueType};
+use crate::models::pager::{Pager, PAGE_SIZE};
+use crate::models::storage::{delete_version, drop_old_versions, Rows, Storage, Version};
+
+const MAGIC: &[u8; 8] = b"PROJ1DB\0";
+const FORMAT_VERSION: u32 = 3;
+const HEADER_PAGE: u64 = 1; // the first page after the header of the pager
+
+/// Definition of a table in the catalog of a database file
+#[derive(Debug, Clone)]
+pub struct TableEntry {
+    pub key_name: String,
+    pub columns: Vec<(String, ValueType)>, // in declared order
+    pub clock: u64, // timestamp of the last saved change
+    root: u64,
+}
+impl TableEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_value(&Value::String(self.key_name.clone()), &mut buf);
+        buf.extend_from_slice(&(self.columns.len() as u16).to_le_bytes());
+        for (name, value_type) in &self.columns {
+            encode_value(&Value::String(name.clone()), &mut buf);
+            buf.push(type_tag(*value_type));
+        }
+        buf.extend_from_slice(&self.root.to_le_bytes());
+        buf.extend_from_slice(&self.clock.to_le_bytes());
+        buf
+    }
+    fn decode(bytes: &[u8]) -> Result<TableEntry, MyDatabaseError> {
+        let mut reader = Reader::new(bytes);
+        let key_name = reader.key::<String>()?;
+        let mut columns = Vec::new();
+        for _ in 0..reader.u16()? {
+            columns.push((reader.key::<String>()?, reader.value_type()?));
+        }
+        Ok(TableEntry {
+            key_name,
+            columns,
+            root: reader.u64()?,
+            clock: reader.u64()?,
+        })
+    }
+}
+
+/// Database file - a header page, a catalog of tables and a B-tree of records of every table
+#[derive(Debug)]
+pub struct PageFile {
+    pager: Arc<Pager>,
+    catalog: Mutex<BTree<String>>, // table name to its entry
+}
+impl PageFile {
+    /// Opens the file or creates a new one, `cache_pages` pages are kept in memory
+    pub fn open(path: &Path, cache_pages: usize) -> Result<Arc<PageFile>, MyDatabaseError> {
+        let pager = Arc::new(Pager::open(path, cache_pages)?);
+        let catalog = if pager.page_count() <= HEADER_PAGE {
+            pager.atomically(|| {
+                let header = pager.allocate()?;
+                let catalog = BTree::create(Arc::clone(&pager))?;
+                let mut buf = MAGIC.to_vec();
+                buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+                buf.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+                buf.extend_from_slice(&catalog.root().to_le_bytes());
+                pager.write(header, &buf)?;
+                Ok(catalog)
+            })?
+        } else {
+            let header = pager.read(HEADER_PAGE)?;
+            let mut reader = Reader::new(&header);
+            if reader.bytes(MAGIC.len())? != MAGIC {
+                return Err(MyDatabaseError::CorruptedFile("not a database file".to_string()));
+            }
+            let version = reader.u32()?;
+            let page_size = reader.u32()? as usize;
+            if version != FORMAT_VERSION || page_size != PAGE_SIZE {
+                return Err(MyDatabaseError::CorruptedFile(format!("format {} with {} byte pages is not supported", version, page_size)));
+            }
+            BTree::open(Arc::clone(&pager), reader.u64()?)
+        };
+        Ok(Arc::new(PageFile {
+            pager,
+            catalog: Mutex::new(catalog),
+        }))
+    }
+    /// Tables saved in the file, with their entries
+    pub fn tables(&self) -> Result<Vec<(String, TableEntry)>, MyDatabaseError> {
+        let catalog = self.catalog.lock().unwrap_or_else(PoisonError::into_inner);
+        catalog.range(Bound::Unbounded, Bound::Unbounded)
+            .map(|entry| entry.and_then(|(name, bytes)| Ok((name, TableEntry::decode(&bytes)?))))
+            .collect()
+    }
+    /// Adds a table with an empty B-tree to the catalog
+    pub fn create_table<K: DatabaseKey>(self: &Arc<Self>, name: &str, key_name: &str, columns: Vec<(String, ValueType)>) -> Result<BTreeStorage<K>, MyDatabaseError> {
+        let (tree, entry) = self.pager.atomically(|| {
+            let tree = BTree::create(Arc::clone(&self.pager))?;
+            let entry = TableEntry {
+                key_name: key_name.to_string(),
+                columns,
+                clock: 0,
+                root: tree.root(),
+            };
+            self.save_entry(name, &entry)?;
+            Ok((tree, entry))
+        })?;
+        Ok(BTreeStorage::new(Arc::clone(self), name, entry, tree))
+    }
+    /// Storage of a table read from the catalog
+    pub fn storage<K: DatabaseKey>(self: &Arc<Self>, name: &str, entry: TableEntry) -> BTreeStorage<K> {
+        let tree = BTree::open(Arc::clone(&self.pager), entry.root);
+        BTreeStorage::new(Arc::clone(self), name, entry, tree)
+    }
+    fn save_entry(&self, name: &str, entry: &TableEntry) -> Result<(), MyDatabaseError> {
+        self.catalog.lock().unwrap_or_else(PoisonError::into_inner).put(name.to_string(), entry.encode())
+    }
+}
+
+/// Versions of records in a B-tree of a database file, only pages in the buffer pool are in memory
+#[derive(Debug)]
+pub struct BTreeStorage<K> {
+    file: Arc<PageFile>,
+    name: String,
+    entry: TableEntry,
+    saved_clock: u64, // clock of the entry when the transaction began
+    tree: BTree<K>,
+    positions: HashMap<String, usize>,
+}
+impl<K: DatabaseKey> BTreeStorage<K> {
+    fn new(file: Arc<PageFile>, name: &str, entry: TableEntry, tree: BTree<K>) -> Self {
+        BTreeStorage {
+            file,
+            name: name.to_string(),
+            positions: entry.columns.iter().enumerate().map(|(position, (column, _))| (column.clone(), position)).collect(),
+            saved_clock: entry.clock,
+            entry,
+            tree,
+        }
+    }
+    fn versions(&self, key: &K) -> Result<Vec<Version<Vec<Value>>>, MyDatabaseError> {
+        match self.tree.get(key)? {
+            Some(bytes) => decode_versions(&bytes, self.entry.columns.len()),
+            None => Ok(Vec::new()),
+        }
+    }
+    fn save_versions(&self, key: K, versions: &[Version<Vec<Value>>]) -> Result<(), MyDatabaseError> {
+        if versions.is_empty() {
+            self.tree.remove(&key)
+        } else {
+            self.tree.put(key, encode_versions(versions))
+        }
+    }
+}
+impl<K: DatabaseKey> Storage<K> for BTreeStorage<K> {
+    /// A key keeps all of its versions in one entry, which continues in overflow pages when it's long,
+    /// only the key itself has to fit in a leaf
+    fn check_row(&self, values: &HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+        let mut buf = Vec::new();
+        if let Some(key) = values.get(&self.entry.key_name) {
+            encode_value(key, &mut buf);
+        }
+        if buf.len() > MAX_KEY {
+            return Err(MyDatabaseError::RecordTooLarge(buf.len(), MAX_KEY));
+        }
+        Ok(())
+    }
+    fn insert(&mut self, key: K, mut values: HashMap<String, Value>, timestamp: u64) -> Result<(), MyDatabaseError> {
+        let row: Vec<Value> = self.entry.columns.iter().map_while(|(column, _)| values.remove(column)).collect();
+        if row.len() != self.entry.columns.len() || !values.is_empty() {
+            return Err(MyDatabaseError::KeysMismatch);
+        }
+        let mut versions = self.versions(&key)?;
+        versions.push(Version {
+            created: timestamp,
+            deleted: None,
+            row,
+        });
+        self.save_versions(key, &versions)
+    }
+    fn delete(&mut self, key: &K, timestamp: u64) -> Result<bool, MyDatabaseError> {
+        let mut versions = self.versions(key)?;
+        if !delete_version(&mut versions, timestamp) {
+            return Ok(false);
+        }
+        self.save_versions(key.clone(), &versions)?;
+        Ok(true)
+    }
+    fn get(&self, key: &K, timestamp: u64) -> Result<Option<Record<'_>>, MyDatabaseError> {
+        let versions = self.versions(key)?;
+        Ok(into_visible(versions, timestamp).map(|row| Record::Owned(row, &self.positions)))
+    }
+    fn range<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64) -> Rows<'a, K> {
+        Box::new(self.tree.range(lower, upper).map(move |entry| {
+            let (key, bytes) = entry?;
+            let versions = decode_versions(&bytes, self.entry.columns.len())?;
+            Ok((key, into_visible(versions, timestamp).map(|row| Record::Owned(row, &self.positions))))
+        }))
+    }
+    fn collect_garbage(&mut self, key: &K, oldest_snapshot: Option<u64>) -> Result<bool, MyDatabaseError> {
+        let mut versions = self.versions(key)?;
+        let deleted_left = drop_old_versions(&mut versions, oldest_snapshot);
+        self.save_versions(key.clone(), &versions)?;
+        Ok(deleted_left)
+    }
+    fn version_count(&self, key: &K) -> Result<usize, MyDatabaseError> {
+        Ok(self.versions(key)?.len())
+    }
+    /// Waits for transactions of other tables in the file, pages are changed by one at a time
+    fn begin(&mut self) -> Result<(), MyDatabaseError> {
+        self.file.pager.begin()?;
+        self.saved_clock = self.entry.clock;
+        Ok(())
+    }
+    /// Saves the clock of the table with its pages, so reopened tables continue from it
+    fn commit(&mut self, clock: u64) -> Result<(), MyDatabaseError> {
+        if self.entry.clock != clock {
+            self.entry.clock = clock;
+            if let Err(e) = self.file.save_entry(&self.name, &self.entry) {
+                self.rollback();
+                return Err(e);
+            }
+        }
+        let committed = self.file.pager.commit();
+        if committed.is_err() {
+            self.entry.clock = self.saved_clock;
+        }
+        committed
+    }
+    fn rollback(&mut self) {
+        self.file.pager.rollback();
+        self.entry.clock = self.saved_clock;
+    }
+}
+
+/// Row of the newest version visible at `timestamp`
+fn into_visible(versions: Vec<Version<Vec<Value>>>, timestamp: u64) -> Option<Vec<Value>> {
+    versions.into_iter().rev().find(|version| version.is_visible_at(timestamp)).map(|version| version.row)
+}
+
+fn encode_versions(versions: &[Version<Vec<Value>>]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(versions.len() as u32).to_le_bytes());
+    for version in versions {
+        buf.extend_from_slice(&version.created.to_le_bytes());
+        buf.extend_from_slice(&version.deleted.unwrap_or(0).to_le_bytes()); // timestamps start at 1
+        version.row.iter().for_each(|value| encode_value(value, &mut buf));
+    }
+    buf
+}
+fn decode_versions(bytes: &[u8], column_count: usize) -> Result<Vec<Version<Vec<Value>>>, MyDatabaseError> {
+    let mut reader = Reader::new(bytes);
+    let count = reader.u32()?;
+    let mut versions = Vec::new();
+    for _ in 0..count {
+        let created = reader.u64()?;
+        let deleted = Some(reader.u64()?).filter(|deleted| *deleted != 0);
+        let row = (0..column_count).map(|_| reader.value()).collect::<Result<Vec<Value>, MyDatabaseError>>()?;
+        versions.push(Version { created, deleted, row });
+    }
+    Ok(versions)
+}
@@ -0,0 +1,496 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use crate::db_errors::MyDatabaseError;
+
+/// Size of every page of a database file
+pub const PAGE_SIZE: usize = 8192;
+const CHECKSUM_LEN: usize = 4;
+/// Bytes of a page available to its users, the rest holds its checksum
+pub const PAGE_CAPACITY: usize = PAGE_SIZE - CHECKSUM_LEN;
+
+const MAGIC: &[u8; 8] = b"PROJ1PG\0";
+const FORMAT_VERSION: u32 = 1;
+/// Page with the header of the pager, users get pages after it
+const HEADER_PAGE: u64 = 0;
+const NO_PAGE: u64 = 0;
+/// Marks the end of a complete transaction in the log
+const COMMIT: u64 = u64::MAX;
+
+/// File of fixed-size pages read through an LRU buffer pool. Pages are changed in transactions:
+/// changed pages evicted before commit are written to the log, commit writes the rest and marks the log complete,
+/// then copies them to the file, so a crash leaves either the old or the new version of every page of a transaction.
+/// A transaction can change more pages than the pool holds.
+/// Every page is checksummed, damaged pages are reported when they are read
+#[derive(Debug)]
+pub struct Pager {
+    state: Mutex<PagerState>,
+    writing: Mutex<bool>, // whether a transaction is running
+    writer_done: Condvar,
+}
+#[derive(Debug)]
+struct PagerState {
+    file: File,
+    log: File,
+    capacity: usize, // pages kept in memory
+    page_count: u64,
+    free_head: u64, // first page of the list of freed pages
+    committed: (u64, u64), // page count and free list of the last commit
+    unapplied: Vec<u64>, // committed pages not yet copied from the log to the file
+    logged: HashMap<u64, u64>, // page to offset of its record in the log
+    frames: HashMap<u64, Frame>,
+    recency: BTreeMap<u64, u64>, // last use to page, the first one is evicted
+    tick: u64,
+}
+#[derive(Debug)]
+struct Frame {
+    data: Box<[u8]>,
+    dirty: bool, // changed since it was last written to the log
+    used: u64,
+}
+
+impl Pager {
+    /// Opens the file or creates an empty one, at most `capacity` pages are cached.
+    /// A transaction left complete in the log by a crash is finished first
+    pub fn open(path: &Path, capacity: usize) -> Result<Pager, MyDatabaseError> {
+        let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(log_path(path))?;
+        replay_log(&mut file, &mut log)?;
+        let len = file.metadata()?.len();
+        if len % PAGE_SIZE as u64 != 0 {
+            return Err(MyDatabaseError::CorruptedFile(format!("size {} is not a multiple of the page size {}", len, PAGE_SIZE)));
+        }
+        let page_count = len / PAGE_SIZE as u64;
+        let pager = Pager {
+            state: Mutex::new(PagerState {
+                file,
+                log,
+                capacity: capacity.max(1),
+                page_count,
+                free_head: NO_PAGE,
+                committed: (page_count, NO_PAGE),
+                unapplied: Vec::new(),
+                logged: HashMap::new(),
+                frames: HashMap::new(),
+                recency: BTreeMap::new(),
+                tick: 0,
+            }),
+            writing: Mutex::new(false),
+            writer_done: Condvar::new(),
+        };
+        if page_count == 0 {
+            pager.atomically(|| pager.allocate().map(|_| ()))?;
+        } else {
+            let header = pager.read(HEADER_PAGE)?;
+            let version = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+            let page_size = u32::from_le_bytes([header[12], header[13], header[14], header[15]]) as usize;
+            if &header[..8] != MAGIC || version != FORMAT_VERSION || page_size != PAGE_SIZE {
+                return Err(MyDatabaseError::CorruptedFile("not a database file of this version".to_string()));
+            }
+            let mut free_head = [0; 8];
+            free_head.copy_from_slice(&header[16..24]);
+            let mut state = pager.lock();
+            state.free_head = u64::from_le_bytes(free_head);
+            state.committed = (page_count, state.free_head);
+        }
+        Ok(pager)
+    }
+    fn lock(&self) -> MutexGuard<'_, PagerState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+    pub fn page_count(&self) -> u64 {
+        self.lock().page_count
+    }
+    /// Copy of the usable part of the page
+    pub fn read(&self, page: u64) -> Result<Vec<u8>, MyDatabaseError> {
+        Ok(self.lock().frame(page)?.data[..PAGE_CAPACITY].to_vec())
+    }
+    /// Replaces contents of the page, the rest of it is zeroed
+    pub fn write(&self, page: u64, data: &[u8]) -> Result<(), MyDatabaseError> {
+        if data.len() > PAGE_CAPACITY {
+            return Err(MyDatabaseError::CorruptedFile(format!("{} bytes don't fit in page {}", data.len(), page)));
+        }
+        self.lock().write(page, data)
+    }
+    /// Zeroed page, a freed one or a new one at the end of the file
+    pub fn allocate(&self) -> Result<u64, MyDatabaseError> {
+        let mut state = self.lock();
+        if state.free_head != NO_PAGE {
+            let page = state.free_head;
+            let data = &state.frame(page)?.data;
+            let mut next = [0; 8];
+            next.copy_from_slice(&data[..8]);
+            state.free_head = u64::from_le_bytes(next);
+            state.write(page, &[])?;
+            return Ok(page);
+        }
+        state.make_room()?;
+        let page = state.page_count;
+        state.page_count += 1;
+        let frame = Frame {
+            data: vec![0; PAGE_SIZE].into_boxed_slice(),
+            dirty: true,
+            used: 0,
+        };
+        state.frames.insert(page, frame);
+        state.frame(page)?;
+        Ok(page)
+    }
+    /// Puts the page on the list of free pages, allocate gives it out again
+    pub fn free(&self, page: u64) -> Result<(), MyDatabaseError> {
+        let mut state = self.lock();
+        let next = state.free_head;
+        state.write(page, &next.to_le_bytes())?;
+        state.free_head = page;
+        Ok(())
+    }
+
+    /// Waits until no other transaction runs and starts one. Pages are only changed in transactions.
+    /// Pages of the last commit that didn't reach the file are copied first, no transaction starts until they do
+    pub fn begin(&self) -> Result<(), MyDatabaseError> {
+        let mut writing = self.writing.lock().unwrap_or_else(PoisonError::into_inner);
+        while *writing {
+            writing = self.writer_done.wait(writing).unwrap_or_else(PoisonError::into_inner);
+        }
+        *writing = true;
+        drop(writing);
+        let mut state = self.lock();
+        let unapplied = std::mem::take(&mut state.unapplied);
+        let applied = state.apply(unapplied);
+        drop(state);
+        if applied.is_err() {
+            self.end();
+        }
+        applied
+    }
+    fn end(&self) {
+        *self.writing.lock().unwrap_or_else(PoisonError::into_inner) = false;
+        self.writer_done.notify_one();
+    }
+    /// Makes the changes of the transaction durable. If the log can't be written, they are rolled back.
+    /// Once it's written they are committed, even if copying them to the file fails - that is retried
+    /// when the next transaction begins or the file is opened again
+    pub fn commit(&self) -> Result<(), MyDatabaseError> {
+        let result = self.lock().commit();
+        if result.is_err() {
+            self.lock().rollback();
+        }
+        self.end();
+        result
+    }
+    /// Drops the changes of the transaction
+    pub fn rollback(&self) {
+        self.lock().rollback();
+        self.end();
+    }
+    /// Runs the changes in a transaction, committed when they succeed
+    pub fn atomically<T>(&self, changes: impl FnOnce() -> Result<T, MyDatabaseError>) -> Result<T, MyDatabaseError> {
+        self.begin()?;
+        match changes() {
+            Ok(result) => self.commit().map(|_| result),
+            Err(e) => {
+                self.rollback();
+                Err(e)
+            }
+        }
+    }
+}
+impl PagerState {
+    /// Cached page, read from the log or the file and checked if needed
+    fn frame(&mut self, page: u64) -> Result<&mut Frame, MyDatabaseError> {
+        if page >= self.page_count {
+            return Err(MyDatabaseError::CorruptedFile(format!("page {} is past the end of the file", page)));
+        }
+        let mut loaded = None;
+        if !self.frames.contains_key(&page) {
+            self.make_room()?;
+            let mut data = vec![0; PAGE_SIZE].into_boxed_slice();
+            match self.logged.get(&page) {
+                Some(offset) => {
+                    self.log.seek(SeekFrom::Start(offset + 8))?;
+                    self.log.read_exact(&mut data)?;
+                },
+                None => {
+                    self.file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+                    self.file.read_exact(&mut data)?;
+                },
+            }
+            if !has_valid_checksum(&data) {
+                return Err(MyDatabaseError::CorruptedFile(format!("checksum of page {} doesn't match", page)));
+            }
+            loaded = Some(data);
+        }
+        self.tick += 1;
+        let frame = self.frames.entry(page).or_insert_with(|| Frame {
+            data: loaded.unwrap_or_default(),
+            dirty: false,
+            used: 0,
+        });
+        self.recency.remove(&frame.used);
+        frame.used = self.tick;
+        self.recency.insert(self.tick, page);
+        Ok(frame)
+    }
+    fn write(&mut self, page: u64, data: &[u8]) -> Result<(), MyDatabaseError> {
+        let frame = self.frame(page)?;
+        frame.data[..data.len()].copy_from_slice(data);
+        frame.data[data.len()..].fill(0);
+        frame.dirty = true;
+        Ok(())
+    }
+    /// Evicts least recently used pages when the pool is full, changed ones are written to the log first
+    fn make_room(&mut self) -> Result<(), MyDatabaseError> {
+        while self.frames.len() >= self.capacity {
+            let Some((used, page)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Err(e) = self.log_page(page) {
+                self.recency.insert(used, page);
+                return Err(e);
+            }
+            self.frames.remove(&page);
+        }
+        Ok(())
+    }
+    /// Writes a changed page to its record in the log, a page is logged once per transaction.
+    /// The records only count once commit marks them complete
+    fn log_page(&mut self, page: u64) -> Result<(), MyDatabaseError> {
+        let Some(frame) = self.frames.get_mut(&page).filter(|frame| frame.dirty) else {
+            return Ok(());
+        };
+        let records = self.logged.len() as u64;
+        let offset = *self.logged.entry(page).or_insert(records * (8 + PAGE_SIZE) as u64);
+        seal(&mut frame.data);
+        self.log.seek(SeekFrom::Start(offset))?;
+        self.log.write_all(&page.to_le_bytes())?;
+        self.log.write_all(&frame.data)?;
+        frame.dirty = false;
+        Ok(())
+    }
+    /// Writes changed pages to the log, then to the file. An error means nothing was committed
+    fn commit(&mut self) -> Result<(), MyDatabaseError> {
+        if (self.page_count, self.free_head) != self.committed || self.frames.get(&HEADER_PAGE).is_some_and(|frame| frame.dirty) {
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+            header.extend_from_slice(&(PAGE_SIZE as u32).to_le_bytes());
+            header.extend_from_slice(&self.free_head.to_le_bytes());
+            self.write(HEADER_PAGE, &header)?;
+        }
+        let mut dirty: Vec<u64> = self.frames.iter().filter(|(_, frame)| frame.dirty).map(|(page, _)| *page).collect();
+        dirty.sort_unstable();
+        for page in dirty {
+            self.log_page(page)?;
+        }
+        if self.logged.is_empty() {
+            return Ok(());
+        }
+        let end = self.logged.len() as u64 * (8 + PAGE_SIZE) as u64;
+        let mut checksum = crc32fast::Hasher::new();
+        let mut records = vec![0; 8 + PAGE_SIZE];
+        self.log.seek(SeekFrom::Start(0))?;
+        for _ in 0..self.logged.len() {
+            self.log.read_exact(&mut records)?;
+            checksum.update(&records);
+        }
+        checksum.update(&COMMIT.to_le_bytes());
+        self.log.seek(SeekFrom::Start(end))?;
+        self.log.write_all(&COMMIT.to_le_bytes())?;
+        self.log.write_all(&checksum.finalize().to_le_bytes())?;
+        self.log.set_len(end + 12)?;
+        self.log.sync_data()?;
+
+        self.committed = (self.page_count, self.free_head);
+        let _ = self.apply(self.logged.keys().copied().collect()); // kept as unapplied, the next transaction can't begin until they are copied
+        Ok(())
+    }
+    /// Copies committed pages from the log to the file, they are kept as unapplied until it succeeds
+    fn apply(&mut self, pages: Vec<u64>) -> Result<(), MyDatabaseError> {
+        if pages.is_empty() {
+            return Ok(());
+        }
+        let PagerState { file, log, logged, .. } = self;
+        let mut data = vec![0; PAGE_SIZE];
+        let copied = pages.iter()
+            .try_for_each(|page| match logged.get(page) {
+                Some(offset) => {
+                    log.seek(SeekFrom::Start(offset + 8))?;
+                    log.read_exact(&mut data)?;
+                    write_page(file, *page, &data)
+                },
+                None => Ok(()),
+            })
+            .and_then(|_| Ok(file.sync_data()?))
+            .and_then(|_| Ok(log.set_len(0)?));
+        if copied.is_err() {
+            self.unapplied = pages;
+            return copied;
+        }
+        self.logged.clear();
+        Ok(())
+    }
+    /// Drops changed pages, also the ones read back from the log
+    fn rollback(&mut self) {
+        let dropped: Vec<u64> = self.frames.iter()
+            .filter(|(page, frame)| frame.dirty || self.logged.contains_key(page))
+            .map(|(page, _)| *page)
+            .collect();
+        for page in dropped {
+            if let Some(frame) = self.frames.remove(&page) {
+                self.recency.remove(&frame.used);
+            }
+        }
+        self.logged.clear();
+        let _ = self.log.set_len(0); // records without the commit mark are ignored anyway
+        (self.page_count, self.free_head) = self.committed;
+    }
+}
+
+fn log_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push("-wal");
+    PathBuf::from(name)
+}
+
+/// Copies pages of a complete transaction from the log to the file. An incomplete one was never committed,
+/// the file wasn't touched by it, so it's dropped. The log is read a record at a time, it can be larger than memory
+fn replay_log(file: &mut File, log: &mut File) -> Result<(), MyDatabaseError> {
+    let len = log.metadata()?.len();
+    let record = (8 + PAGE_SIZE) as u64;
+    let mut entry = vec![0; 8 + PAGE_SIZE];
+    let complete = len >= 12 && (len - 12) % record == 0 && {
+        let records = (len - 12) / record;
+        let mut checksum = crc32fast::Hasher::new();
+        log.seek(SeekFrom::Start(0))?;
+        for _ in 0..records {
+            log.read_exact(&mut entry)?;
+            checksum.update(&entry);
+        }
+        let mut tail = [0; 12];
+        log.read_exact(&mut tail)?;
+        checksum.update(&tail[..8]);
+        tail[..8] == COMMIT.to_le_bytes() && checksum.finalize().to_le_bytes() == tail[8..]
+    };
+    if complete {
+        log.seek(SeekFrom::Start(0))?;
+        for _ in 0..(len - 12) / record {
+            log.read_exact(&mut entry)?;
+            let (page, data) = entry.split_at(8);
+            let mut number = [0; 8];
+            number.copy_from_slice(page);
+            write_page(file, u64::from_le_bytes(number), data)?;
+        }
+        file.sync_data()?;
+    }
+    if len > 0 {
+        log.set_len(0)?;
+        log.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Puts the checksum of the page in its last bytes
+fn seal(data: &mut [u8]) {
+    let checksum = crc32fast::hash(&data[..PAGE_CAPACITY]);
+    data[PAGE_CAPACITY..].copy_from_slice(&checksum.to_le_bytes());
+}
+fn has_valid_checksum(data: &[u8]) -> bool {
+    crc32fast::hash(&data[..PAGE_CAPACITY]).to_le_bytes() == data[PAGE_CAPACITY..]
+}
+
+fn write_page(file: &mut File, page: u64, data: &[u8]) -> Result<(), MyDatabaseError> {
+    file.seek(SeekFrom::Start(page * PAGE_SIZE as u64))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_committed_transactions_reach_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.db");
+        let pager = Pager::open(&path, 2).unwrap();
+        let pages = pager.atomically(|| (0..4).map(|_| pager.allocate()).collect::<Result<Vec<u64>, _>>()).unwrap();
+        pager.atomically(|| pages.iter().try_for_each(|page| pager.write(*page, &page.to_le_bytes()))).unwrap();
+
+        // changed pages that don't fit in the pool go to the log and are dropped by the rollback
+        pager.begin().unwrap();
+        for page in &pages {
+            pager.write(*page, b"uncommitted").unwrap();
+        }
+        pager.free(pages[0]).unwrap();
+        pager.rollback();
+        assert_eq!(&pager.read(pages[1]).unwrap()[..8], &pages[1].to_le_bytes());
+        pager.atomically(|| pager.free(pages[3])).unwrap();
+        drop(pager);
+
+        let pager = Pager::open(&path, 2).unwrap();
+        assert_eq!(&pager.read(pages[2]).unwrap()[..8], &pages[2].to_le_bytes());
+        assert_eq!(pager.atomically(|| pager.allocate()).unwrap(), pages[3]);
+        drop(pager);
+
+        // a transaction complete in the log is finished when the file is opened, a torn one is dropped
+        let log = log_path(&path);
+        let mut torn = vec![0; 8 + PAGE_SIZE];
+        torn[..8].copy_from_slice(&pages[1].to_le_bytes());
+        std::fs::write(&log, &torn).unwrap();
+        let pager = Pager::open(&path, 2).unwrap();
+        assert_eq!(&pager.read(pages[1]).unwrap()[..8], &pages[1].to_le_bytes());
+        drop(pager);
+        let mut page = vec![0; PAGE_SIZE];
+        page[..5].copy_from_slice(b"after");
+        seal(&mut page);
+        let mut complete = pages[1].to_le_bytes().to_vec();
+        complete.extend_from_slice(&page);
+        complete.extend_from_slice(&COMMIT.to_le_bytes());
+        complete.extend_from_slice(&crc32fast::hash(&complete).to_le_bytes());
+        std::fs::write(&log, &complete).unwrap();
+        let pager = Pager::open(&path, 2).unwrap();
+        assert_eq!(&pager.read(pages[1]).unwrap()[..5], b"after");
+        drop(pager);
+
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[pages[2] as usize * PAGE_SIZE + 100] ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        let pager = Pager::open(&path, 2).unwrap();
+        assert!(matches!(pager.read(pages[2]), Err(MyDatabaseError::CorruptedFile(_))));
+    }
+
+    #[test]
+    fn transactions_can_change_more_pages_than_the_pool_holds() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pages.db");
+        let pager = Pager::open(&path, 4).unwrap();
+        let pages = pager.atomically(|| {
+            let pages = (0..50).map(|_| pager.allocate()).collect::<Result<Vec<u64>, _>>()?;
+            for page in &pages {
+                pager.write(*page, &page.to_le_bytes())?;
+                assert!(pager.lock().frames.len() <= 4);
+            }
+            // pages written to the log are read back from it
+            assert_eq!(&pager.read(pages[0])?[..8], &pages[0].to_le_bytes());
+            pager.write(pages[0], b"changed again")?;
+            Ok(pages)
+        }).unwrap();
+
+        pager.begin().unwrap();
+        for page in &pages {
+            pager.write(*page, b"uncommitted").unwrap();
+        }
+        assert!(pager.lock().frames.len() <= 4);
+        pager.rollback();
+        assert_eq!(&pager.read(pages[1]).unwrap()[..8], &pages[1].to_le_bytes());
+        drop(pager);
+
+        let pager = Pager::open(&path, 4).unwrap();
+        assert_eq!(&pager.read(pages[0]).unwrap()[..13], b"changed again");
+        for page in &pages[1..] {
+            assert_eq!(&pager.read(*page).unwrap()[..8], &page.to_le_bytes());
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use crate::models::db_structure::{Value, ValueType};
+
+/// Typed result of a query - ordered columns with their types and rows of values.
+/// Rendering to text is a separate step, so embedding applications can use the values directly.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResultSet {
+    columns: Vec<String>,
+    column_types: Vec<ValueType>,
+    rows: Vec<Vec<Value>>,
+}
+impl ResultSet {
+    pub fn new(columns: Vec<String>, column_types: Vec<ValueType>) -> Self {
+        ResultSet {
+            columns,
+            column_types,
+            rows: Vec::new(),
+        }
+    }
+    pub fn push_row(&mut self, row: Vec<Value>) {
+        self.rows.push(row);
+    }
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+    pub fn column_types(&self) -> &[ValueType] {
+        &self.column_types
+    }
+    pub fn rows(&self) -> &[Vec<Value>] {
+        &self.rows
+    }
+    pub fn into_rows(self) -> Vec<Vec<Value>> {
+        self.rows
+    }
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+    pub fn get_column_index(&self, column_name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c == column_name)
+    }
+    pub fn get_value(&self, row: usize, column_name: &str) -> Option<&Value> {
+        let index = self.get_column_index(column_name)?;
+        self.rows.get(row)?.get(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db_structure::{AnyDatabase, Database};
+    use crate::models::test_utils::run;
+
+    #[test]
+    fn select_returns_typed_rows_in_declared_order() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE items KEY id FIELDS id: Int, name: String, price: Float, active: Bool").unwrap();
+        run(&db, "INSERT id = 2, name = \"b\", price = 2.5, active = false INTO items").unwrap();
+        run(&db, "INSERT id = 1, name = \"a\", price = 1.5, active = true INTO items").unwrap();
+
+        let result = run(&db, "SELECT * FROM items WHERE price > 1").unwrap().unwrap();
+        assert_eq!(result.columns(), ["id", "name", "price", "active"]);
+        assert_eq!(result.column_types(), [ValueType::Int, ValueType::String, ValueType::Float, ValueType::Bool]);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result.rows()[0], vec![Value::Int(1), Value::String("a".to_string()), Value::Float(1.5), Value::Bool(true)]);
+        assert_eq!(result.get_value(1, "name"), Some(&Value::String("b".to_string())));
+    }
+
+    #[test]
+    fn non_queries_return_no_result() {
+        let db = AnyDatabase::StringDatabase(Database::<String>::new());
+        assert!(run(&db, "CREATE t KEY k FIELDS k: String").unwrap().is_none());
+        assert!(run(&db, "INSERT k = \"x\" INTO t").unwrap().is_none());
+        let result = run(&db, "SELECT k FROM t").unwrap().unwrap();
+        assert_eq!(result.into_rows(), vec![vec![Value::String("x".to_string())]]);
+    }
+
+}
@@ -0,0 +1,186 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use flate2::Compression;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use tempfile::NamedTempFile;
+use crate::db_errors::MyDatabaseError;
+
+const MAGIC: &[u8; 8] = b"PROJ1SV\0";
+const FORMAT_VERSION: u32 = 2;
+const COMPRESSED: u8 = 1;
+const HEADER_LEN: usize = 8 + 4 + 1 + 4 + 4;
+/// Commands are grouped into blocks of about this many bytes, a longer command takes a block of its own
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// Saves commands as a header followed by blocks of length prefixed commands, each block with its length and checksum.
+/// The file is written next to `path` and renamed over it only after it was read back and verified,
+/// so a failed save or a crash leaves the previous file as it was
+pub fn save(path: &Path, commands: &[String], compress: bool) -> Result<(), MyDatabaseError> {
+    let blocks = split_into_blocks(commands);
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    buf.push(if compress { COMPRESSED } else { 0 });
+    buf.extend_from_slice(&(blocks.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&crc32fast::hash(&buf).to_le_bytes());
+    for text in blocks {
+        let stored = if compress {
+            let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(&text)?;
+            encoder.finish()?
+        } else {
+            text.clone()
+        };
+        buf.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&(text.len() as u32).to_le_bytes());
+        // the checksum covers the bytes as stored, so also flips that decompress to the same text are found
+        buf.extend_from_slice(&crc32fast::hash(&stored).to_le_bytes());
+        buf.extend_from_slice(&stored);
+    }
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    fs::create_dir_all(directory)?;
+    let mut file = NamedTempFile::new_in(directory)?;
+    file.write_all(&buf)?;
+    file.as_file().sync_all()?;
+    let written = fs::read(file.path())?;
+    if parse(&written)? != commands {
+        return Err(MyDatabaseError::CorruptedSaveFile("file read back differs from the saved commands".to_string()));
+    }
+    file.persist(path).map_err(|e| e.error)?;
+    // the rename is only durable once the directory is synced
+    #[cfg(unix)]
+    fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+/// Commands of a saved file, all blocks are verified before any of them is returned.
+/// Files without the header are plain scripts, one command per line, as saved by older versions
+pub fn load(path: &Path) -> Result<Vec<String>, MyDatabaseError> {
+    parse(&fs::read(path)?)
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<String>, MyDatabaseError> {
+    let corrupted = |message: &str| MyDatabaseError::CorruptedSaveFile(message.to_string());
+    if !bytes.starts_with(MAGIC) {
+        if bytes.is_empty() || (!MAGIC.starts_with(bytes) && !bytes.contains(&0)) {
+            let text = std::str::from_utf8(bytes).map_err(|_| corrupted("script is not valid UTF-8"))?;
+            return Ok(text.lines().filter(|line| !line.trim().is_empty()).map(str::to_string).collect());
+        }
+        return Err(corrupted("header is missing or damaged"));
+    }
+    let Some((header, mut rest)) = bytes.split_at_checked(HEADER_LEN) else {
+        return Err(corrupted("header is truncated"));
+    };
+    let (fields, checksum) = header.split_at(HEADER_LEN - 4);
+    if crc32fast::hash(fields).to_le_bytes() != checksum {
+        return Err(corrupted("header checksum doesn't match"));
+    }
+    let version = read_u32(&fields[8..]);
+    if version != FORMAT_VERSION {
+        return Err(MyDatabaseError::CorruptedSaveFile(format!("format {} is not supported", version)));
+    }
+    let compressed = match fields[12] {
+        0 => false,
+        COMPRESSED => true,
+        _ => return Err(corrupted("unknown flags in the header")),
+    };
+    let block_count = read_u32(&fields[13..]);
+
+    let mut commands = Vec::new();
+    for block in 0..block_count {
+        let Some((block_header, after)) = rest.split_at_checked(12) else {
+            return Err(MyDatabaseError::CorruptedSaveFile(format!("block {} is truncated", block)));
+        };
+        let stored_len = read_u32(block_header) as usize;
+        let text_len = read_u32(&block_header[4..]) as usize;
+        let Some((stored, after)) = after.split_at_checked(stored_len) else {
+            return Err(MyDatabaseError::CorruptedSaveFile(format!("block {} is truncated", block)));
+        };
+        if crc32fast::hash(stored).to_le_bytes() != block_header[8..] {
+            return Err(MyDatabaseError::CorruptedSaveFile(format!("checksum of block {} doesn't match", block)));
+        }
+        let text = if compressed {
+            // the length only limits decompression, damaged data can't make it allocate more
+            let mut text = Vec::new();
+            DeflateDecoder::new(stored).take(text_len as u64 + 1).read_to_end(&mut text)
+                .map_err(|_| MyDatabaseError::CorruptedSaveFile(format!("block {} can't be decompressed", block)))?;
+            text
+        } else {
+            stored.to_vec()
+        };
+        if text.len() != text_len {
+            return Err(MyDatabaseError::CorruptedSaveFile(format!("length of block {} doesn't match", block)));
+        }
+        let mut text = text.as_slice();
+        while !text.is_empty() {
+            let Some((command, after)) = text.split_at_checked(4)
+                .and_then(|(len, after)| after.split_at_checked(read_u32(len) as usize)) else {
+                return Err(MyDatabaseError::CorruptedSaveFile(format!("command in block {} is truncated", block)));
+            };
+            let command = String::from_utf8(command.to_vec())
+                .map_err(|_| MyDatabaseError::CorruptedSaveFile(format!("block {} is not valid UTF-8", block)))?;
+            commands.push(command);
+            text = after;
+        }
+        rest = after;
+    }
+    if !rest.is_empty() {
+        return Err(corrupted("unexpected data after the last block"));
+    }
+    Ok(commands)
+}
+
+/// Commands with their length before each, so they can contain newlines. A command never spans two blocks
+fn split_into_blocks(commands: &[String]) -> Vec<Vec<u8>> {
+    let mut blocks = Vec::new();
+    let mut block = Vec::new();
+    for command in commands {
+        if !block.is_empty() && block.len() + 4 + command.len() > BLOCK_SIZE {
+            blocks.push(std::mem::take(&mut block));
+        }
+        block.extend_from_slice(&(command.len() as u32).to_le_bytes());
+        block.extend_from_slice(command.as_bytes());
+    }
+    if !block.is_empty() {
+        blocks.push(block);
+    }
+    blocks
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn damaged_save_files_are_refused() {
+        let commands: Vec<String> = (0..5000).map(|id| format!("INSERT id = {}, name = \"name {}\" INTO t", id, id)).collect();
+        let dir = tempfile::tempdir().unwrap();
+        for compress in [false, true] {
+            let path = dir.path().join(format!("save_{}.sav", compress));
+            save(&path, &commands, compress).unwrap();
+            assert_eq!(load(&path).unwrap(), commands);
+
+            let bytes = fs::read(&path).unwrap();
+            assert!(parse(&bytes[..bytes.len() - 10]).is_err());
+            for position in [3, 14, HEADER_LEN + 5, bytes.len() / 2, bytes.len() - 1] {
+                let mut damaged = bytes.clone();
+                damaged[position] ^= 0x40;
+                assert!(matches!(parse(&damaged), Err(MyDatabaseError::CorruptedSaveFile(_))), "byte {} changed", position);
+            }
+        }
+        let multi_line = vec!["CREATE t KEY id FIELDS id: Int, name: String".to_string(), "INSERT id = 2,\nname = \"a\nb\"\nINTO t".to_string(), String::new()];
+        let path = dir.path().join("multi_line.sav");
+        save(&path, &multi_line, false).unwrap();
+        assert_eq!(load(&path).unwrap(), multi_line);
+        assert_eq!(parse(b"CREATE t KEY id FIELDS id: Int\n\nINSERT id = 1 INTO t\n").unwrap(), ["CREATE t KEY id FIELDS id: Int", "INSERT id = 1 INTO t"]);
+    }
+}
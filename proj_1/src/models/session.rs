@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+use crate::models::output_format::OutputFormat;
+use crate::models::result_set::ResultSet;
+
+/// State of a single client - its command history and settings
+#[derive(Debug, Default)]
+pub struct Session {
+    executed_commands: Vec<String>,
+    output_format: OutputFormat,
+    user: Option<String>,
+}
+impl Session {
+    pub fn new(output_format: OutputFormat) -> Self {
+        Session {
+            executed_commands: Vec::new(),
+            output_format,
+            user: None,
+        }
+    }
+    pub fn record_command(&mut self, command: String) {
+        self.executed_commands.push(command);
+    }
+    pub fn executed_commands(&self) -> &[String] {
+        &self.executed_commands
+    }
+    pub fn output_format(&self) -> OutputFormat {
+        self.output_format
+    }
+    pub fn set_output_format(&mut self, output_format: OutputFormat) {
+        self.output_format = output_format;
+    }
+    pub fn login(&mut self, user: String) {
+        self.user = Some(user);
+    }
+    pub fn logout(&mut self) {
+        self.user = None;
+    }
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+    pub fn render(&self, result: &ResultSet, response_buf: &mut String) {
+        self.output_format.render(result, response_buf);
+    }
+}
+
+/// Keeps a session per client, sessions idle for longer than the timeout are dropped
+#[derive(Debug)]
+pub struct SessionManager<K: Hash + Eq> {
+    sessions: HashMap<K, (Session, Instant)>,
+    idle_timeout: Duration,
+    default_format: OutputFormat,
+}
+impl<K: Hash + Eq> SessionManager<K> {
+    pub fn new(idle_timeout: Duration, default_format: OutputFormat) -> Self {
+        SessionManager {
+            sessions: HashMap::new(),
+            idle_timeout,
+            default_format,
+        }
+    }
+    /// Returns session of the client, creating a fresh one for new clients
+    pub fn get_or_create(&mut self, key: K) -> &mut Session {
+        let default_format = self.default_format;
+        let entry = self.sessions.entry(key).or_insert_with(|| (Session::new(default_format), Instant::now()));
+        entry.1 = Instant::now();
+        &mut entry.0
+    }
+    /// New session with the default settings, not kept until it's inserted
+    pub fn new_session(&self) -> Session {
+        Session::new(self.default_format)
+    }
+    pub fn insert(&mut self, key: K, session: Session) {
+        self.sessions.insert(key, (session, Instant::now()));
+    }
+    /// Session of a known client, without creating one
+    pub fn get(&mut self, key: &K) -> Option<&mut Session> {
+        let entry = self.sessions.get_mut(key)?;
+        entry.1 = Instant::now();
+        Some(&mut entry.0)
+    }
+    pub fn remove(&mut self, key: &K) -> Option<Session> {
+        self.sessions.remove(key).map(|(session, _)| session)
+    }
+    /// Drops sessions idle at `now`, returns how many were dropped
+    pub fn expire_idle(&mut self, now: Instant) -> usize {
+        let before = self.sessions.len();
+        let idle_timeout = self.idle_timeout;
+        self.sessions.retain(|_, (_, last_activity)| now.saturating_duration_since(*last_activity) < idle_timeout);
+        before - self.sessions.len()
+    }
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_separate_and_expire() {
+        let mut manager = SessionManager::new(Duration::from_secs(60), OutputFormat::Table);
+        manager.get_or_create("a").record_command("CREATE x KEY k FIELDS k: Int".to_string());
+        manager.get_or_create("b").set_output_format(OutputFormat::Csv);
+        assert_eq!(manager.get_or_create("a").executed_commands().len(), 1);
+        assert!(manager.get_or_create("b").executed_commands().is_empty());
+        assert_eq!(manager.get_or_create("a").output_format(), OutputFormat::Table);
+
+        let started = Instant::now();
+        assert_eq!(manager.expire_idle(started), 0);
+        // b was used again a minute and a half later
+        let b_used = started + Duration::from_secs(90);
+        manager.sessions.get_mut("b").unwrap().1 = b_used;
+        assert_eq!(manager.expire_idle(b_used + Duration::from_secs(30)), 1);
+        assert_eq!(manager.len(), 1);
+        assert_eq!(manager.get_or_create("b").output_format(), OutputFormat::Csv);
+        assert!(manager.get_or_create("a").executed_commands().is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Value, ValueType};
+use crate::models::result_set::ResultSet;
+
+/// How results of two queries are combined
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperator {
+    Union,
+    UnionAll,
+    Intersect,
+    Except,
+}
+impl SetOperator {
+    /// Longer keywords first, so UNION ALL isn't read as UNION
+    pub const KEYWORDS: [&'static str; 4] = ["UNION ALL", "UNION", "INTERSECT", "EXCEPT"];
+
+    pub fn from_keyword(keyword: &str) -> Option<SetOperator> {
+        match keyword {
+            "UNION ALL" => Some(SetOperator::UnionAll),
+            "UNION" => Some(SetOperator::Union),
+            "INTERSECT" => Some(SetOperator::Intersect),
+            "EXCEPT" => Some(SetOperator::Except),
+            _ => None,
+        }
+    }
+    pub fn keyword(&self) -> &'static str {
+        match self {
+            SetOperator::Union => "UNION",
+            SetOperator::UnionAll => "UNION ALL",
+            SetOperator::Intersect => "INTERSECT",
+            SetOperator::Except => "EXCEPT",
+        }
+    }
+    /// Combines results of two queries, columns are named after the left one.
+    /// UNION ALL keeps rows in the order of the queries, other operators return distinct rows sorted by all columns
+    pub fn combine(self, left: ResultSet, right: ResultSet) -> Result<ResultSet, MyDatabaseError> {
+        let column_types = self.combined_types(left.column_types(), right.column_types())?;
+        let mut combined = ResultSet::new(left.columns().to_vec(), column_types.clone());
+        let left = promote_rows(left.into_rows(), &column_types);
+        let right = promote_rows(right.into_rows(), &column_types);
+        let rows = match self {
+            SetOperator::UnionAll => left.into_iter().chain(right).collect(),
+            SetOperator::Union => sorted_distinct(left.into_iter().chain(right).collect()),
+            SetOperator::Intersect | SetOperator::Except => {
+                let right = sorted_distinct(right);
+                let keep_found = self == SetOperator::Intersect;
+                sorted_distinct(left).into_iter()
+                    .filter(|row| right.binary_search_by(|probe| compare_rows(probe, row)).is_ok() == keep_found)
+                    .collect()
+            },
+        };
+        for row in rows {
+            combined.push_row(row);
+        }
+        Ok(combined)
+    }
+    /// Column types of the combined result - types have to match, except that Int with Float gives Float
+    pub fn combined_types(&self, left: &[ValueType], right: &[ValueType]) -> Result<Vec<ValueType>, MyDatabaseError> {
+        if left.len() != right.len() {
+            return Err(MyDatabaseError::IncompatibleSetOperation(self.keyword(), format!("left query has {} columns, right query has {}", left.len(), right.len())));
+        }
+        left.iter().zip(right).enumerate().map(|(index, (left, right))| match (left, right) {
+            _ if left == right => Ok(*left),
+            (ValueType::Int | ValueType::Float, ValueType::Int | ValueType::Float) => Ok(ValueType::Float),
+            _ => Err(MyDatabaseError::IncompatibleSetOperation(self.keyword(), format!("column {} is {} in the left query and {} in the right one", index + 1, left, right))),
+        }).collect()
+    }
+}
+
+/// Turns Int values into Floats in columns that became Float
+fn promote_rows(mut rows: Vec<Vec<Value>>, column_types: &[ValueType]) -> Vec<Vec<Value>> {
+    for row in &mut rows {
+        for (value, value_type) in row.iter_mut().zip(column_types) {
+            if let (Value::Int(i), ValueType::Float) = (&value, value_type) {
+                *value = Value::Float(*i as f64);
+            }
+        }
+    }
+    rows
+}
+
+fn compare_rows(left: &[Value], right: &[Value]) -> Ordering {
+    left.iter().zip(right)
+        .map(|(l, r)| l.compare(r).unwrap_or(Ordering::Equal))
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+fn sorted_distinct(mut rows: Vec<Vec<Value>>) -> Vec<Vec<Value>> {
+    rows.sort_by(|a, b| compare_rows(a, b));
+    rows.dedup_by(|a, b| compare_rows(a, b) == Ordering::Equal);
+    rows
+}
+
+/// DISTINCT - removes repeated rows, the first of equal rows stays where it was
+pub fn remove_duplicates(result: ResultSet) -> ResultSet {
+    let mut distinct = ResultSet::new(result.columns().to_vec(), result.column_types().to_vec());
+    let mut seen = HashSet::new();
+    for row in result.into_rows() {
+        if seen.insert(row.clone()) {
+            distinct.push_row(row);
+        }
+    }
+    distinct
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db_structure::{AnyDatabase, Database};
+    use crate::models::commands::AnyCommand;
+    use crate::models::session::Session;
+    use crate::models::test_utils::run;
+
+    #[test]
+    fn set_operations_check_shapes_and_sort_distinct_rows() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE a KEY id FIELDS id: Int, v: Float, name: String").unwrap();
+        run(&db, "CREATE b KEY id FIELDS id: Int, n: Int, label: String").unwrap();
+        for (id, v, name) in [(1, 1.0, "x"), (2, 2.5, "y"), (3, 3.0, "z")] {
+            run(&db, &format!("INSERT id = {}, v = {}, name = \"{}\" INTO a", id, v, name)).unwrap();
+        }
+        for (id, n, label) in [(5, 3, "z"), (6, 1, "x"), (7, 9, "q")] {
+            run(&db, &format!("INSERT id = {}, n = {}, label = \"{}\" INTO b", id, n, label)).unwrap();
+        }
+        let names = |db: &AnyDatabase, input: &str| -> Vec<String> {
+            run(db, input).unwrap().unwrap().rows().iter().map(|row| row.last().unwrap().to_string()).collect()
+        };
+
+        let union = run(&db, "SELECT v, name FROM a UNION SELECT n, label FROM b").unwrap().unwrap();
+        assert_eq!(union.columns(), ["v", "name"]);
+        assert_eq!(union.column_types(), [ValueType::Float, ValueType::String]);
+        assert_eq!(union.rows()[3], vec![Value::Float(9.0), Value::String("q".to_string())]);
+        assert_eq!(names(&db, "SELECT v, name FROM a UNION SELECT n, label FROM b"), ["x", "y", "z", "q"]);
+        assert_eq!(names(&db, "SELECT v, name FROM a UNION ALL SELECT n, label FROM b"), ["x", "y", "z", "z", "x", "q"]);
+        assert_eq!(names(&db, "SELECT v, name FROM a INTERSECT SELECT n, label FROM b"), ["x", "z"]);
+        assert_eq!(names(&db, "SELECT v, name FROM a EXCEPT SELECT n, label FROM b"), ["y"]);
+        // INTERSECT goes first
+        assert_eq!(names(&db, "SELECT name FROM a EXCEPT SELECT label FROM b INTERSECT SELECT label FROM b WHERE n < 5"), ["y"]);
+
+        let mut session = Session::default();
+        let mut error = |input: &str| AnyCommand::create_and_execute(input, &db, &mut session).unwrap_err().to_string();
+        assert_eq!(error("SELECT name FROM a UNION SELECT n FROM b"), "Error parsing command: UNION needs queries with matching columns: column 1 is String in the left query and Int in the right one");
+        assert_eq!(error("SELECT name FROM a EXCEPT SELECT n, label FROM b"), "Error parsing command: EXCEPT needs queries with matching columns: left query has 1 columns, right query has 2");
+    }
+
+    #[test]
+    fn distinct_keeps_first_of_equal_rows() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE t KEY id FIELDS id: Int, v: Float, name: String").unwrap();
+        for (id, v, name) in [(1, "1.0", "x"), (2, "NaN", "y"), (3, "1.0", "x"), (4, "NaN", "y"), (5, "-0.0", "x"), (6, "0.0", "x"), (7, "1.0", "z")] {
+            run(&db, &format!("INSERT id = {}, v = {}, name = \"{}\" INTO t", id, v, name)).unwrap();
+        }
+        let result = run(&db, "SELECT DISTINCT v, name FROM t").unwrap().unwrap();
+        assert_eq!(result.len(), 4);
+        assert_eq!(result.rows()[1][1], Value::String("y".to_string()));
+        assert!(matches!(result.rows()[1][0], Value::Float(f) if f.is_nan()));
+        assert_eq!(result.rows()[2], vec![Value::Float(-0.0), Value::String("x".to_string())]);
+        assert_eq!(run(&db, "SELECT DISTINCT name FROM t").unwrap().unwrap().len(), 3);
+        assert_eq!(run(&db, "SELECT name FROM t").unwrap().unwrap().len(), 7);
+    }
+}
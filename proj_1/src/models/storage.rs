@@ -0,0 +1,303 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+use std::ops::Bound;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Record, Value};
+use crate::models::where_parsing::WhereClause;
+
+/// Layout of the records of a table, chosen in `CREATE ... WITH (storage = row|columnar)`
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum StorageKind {
+    #[default]
+    Row,
+    Columnar,
+}
+impl StorageKind {
+    pub fn from_name(name: &str) -> Option<StorageKind> {
+        match name.trim().to_lowercase().as_str() {
+            "row" => Some(StorageKind::Row),
+            "columnar" => Some(StorageKind::Columnar),
+            _ => None,
+        }
+    }
+}
+
+/// Timestamp newer than every change, versions visible at it are the live ones
+pub const LATEST: u64 = u64::MAX;
+
+/// Keys in order with their versions visible at some timestamp
+pub type Rows<'a, K> = Box<dyn Iterator<Item = Result<(K, Option<Record<'a>>), MyDatabaseError>> + 'a>;
+
+/// Keeps versions of the records of a table. The table decides about timestamps,
+/// a storage only finds versions visible at them, so new formats don't change how queries see changes
+pub trait Storage<K>: Debug + Send + Sync {
+    /// Checks that values can be stored, before any row of a batch is inserted
+    fn check_row(&self, _values: &HashMap<String, Value>) -> Result<(), MyDatabaseError> {
+        Ok(())
+    }
+    /// Adds a live version created at `timestamp`, values have to hold every column
+    fn insert(&mut self, key: K, values: HashMap<String, Value>, timestamp: u64) -> Result<(), MyDatabaseError>;
+    /// Marks the live version of the key as deleted at `timestamp`, false if there is none
+    fn delete(&mut self, key: &K, timestamp: u64) -> Result<bool, MyDatabaseError>;
+    /// Version of the record visible at `timestamp`
+    fn get(&self, key: &K, timestamp: u64) -> Result<Option<Record<'_>>, MyDatabaseError>;
+    /// Keys in the range with versions visible at `timestamp`.
+    /// Keys without such a version are listed too, so a scan can continue after them
+    fn range<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64) -> Rows<'a, K>;
+    fn scan(&self, timestamp: u64) -> Rows<'_, K> {
+        self.range(Bound::Unbounded, Bound::Unbounded, timestamp)
+    }
+    /// Like range, but records not meeting the condition are listed without a version
+    fn range_where<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64, condition: &'a WhereClause) -> Rows<'a, K> where K: 'a {
+        filter_rows(self.range(lower, upper, timestamp), condition)
+    }
+    /// Drops deleted versions of the key no snapshot since `oldest_snapshot` can see,
+    /// returns whether deleted versions are left
+    fn collect_garbage(&mut self, key: &K, oldest_snapshot: Option<u64>) -> Result<bool, MyDatabaseError>;
+    /// Number of versions kept for the key
+    fn version_count(&self, key: &K) -> Result<usize, MyDatabaseError>;
+    /// Starts a group of changes, they are kept or dropped together
+    fn begin(&mut self) -> Result<(), MyDatabaseError> {
+        Ok(())
+    }
+    /// Makes changes since begin durable, `clock` is the timestamp of the last one
+    fn commit(&mut self, _clock: u64) -> Result<(), MyDatabaseError> {
+        Ok(())
+    }
+    /// Drops changes since begin, as far as the storage can undo them
+    fn rollback(&mut self) {}
+}
+
+/// Evaluates the condition for every record, hiding the ones that don't meet it
+pub(crate) fn filter_rows<'a, K: 'a>(rows: Rows<'a, K>, condition: &'a WhereClause) -> Rows<'a, K> {
+    Box::new(rows.map(move |row| {
+        let (key, record) = row?;
+        match record {
+            Some(record) if !condition.evaluate_for_record(&record)? => Ok((key, None)),
+            record => Ok((key, record)),
+        }
+    }))
+}
+
+/// How the in-memory storage keeps values of a row
+pub trait StoredRow: Debug + Send + Sync + Sized {
+    fn store(values: HashMap<String, Value>, columns: &[String]) -> Option<Self>;
+    fn record<'a>(&'a self, positions: &'a HashMap<String, usize>) -> Record<'a>;
+}
+/// Values by column name, every row repeats the names
+impl StoredRow for HashMap<String, Value> {
+    fn store(values: HashMap<String, Value>, columns: &[String]) -> Option<Self> {
+        (values.len() == columns.len()).then_some(values)
+    }
+    fn record<'a>(&'a self, _positions: &'a HashMap<String, usize>) -> Record<'a> {
+        Record::Named(self)
+    }
+}
+/// Values by column position, names are kept once per table
+impl StoredRow for Vec<Value> {
+    fn store(mut values: HashMap<String, Value>, columns: &[String]) -> Option<Self> {
+        let row: Vec<Value> = columns.iter().map_while(|column| values.remove(column)).collect();
+        (row.len() == columns.len() && values.is_empty()).then_some(row)
+    }
+    fn record<'a>(&'a self, positions: &'a HashMap<String, usize>) -> Record<'a> {
+        Record::Positional(self, positions)
+    }
+}
+
+/// One version of a record, visible to snapshots taken after it was created and before it was deleted
+#[derive(Debug)]
+pub(crate) struct Version<R> {
+    pub(crate) created: u64,
+    pub(crate) deleted: Option<u64>,
+    pub(crate) row: R,
+}
+impl<R> Version<R> {
+    pub(crate) fn is_visible_at(&self, timestamp: u64) -> bool {
+        self.created <= timestamp && self.deleted.is_none_or(|deleted| deleted > timestamp)
+    }
+}
+/// Newest version visible at `timestamp`, versions of a key are kept oldest first and only the last one can be live
+pub(crate) fn visible_version<R>(versions: &[Version<R>], timestamp: u64) -> Option<&Version<R>> {
+    versions.iter().rev().find(|version| version.is_visible_at(timestamp))
+}
+/// Marks the live version deleted, false if there is none
+pub(crate) fn delete_version<R>(versions: &mut [Version<R>], timestamp: u64) -> bool {
+    match versions.last_mut() {
+        Some(version) if version.deleted.is_none() => {
+            version.deleted = Some(timestamp);
+            true
+        }
+        _ => false,
+    }
+}
+/// Reverts changes made to the versions at `timestamp`, returns rows of the versions created at it
+pub(crate) fn undo_versions<R>(versions: &mut Vec<Version<R>>, timestamp: u64) -> Vec<R> {
+    let mut created = Vec::new();
+    while let Some(version) = versions.pop_if(|version| version.created == timestamp) {
+        created.push(version.row);
+    }
+    if let Some(version) = versions.last_mut()
+        && version.deleted == Some(timestamp) {
+        version.deleted = None;
+    }
+    created
+}
+/// Drops deleted versions no snapshot since `oldest_snapshot` can see, returns whether deleted versions are left
+pub(crate) fn drop_old_versions<R>(versions: &mut Vec<Version<R>>, oldest_snapshot: Option<u64>) -> bool {
+    versions.retain(|version| match version.deleted {
+        Some(deleted) => oldest_snapshot.is_some_and(|oldest| oldest < deleted),
+        None => true,
+    });
+    versions.iter().any(|version| version.deleted.is_some())
+}
+
+/// Versions of records in a B-tree map in memory
+#[derive(Debug)]
+pub struct MemoryStorage<K, R> {
+    columns: Vec<String>,
+    positions: HashMap<String, usize>,
+    records: BTreeMap<K, Vec<Version<R>>>, // versions of a key, oldest first, only the last one can be live
+    changed: Option<Vec<(K, u64)>>, // keys changed since begin with the timestamps, to undo them
+}
+/// The original format, a map of values in every row
+pub type MapStorage<K> = MemoryStorage<K, HashMap<String, Value>>;
+/// Rows as vectors of values in the order of columns
+pub type CompactStorage<K> = MemoryStorage<K, Vec<Value>>;
+
+impl<K, R> MemoryStorage<K, R> {
+    pub fn new(columns: &[String]) -> Self {
+        MemoryStorage {
+            columns: columns.to_vec(),
+            positions: columns.iter().enumerate().map(|(position, column)| (column.clone(), position)).collect(),
+            records: BTreeMap::new(),
+            changed: None,
+        }
+    }
+}
+impl<K: Ord + Clone + Debug + Send + Sync, R: StoredRow> Storage<K> for MemoryStorage<K, R> {
+    fn insert(&mut self, key: K, values: HashMap<String, Value>, timestamp: u64) -> Result<(), MyDatabaseError> {
+        let row = R::store(values, &self.columns).ok_or(MyDatabaseError::KeysMismatch)?;
+        let version = Version {
+            created: timestamp,
+            deleted: None,
+            row,
+        };
+        if let Some(changed) = &mut self.changed {
+            changed.push((key.clone(), timestamp));
+        }
+        self.records.entry(key).or_default().push(version);
+        Ok(())
+    }
+    fn delete(&mut self, key: &K, timestamp: u64) -> Result<bool, MyDatabaseError> {
+        let deleted = self.records.get_mut(key).is_some_and(|versions| delete_version(versions, timestamp));
+        if deleted && let Some(changed) = &mut self.changed {
+            changed.push((key.clone(), timestamp));
+        }
+        Ok(deleted)
+    }
+    fn get(&self, key: &K, timestamp: u64) -> Result<Option<Record<'_>>, MyDatabaseError> {
+        let version = self.records.get(key).and_then(|versions| visible_version(versions, timestamp));
+        Ok(version.map(|version| version.row.record(&self.positions)))
+    }
+    fn range<'a>(&'a self, lower: Bound<&K>, upper: Bound<&K>, timestamp: u64) -> Rows<'a, K> {
+        Box::new(self.records.range((lower, upper)).map(move |(key, versions)| {
+            let version = visible_version(versions, timestamp);
+            Ok((key.clone(), version.map(|version| version.row.record(&self.positions))))
+        }))
+    }
+    fn collect_garbage(&mut self, key: &K, oldest_snapshot: Option<u64>) -> Result<bool, MyDatabaseError> {
+        let Some(versions) = self.records.get_mut(key) else {
+            return Ok(false);
+        };
+        let deleted_left = drop_old_versions(versions, oldest_snapshot);
+        if versions.is_empty() {
+            self.records.remove(key);
+        }
+        Ok(deleted_left)
+    }
+    fn version_count(&self, key: &K) -> Result<usize, MyDatabaseError> {
+        Ok(self.records.get(key).map_or(0, Vec::len))
+    }
+    fn begin(&mut self) -> Result<(), MyDatabaseError> {
+        self.changed = Some(Vec::new());
+        Ok(())
+    }
+    fn commit(&mut self, _clock: u64) -> Result<(), MyDatabaseError> {
+        self.changed = None;
+        Ok(())
+    }
+    fn rollback(&mut self) {
+        for (key, timestamp) in self.changed.take().unwrap_or_default().into_iter().rev() {
+            if let Some(versions) = self.records.get_mut(&key) {
+                undo_versions(versions, timestamp);
+                if versions.is_empty() {
+                    self.records.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::borrow::Cow;
+    use crate::models::columnar::ColumnarStorage;
+    use crate::models::db_structure::ValueType;
+
+    fn values(id: i64, name: &str) -> HashMap<String, Value> {
+        HashMap::from([("id".to_string(), Value::Int(id)), ("name".to_string(), Value::String(name.to_string()))])
+    }
+
+    #[test]
+    fn row_formats_keep_the_same_versions() {
+        let columns = ["id".to_string(), "name".to_string()];
+        let storages: [Box<dyn Storage<i64>>; 2] = [Box::new(MapStorage::new(&columns)), Box::new(CompactStorage::new(&columns))];
+        for mut storage in storages {
+            for id in 0..5 {
+                storage.insert(id, values(id, "old"), 1).unwrap();
+            }
+            assert!(storage.insert(5, HashMap::from([("id".to_string(), Value::Int(5))]), 1).is_err());
+            assert!(storage.delete(&2, 2).unwrap());
+            assert!(!storage.delete(&2, 3).unwrap());
+            storage.insert(2, values(2, "new"), 3).unwrap();
+
+            let name = |record: Result<Option<Record>, MyDatabaseError>| record.unwrap().and_then(|record| record.get_value_for_column("name").map(Cow::into_owned));
+            assert_eq!(name(storage.get(&2, 1)), Some(Value::String("old".to_string())));
+            assert_eq!(name(storage.get(&2, 2)), None);
+            assert_eq!(name(storage.get(&2, LATEST)), Some(Value::String("new".to_string())));
+            let keys: Vec<i64> = storage.range(Bound::Excluded(&1), Bound::Included(&3), 2).map(|row| row.unwrap().0).collect();
+            assert_eq!(keys, [2, 3]);
+            assert_eq!(storage.scan(2).filter(|row| row.as_ref().unwrap().1.is_some()).count(), 4);
+
+            assert!(storage.collect_garbage(&2, Some(1)).unwrap());
+            assert_eq!(storage.version_count(&2).unwrap(), 2);
+            assert!(!storage.collect_garbage(&2, None).unwrap());
+            assert_eq!(storage.version_count(&2).unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn rollback_undoes_changes_since_begin() {
+        let columns = ["id".to_string(), "name".to_string()];
+        let typed = [("id".to_string(), ValueType::Int), ("name".to_string(), ValueType::String)];
+        let storages: [Box<dyn Storage<i64>>; 3] = [Box::new(MapStorage::new(&columns)), Box::new(CompactStorage::new(&columns)), Box::new(ColumnarStorage::new(&typed))];
+        for mut storage in storages {
+            storage.insert(1, values(1, "kept"), 1).unwrap();
+            storage.begin().unwrap();
+            assert!(storage.delete(&1, 2).unwrap());
+            storage.insert(1, values(1, "dropped"), 2).unwrap();
+            storage.insert(2, values(2, "dropped"), 2).unwrap();
+            storage.rollback();
+            assert_eq!(storage.version_count(&1).unwrap(), 1);
+            assert_eq!(storage.version_count(&2).unwrap(), 0);
+            assert!(storage.get(&1, LATEST).unwrap().is_some());
+
+            storage.begin().unwrap();
+            storage.insert(3, values(3, "new"), 3).unwrap();
+            storage.commit(3).unwrap();
+            storage.rollback();
+            assert_eq!(storage.scan(LATEST).count(), 2);
+        }
+    }
+}
@@ -0,0 +1,17 @@
+use std::sync::RwLock;
+use crate::db_errors::MyDatabaseError;
+use crate::models::auth::{execute_with_auth, UserRegistry};
+use crate::models::commands::{AnyCommand, CommandOutput};
+use crate::models::db_structure::AnyDatabase;
+use crate::models::result_set::ResultSet;
+use crate::models::session::Session;
+
+/// Executes a command in a fresh session, without authentication
+pub fn run(db: &AnyDatabase, input: &str) -> Result<Option<ResultSet>, MyDatabaseError> {
+    AnyCommand::create_and_execute(input, db, &mut Session::default()).map(CommandOutput::into_rows)
+}
+
+/// Executes a command as the user logged into the session
+pub fn run_as(db: &AnyDatabase, users: &RwLock<UserRegistry>, session: &mut Session, input: &str) -> Result<Option<ResultSet>, MyDatabaseError> {
+    execute_with_auth(input, db, users, session).map(CommandOutput::into_rows)
+}
@@ -24,9 +24,7 @@ pub fn split_preserving_quote_insides(input: &str, splitter: char) -> Vec<&str>
 
 pub fn split_once_skipping_outside_quotes(input: &str, splitter: char) -> Option<(&str, &str)> {
     
-    let Some((mut left, mut right)) = input.split_once(splitter) else {
-        return None;
-    };
+    let (mut left, mut right) = input.split_once(splitter)?;
 
     left = left.trim();
     right = right.trim();
@@ -41,6 +39,22 @@ pub fn split_once_skipping_outside_quotes(input: &str, splitter: char) -> Option
     Some((left, right))
 }
 
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// Word operators like AND match only as whole words, so columns like BRAND stay intact
+fn starts_with_operator(input: &str, byte_pos: usize, op: &str) -> bool {
+    if !input[byte_pos..].starts_with(op) {
+        return false;
+    }
+    let before = input[..byte_pos].chars().next_back();
+    let after = input[byte_pos + op.len()..].chars().next();
+    let starts_word = op.starts_with(is_word_char) && before.is_some_and(is_word_char);
+    let ends_word = op.ends_with(is_word_char) && after.is_some_and(is_word_char);
+    !starts_word && !ends_word
+}
+
 /// Operators have to be sorted by length descending!!!
 pub fn split_by_operators_preserving_quotes<'a>(input: &'a str, operators: &[&str]) -> Vec<&'a str> {
     let mut result = Vec::new();
@@ -57,33 +71,23 @@ pub fn split_by_operators_preserving_quotes<'a>(input: &'a str, operators: &[&st
             continue;
         }
 
-        if !in_quotes {
-            if let Some(op) = {
-                let mut best: Option<&str> = None;
-                for &o in operators {
-                    if input[byte_pos..].starts_with(o) {
-                        best = Some(o);
-                        break;
-                    }
-                }
-                best
-            } {
-                if start_byte < byte_pos {
-                    let before = input[start_byte..byte_pos].trim();
-                    if !before.is_empty() {
-                        result.push(before);
-                    }
+        if !in_quotes
+            && let Some(op) = operators.iter().find(|o| starts_with_operator(input, byte_pos, o)) {
+            if start_byte < byte_pos {
+                let before = input[start_byte..byte_pos].trim();
+                if !before.is_empty() {
+                    result.push(before);
                 }
+            }
 
-                let next_byte = byte_pos + op.len();
-                result.push(&input[byte_pos..next_byte]);
-                
-                while idx < chars.len() && chars[idx].0 < next_byte {
-                    idx += 1;
-                }
-                start_byte = next_byte;
-                continue;
+            let next_byte = byte_pos + op.len();
+            result.push(&input[byte_pos..next_byte]);
+            
+            while idx < chars.len() && chars[idx].0 < next_byte {
+                idx += 1;
             }
+            start_byte = next_byte;
+            continue;
         }
 
         idx += 1;
@@ -96,4 +100,108 @@ pub fn split_by_operators_preserving_quotes<'a>(input: &'a str, operators: &[&st
         }
     }
     result
-}
\ No newline at end of file
+}
+/// Splits off a leading 'quoted' or "quoted" part, returns its inside and the rest of input
+pub fn split_quoted_prefix(input: &str) -> Option<(&str, &str)> {
+    let input = input.trim_start();
+    let quote = input.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let end = input[1..].find(quote)? + 1;
+    Some((&input[1..end], input[end + 1..].trim()))
+}
+/// Like split_quoted_prefix, but a backslash escapes the next character, so the inside can hold quotes
+pub fn split_escaped_quoted_prefix(input: &str) -> Option<(String, &str)> {
+    let input = input.trim_start();
+    let quote = input.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let mut inside = String::new();
+    let mut chars = input.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => inside.push(chars.next()?.1),
+            _ if c == quote => return Some((inside, input[i + 1..].trim())),
+            _ => inside.push(c),
+        }
+    }
+    None
+}
+/// Puts the text in double quotes, escaped so split_escaped_quoted_prefix gives it back
+pub fn quote_escaped(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Like split_preserving_quote_insides, but bracketed parts like function arguments are kept together
+pub fn split_outside_brackets(input: &str, splitter: char) -> Vec<&str> {
+    let mut result = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut depth = 0usize;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => depth = depth.saturating_sub(1),
+            _ if c == splitter && !in_quotes && depth == 0 => {
+                let output = input[start..i].trim();
+                if !output.is_empty() {
+                    result.push(output);
+                }
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+
+    let output = input[start..].trim();
+    if !output.is_empty() {
+        result.push(output);
+    }
+    result
+}
+
+/// Splits at the last splitter outside of quotes and brackets
+pub fn rsplit_once_outside_brackets<'a>(input: &'a str, splitter: &str) -> Option<(&'a str, &'a str)> {
+    let mut found = None;
+    let mut in_quotes = false;
+    let mut depth = 0usize;
+
+    for (i, c) in input.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => depth = depth.saturating_sub(1),
+            _ if !in_quotes && depth == 0 && input[i..].starts_with(splitter) => found = Some(i),
+            _ => {},
+        }
+    }
+    found.map(|i| (input[..i].trim(), input[i + splitter.len()..].trim()))
+}
+
+/// Splits at keywords outside of quotes and brackets, every part comes with the keyword in front of it
+pub fn split_at_keywords_outside_brackets<'a>(input: &'a str, keywords: &[&'static str]) -> Vec<(Option<&'static str>, &'a str)> {
+    let mut result = Vec::new();
+    let mut keyword = None;
+    let mut start = 0;
+    let mut in_quotes = false;
+    let mut depth = 0usize;
+
+    for (i, c) in input.char_indices() {
+        if i < start {
+            continue; // inside a keyword that was just found
+        }
+        match c {
+            '"' => in_quotes = !in_quotes,
+            '(' if !in_quotes => depth += 1,
+            ')' if !in_quotes => depth = depth.saturating_sub(1),
+            _ if !in_quotes && depth == 0 => {
+                if let Some(found) = keywords.iter().find(|k| starts_with_operator(input, i, k)) {
+                    result.push((keyword, input[start..i].trim()));
+                    keyword = Some(*found);
+                    start = i + found.len();
+                }
+            },
+            _ => {},
+        }
+    }
+    result.push((keyword, input[start..].trim()));
+    result
+}
@@ -1,6 +1,12 @@
-use crate::{db_errors::MyDatabaseError, models::utilities::split_by_operators_preserving_quotes};
+use crate::{db_errors::MyDatabaseError, models::utilities::{rsplit_once_outside_brackets, split_by_operators_preserving_quotes}};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use crate::models::db_structure::{ValueType, Record, Value};
+use std::fmt;
+use std::ops::Bound;
+use crate::models::db_structure::{ValueType, Record, Snapshot, Value, ValueRef};
+use std::cmp::Ordering;
+use crate::models::functions::{self, ScalarFunction};
+use crate::models::window::WindowColumn;
 
 #[derive(PartialEq, Debug)]
 enum ComparisonOperator {
@@ -12,6 +18,17 @@ enum ComparisonOperator {
     LessThanOrEqual
 }
 impl ComparisonOperator {
+    /// Operator that gives the same result with swapped operands
+    fn flipped(&self) -> ComparisonOperator {
+        match self {
+            ComparisonOperator::Equal => ComparisonOperator::Equal,
+            ComparisonOperator::NotEqual => ComparisonOperator::NotEqual,
+            ComparisonOperator::GreaterThan => ComparisonOperator::LessThan,
+            ComparisonOperator::LessThan => ComparisonOperator::GreaterThan,
+            ComparisonOperator::GreaterThanOrEqual => ComparisonOperator::LessThanOrEqual,
+            ComparisonOperator::LessThanOrEqual => ComparisonOperator::GreaterThanOrEqual,
+        }
+    }
     fn evaluate(&self, v1: &Value, v2: &Value) -> Result<Value, MyDatabaseError> {
         let result = match self {
             ComparisonOperator::Equal => v1.is_equal_to(v2),
@@ -27,6 +44,17 @@ impl ComparisonOperator {
         };
         Ok(Value::Bool(result))
     }
+    /// Whether the operator holds for operands in this order
+    fn holds(&self, ordering: Ordering) -> bool {
+        match self {
+            ComparisonOperator::Equal => ordering == Ordering::Equal,
+            ComparisonOperator::NotEqual => ordering != Ordering::Equal,
+            ComparisonOperator::GreaterThan => ordering == Ordering::Greater,
+            ComparisonOperator::LessThan => ordering == Ordering::Less,
+            ComparisonOperator::GreaterThanOrEqual => ordering != Ordering::Less,
+            ComparisonOperator::LessThanOrEqual => ordering != Ordering::Greater,
+        }
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -55,71 +83,72 @@ enum MathOperator {
     Subtract,
     Multiply,
     Divide,
+    Modulo,
+    Power,
 }
 impl MathOperator {
+    /// Int with Int stays Int, anything with a Float is computed on floats
     fn evaluate(&self, v1: &Value, v2: &Value) -> Result<Value, MyDatabaseError> {
         match (v1, v2) {
-            (Value::Float(f1), Value::Float(f2)) => {
-                let result = match self {
-                    MathOperator::Add => f1 + f2,
-                    MathOperator::Subtract => f1 - f2,
-                    MathOperator::Multiply => f1 * f2,
-                    MathOperator::Divide => {
-                        if *f2 == 0.0 {
-                            return Err(MyDatabaseError::DivisionByZero);
-                        }
-                        f1 / f2
-                    },
-                };
-                Ok(Value::Float(result))
-            },
-            (Value::Int(i1), Value::Int(i2)) => {
-                let result = match self {
-                    MathOperator::Add => i1 + i2,
-                    MathOperator::Subtract => i1 - i2,
-                    MathOperator::Multiply => i1 * i2,
-                    MathOperator::Divide => {
-                        if *i2 == 0 {
-                            return Err(MyDatabaseError::DivisionByZero);
-                        }
-                        i1 / i2
-                    },
-                };
-                Ok(Value::Int(result))
-            },
-            (Value::Int(i1), Value::Float(f2)) => {
-                let f1 = *i1 as f64;
-                let result = match self {
-                    MathOperator::Add => f1 + f2,
-                    MathOperator::Subtract => f1 - f2,
-                    MathOperator::Multiply => f1 * f2,
-                    MathOperator::Divide => {
-                        if *f2 == 0.0 {
-                            return Err(MyDatabaseError::DivisionByZero);
-                        }
-                        f1 / f2
-                    },
-                };
-                Ok(Value::Float(result))
-            },
-            (Value::Float(f1), Value::Int(i2)) => {
-                let f2 = *i2 as f64;
-                let result = match self {
-                    MathOperator::Add => f1 + f2,
-                    MathOperator::Subtract => f1 - f2,
-                    MathOperator::Multiply => f1 * f2,
-                    MathOperator::Divide => {
-                        if f2 == 0.0 {
-                            return Err(MyDatabaseError::DivisionByZero);
-                        }
-                        f1 / f2
-                    },
-                };
-                Ok(Value::Float(result))
-            }
+            (Value::Int(i1), Value::Int(i2)) => self.evaluate_int(*i1, *i2).map(Value::Int),
+            (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => self.evaluate_float(as_float(v1), as_float(v2)).map(Value::Float),
             _ => Err(MyDatabaseError::InvalidMathOperation),
         }
     }
+    fn evaluate_int(&self, i1: i64, i2: i64) -> Result<i64, MyDatabaseError> {
+        let result = match self {
+            MathOperator::Add => i1.checked_add(i2),
+            MathOperator::Subtract => i1.checked_sub(i2),
+            MathOperator::Multiply => i1.checked_mul(i2),
+            MathOperator::Divide | MathOperator::Modulo if i2 == 0 => return Err(MyDatabaseError::DivisionByZero),
+            MathOperator::Divide => i1.checked_div(i2),
+            MathOperator::Modulo => i1.checked_rem(i2),
+            MathOperator::Power => {
+                if i2 < 0 {
+                    return Err(MyDatabaseError::NegativeExponent(i2));
+                }
+                // exponents past u32 only fit for these bases
+                match (i1, u32::try_from(i2)) {
+                    (_, Ok(exponent)) => i1.checked_pow(exponent),
+                    (0 | 1, Err(_)) => Some(i1),
+                    (-1, Err(_)) => Some(if i2 % 2 == 0 { 1 } else { -1 }),
+                    (_, Err(_)) => None,
+                }
+            },
+        };
+        result.ok_or_else(|| MyDatabaseError::IntegerOverflow(format!("{} {} {}", i1, self.symbol(), i2)))
+    }
+    fn evaluate_float(&self, f1: f64, f2: f64) -> Result<f64, MyDatabaseError> {
+        let result = match self {
+            MathOperator::Add => f1 + f2,
+            MathOperator::Subtract => f1 - f2,
+            MathOperator::Multiply => f1 * f2,
+            MathOperator::Divide | MathOperator::Modulo if f2 == 0.0 => return Err(MyDatabaseError::DivisionByZero),
+            MathOperator::Divide => f1 / f2,
+            MathOperator::Modulo => f1 % f2,
+            MathOperator::Power => f1.powf(f2),
+        };
+        Ok(result)
+    }
+    fn symbol(&self) -> &'static str {
+        match self {
+            MathOperator::Add => "+",
+            MathOperator::Subtract => "-",
+            MathOperator::Multiply => "*",
+            MathOperator::Divide => "/",
+            MathOperator::Modulo => "%",
+            MathOperator::Power => "^",
+        }
+    }
+}
+
+/// Numeric value as a Float, the only place where Int gets promoted
+fn as_float(value: &Value) -> f64 {
+    match value {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        _ => f64::NAN,
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -129,6 +158,38 @@ enum AnyOperator {
     Math(MathOperator),
 }
 impl AnyOperator {
+    /// Type of the result for operands of given types, Err with the reason when they don't fit the operator
+    fn result_type(&self, left: ValueType, right: ValueType) -> Result<ValueType, String> {
+        let is_numeric = |t: ValueType| matches!(t, ValueType::Int | ValueType::Float);
+        match self {
+            AnyOperator::Comparison(op) => {
+                let comparable = match op {
+                    ComparisonOperator::Equal | ComparisonOperator::NotEqual => left == right || (is_numeric(left) && is_numeric(right)),
+                    _ => (is_numeric(left) && is_numeric(right)) || (left == ValueType::String && right == ValueType::String),
+                };
+                if !comparable {
+                    return Err(format!("cannot compare {} with {} using {}", left, right, self));
+                }
+                Ok(ValueType::Bool)
+            },
+            AnyOperator::Logical(_) => {
+                if left != ValueType::Bool || right != ValueType::Bool {
+                    return Err(format!("{} expects Bool operands, found {} and {}", self, left, right));
+                }
+                Ok(ValueType::Bool)
+            },
+            AnyOperator::Math(_) => {
+                if !is_numeric(left) || !is_numeric(right) {
+                    return Err(format!("{} expects numeric operands, found {} and {}", self, left, right));
+                }
+                if left == ValueType::Int && right == ValueType::Int {
+                    Ok(ValueType::Int)
+                } else {
+                    Ok(ValueType::Float)
+                }
+            },
+        }
+    }
     fn evaluate(&self, v1: &Value, v2: &Value) -> Result<Value, MyDatabaseError> {
         match self {
             AnyOperator::Comparison(op) => op.evaluate(v1, v2),
@@ -138,193 +199,1328 @@ impl AnyOperator {
     }
 }
 
+impl fmt::Display for AnyOperator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let symbol = match self {
+            AnyOperator::Comparison(ComparisonOperator::Equal) => "=",
+            AnyOperator::Comparison(ComparisonOperator::NotEqual) => "!=",
+            AnyOperator::Comparison(ComparisonOperator::GreaterThan) => ">",
+            AnyOperator::Comparison(ComparisonOperator::LessThan) => "<",
+            AnyOperator::Comparison(ComparisonOperator::GreaterThanOrEqual) => ">=",
+            AnyOperator::Comparison(ComparisonOperator::LessThanOrEqual) => "<=",
+            AnyOperator::Logical(LogicalOperator::And) => "AND",
+            AnyOperator::Logical(LogicalOperator::Or) => "OR",
+            AnyOperator::Math(op) => op.symbol(),
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
+/// Integer literals are Int, a decimal point or an exponent makes a Float. None when the token isn't a number
+fn parse_number(token: &str) -> Result<Option<Value>, MyDatabaseError> {
+    if !token.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return Ok(None);
+    }
+    if token.contains(['.', 'e', 'E']) {
+        return Ok(token.parse::<f64>().ok().map(Value::Float));
+    }
+    if !token.bytes().all(|b| b.is_ascii_digit()) {
+        return Ok(None);
+    }
+    match token.parse::<i64>() {
+        Ok(num) => Ok(Some(Value::Int(num))),
+        Err(_) => Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Integer literal out of range: {}", token))),
+    }
+}
+
+/// Constant as it would be written in a clause, strings are quoted
+fn literal(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("\"{}\"", s),
+        _ => value.to_string(),
+    }
+}
+
 #[derive(PartialEq, Debug)]
 enum ClauseElement {
     OpeningBracket,
     ClosingBracket,
+    Comma,
     Operator(AnyOperator),
+    Function(&'static ScalarFunction, usize), // with the number of arguments
+    Cast(ValueType),
+    Case(CaseExpression),
+    Subquery(Subquery),
+    InValues(Vec<Value>), // IN after its subquery was run, sorted
     ColumnIdentifier(String),
     Constant(Value)
 }
 impl ClauseElement {
     fn get_importance(&self) -> i8 {
         match self {
-            ClauseElement::OpeningBracket | ClauseElement::ClosingBracket => -1, // doesn't matter, it's a special case anyway
+            ClauseElement::OpeningBracket | ClauseElement::ClosingBracket | ClauseElement::Comma => -1, // doesn't matter, it's a special case anyway
+            ClauseElement::Function(_, _) | ClauseElement::Cast(_) => -1, // always right below their bracket
             ClauseElement::Operator(AnyOperator::Logical(op)) => match op {
                 LogicalOperator::And => 1,
                 LogicalOperator::Or => 0,
             },
             ClauseElement::Operator(AnyOperator::Comparison(_)) => 2,
+            ClauseElement::Subquery(Subquery { kind: SubqueryKind::In, .. }) | ClauseElement::InValues(_) => 2,
             ClauseElement::Operator(AnyOperator::Math(op)) => match op {
                 MathOperator::Add | MathOperator::Subtract => 3,
-                MathOperator::Multiply | MathOperator::Divide => 4,
+                MathOperator::Multiply | MathOperator::Divide | MathOperator::Modulo => 4,
+                MathOperator::Power => 5,
             },
-            ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => 10, // again, doesn't matter
+            ClauseElement::Case(_) | ClauseElement::Subquery(_) | ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => 10, // again, doesn't matter
+        }
+    }
+    /// Number of operands taken from the stack, 0 for columns, constants and CASE
+    fn arity(&self) -> usize {
+        match self {
+            ClauseElement::Operator(_) => 2,
+            ClauseElement::Function(_, count) => *count,
+            ClauseElement::Cast(_) => 1,
+            ClauseElement::Subquery(Subquery { kind: SubqueryKind::In, .. }) | ClauseElement::InValues(_) => 1,
+            _ => 0,
+        }
+    }
+    /// Text of an operation applied to operands written as given
+    fn describe_call(&self, operands: &[String]) -> String {
+        match (self, operands) {
+            (ClauseElement::Operator(op), [left, right]) => format!("({} {} {})", left, op, right),
+            (ClauseElement::Function(function, _), _) => format!("{}({})", function.name, operands.join(", ")),
+            (ClauseElement::Cast(to), [value]) => format!("CAST({} AS {})", value, to),
+            (ClauseElement::Subquery(_) | ClauseElement::InValues(_), [value]) => format!("({} {})", value, self),
+            _ => self.to_string(),
+        }
+    }
+    /// Type of the operation result, Err with the reason when operand types don't fit
+    fn result_type(&self, operands: &[ValueType]) -> Result<ValueType, String> {
+        match (self, operands) {
+            (ClauseElement::Operator(op), [left, right]) => op.result_type(*left, *right),
+            (ClauseElement::Function(function, _), _) => function.return_type(operands),
+            (ClauseElement::Cast(to), [from]) if functions::can_cast(*from, *to) => Ok(*to),
+            (ClauseElement::Cast(to), [from]) => Err(format!("cannot convert {} to {}", from, to)),
+            (ClauseElement::Subquery(subquery), [left]) => {
+                let right = subquery.column_type()?;
+                let is_numeric = |t: ValueType| matches!(t, ValueType::Int | ValueType::Float);
+                if *left != right && !(is_numeric(*left) && is_numeric(right)) {
+                    return Err(format!("cannot compare {} with {} using IN", left, right));
+                }
+                Ok(ValueType::Bool)
+            },
+            (ClauseElement::InValues(_), [_]) => Ok(ValueType::Bool),
+            _ => Err(format!("{} can't be applied to {} operands", self, operands.len())),
+        }
+    }
+    fn apply(&self, operands: Vec<Cow<'_, Value>>) -> Result<Value, MyDatabaseError> {
+        match self {
+            ClauseElement::Operator(op) => match operands.as_slice() {
+                [left, right] => op.evaluate(left, right),
+                _ => Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string())),
+            },
+            ClauseElement::Function(function, _) => {
+                let args: Vec<Value> = operands.into_iter().map(Cow::into_owned).collect();
+                function.evaluate(&args)
+            },
+            ClauseElement::Cast(to) => match operands.as_slice() {
+                [value] => functions::cast(value, *to),
+                _ => Err(MyDatabaseError::WronglyParsedClause("CAST takes one value".to_string())),
+            },
+            ClauseElement::InValues(values) => match operands.as_slice() {
+                [value] => Ok(Value::Bool(values.binary_search_by(|probe| probe.compare(value).unwrap_or(Ordering::Equal)).is_ok())),
+                _ => Err(MyDatabaseError::WronglyParsedClause("IN takes one value".to_string())),
+            },
+            ClauseElement::Subquery(_) => Err(MyDatabaseError::WronglyParsedClause("Subquery has to be run before the clause is evaluated".to_string())),
+            _ => Err(MyDatabaseError::WronglyParsedClause("Expected an operation".to_string())),
         }
     }
 }
 
-#[derive(Debug)]
-enum BoxedOrReferencedElement<'a> {
-    Boxed(Box<ClauseElement>),
-    Referenced(&'a ClauseElement),
-}
-impl<'a> BoxedOrReferencedElement<'a> {
-    fn get_element_ref(&self) -> &ClauseElement {
+impl fmt::Display for ClauseElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            BoxedOrReferencedElement::Boxed(b) => b.as_ref(),
-            BoxedOrReferencedElement::Referenced(r) => r,
+            ClauseElement::OpeningBracket => write!(f, "("),
+            ClauseElement::ClosingBracket => write!(f, ")"),
+            ClauseElement::Comma => write!(f, ","),
+            ClauseElement::Operator(op) => write!(f, "{}", op),
+            ClauseElement::Function(function, _) => write!(f, "{}", function.name),
+            ClauseElement::Cast(to) => write!(f, "CAST AS {}", to),
+            ClauseElement::Case(_) => write!(f, "CASE"),
+            ClauseElement::Subquery(subquery) => match subquery.kind {
+                SubqueryKind::Scalar => write!(f, "({})", subquery.text),
+                SubqueryKind::Exists => write!(f, "EXISTS ({})", subquery.text),
+                SubqueryKind::In => write!(f, "IN ({})", subquery.text),
+            },
+            ClauseElement::InValues(values) => {
+                let values: Vec<String> = values.iter().map(literal).collect();
+                write!(f, "IN ({})", values.join(", "))
+            },
+            ClauseElement::ColumnIdentifier(name) => write!(f, "{}", name),
+            ClauseElement::Constant(value) => write!(f, "{}", literal(value)),
+        }
+    }
+}
+
+/// CASE WHEN ... THEN ... ELSE ... END, every part is a separate ONP program, so only the chosen branch is evaluated
+#[derive(PartialEq, Debug)]
+struct CaseExpression {
+    branches: Vec<(Vec<ClauseElement>, Vec<ClauseElement>)>, // conditions with their values
+    otherwise: Vec<ClauseElement>,
+}
+impl CaseExpression {
+    /// Reads tokens up to the matching END, the CASE token itself is already consumed
+    fn parse<'t>(tokens: &mut impl Iterator<Item = &'t str>, columns: &HashMap<String, ValueType>, database: Option<&dyn SubqueryParser>) -> Result<CaseExpression, MyDatabaseError> {
+        let mut parts: Vec<(&str, Vec<&str>)> = Vec::new();
+        let mut nesting = 0;
+        loop {
+            let Some(token) = tokens.next() else {
+                return Err(MyDatabaseError::InvalidWhereClauseFormat("CASE is missing END".to_string()));
+            };
+            match token {
+                "END" if nesting == 0 => break,
+                "WHEN" | "THEN" | "ELSE" if nesting == 0 => {
+                    parts.push((token, Vec::new()));
+                    continue;
+                },
+                "CASE" => nesting += 1,
+                "END" => nesting -= 1,
+                _ => {},
+            }
+            let Some((_, part)) = parts.last_mut() else {
+                return Err(MyDatabaseError::InvalidWhereClauseFormat("CASE has to start with WHEN".to_string()));
+            };
+            part.push(token);
+        }
+
+        let mut branches = Vec::new();
+        let mut parts = parts.into_iter();
+        loop {
+            match (parts.next(), parts.next()) {
+                (Some(("WHEN", condition)), Some(("THEN", value))) => branches.push((parse_onp(&condition, columns, database)?, parse_onp(&value, columns, database)?)),
+                (Some(("ELSE", value)), None) if !branches.is_empty() => {
+                    return Ok(CaseExpression {
+                        branches,
+                        otherwise: parse_onp(&value, columns, database)?,
+                    });
+                },
+                _ => return Err(MyDatabaseError::InvalidWhereClauseFormat("CASE expects WHEN <condition> THEN <value> ... ELSE <value> END".to_string())),
+            }
+        }
+    }
+    fn evaluate(&self, record: &Record) -> Result<Value, MyDatabaseError> {
+        for (condition, value) in &self.branches {
+            if evaluate_onp(condition, record)? == Value::Bool(true) {
+                return evaluate_onp(value, record);
+            }
         }
+        evaluate_onp(&self.otherwise, record)
     }
 }
 
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum SubqueryKind {
+    Scalar, // (SELECT ...) returning a single value
+    Exists,
+    In,
+}
+
+/// Parses the SELECT of a subquery, implemented where the commands live so clauses don't depend on them
+pub trait SubqueryParser {
+    fn parse_select(&self, query: &str) -> Result<Box<dyn SubquerySelect>, MyDatabaseError>;
+}
+
+/// Parsed SELECT of a subquery
+pub trait SubquerySelect: fmt::Debug {
+    fn column_types(&self) -> Vec<ValueType>;
+    fn tables_read(&self) -> Vec<String>;
+    /// Values of the first column of the result, read at the snapshot of the statement
+    fn run(&mut self, snapshot: &Snapshot) -> Result<Vec<Value>, MyDatabaseError>;
+}
+
+/// Uncorrelated SELECT inside a clause. It's run once before the statement reads any record and replaced with its result
 #[derive(Debug)]
-pub struct WhereClause {
-    onp_elements: Vec<ClauseElement>
+struct Subquery {
+    kind: SubqueryKind,
+    text: String,
+    select: Box<dyn SubquerySelect>,
 }
-impl WhereClause {
-    pub fn create_from_string(clause: String, columns: &HashMap<String, ValueType>) -> Result<WhereClause, MyDatabaseError> {
-        let operators = ["!=", ">=", "<=", ">", "<", "=", "AND", "OR", "+", "-", "*", "/", "(", ")"];
-        
-        let mut elements: Vec<ClauseElement> = Vec::new();
-        for token in split_by_operators_preserving_quotes(&clause, &operators) {
+impl Subquery {
+    /// Reads tokens up to the closing bracket, the opening one is already consumed
+    fn parse<'t>(kind: SubqueryKind, tokens: &mut impl Iterator<Item = &'t str>, database: Option<&dyn SubqueryParser>) -> Result<Subquery, MyDatabaseError> {
+        let mut text = String::new();
+        let mut depth = 0;
+        loop {
+            let Some(token) = tokens.next() else {
+                return Err(MyDatabaseError::InvalidWhereClauseFormat("Closing bracket missing".to_string()));
+            };
             match token {
-                "(" => elements.push(ClauseElement::OpeningBracket),
-                ")" => elements.push(ClauseElement::ClosingBracket),
-                "=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::Equal))),
-                "!=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::NotEqual))),
-                ">" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::GreaterThan))),
-                "<" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::LessThan))),
-                ">=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::GreaterThanOrEqual))),
-                "<=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::LessThanOrEqual))),
-                "AND" => elements.push(ClauseElement::Operator(AnyOperator::Logical(LogicalOperator::And))),
-                "OR" => elements.push(ClauseElement::Operator(AnyOperator::Logical(LogicalOperator::Or))),
-                "+" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Add))),
-                "-" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Subtract))),
-                "*" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Multiply))),
-                "/" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Divide))),
-                _ => {
-                    if columns.contains_key(token) {
-                        elements.push(ClauseElement::ColumnIdentifier(token.to_string()));
-                    } else if let Ok(constant) = token.parse::<f64>() {
-                        elements.push(ClauseElement::Constant(Value::Float(constant)));
-                    } else if token.eq_ignore_ascii_case("true") {
-                        elements.push(ClauseElement::Constant(Value::Bool(true)));
-                    } else if token.eq_ignore_ascii_case("false") {
-                        elements.push(ClauseElement::Constant(Value::Bool(false)));
-                    } else if token.starts_with("\"") && token.ends_with("\"") && token.len() >= 2 {
-                        let str_content = &token[1..token.len()-1];
-                        elements.push(ClauseElement::Constant(Value::String(str_content.to_string())));
-                    } else {
-                        return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Unknown token in WHERE clause: {}", token)));
+                ")" if depth == 0 => break,
+                "(" => depth += 1,
+                ")" => depth -= 1,
+                _ => {},
+            }
+            // the tokens are joined back into the query, spaces only matter to keep words apart
+            if !text.is_empty() && !text.ends_with('(') && token != "," && token != ")" {
+                text.push(' ');
+            }
+            text.push_str(token.trim());
+        }
+        let Some(("SELECT", query)) = text.split_once(' ') else {
+            return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Expected SELECT in brackets, found ({})", text)));
+        };
+        let Some(database) = database else {
+            return Err(MyDatabaseError::SubqueryNotAllowed);
+        };
+        let select = database.parse_select(query)?;
+        Ok(Subquery {
+            kind,
+            text,
+            select,
+        })
+    }
+    /// Type of the single column the subquery returns
+    fn column_type(&self) -> Result<ValueType, String> {
+        match self.select.column_types().as_slice() {
+            [value_type] => Ok(*value_type),
+            types => Err(format!("subquery has to return one column, returns {}", types.len())),
+        }
+    }
+    fn run(&mut self, snapshot: &Snapshot) -> Result<ClauseElement, MyDatabaseError> {
+        let mut values = self.select.run(snapshot)?;
+        match self.kind {
+            SubqueryKind::Exists => Ok(ClauseElement::Constant(Value::Bool(!values.is_empty()))),
+            SubqueryKind::Scalar => match values.len() {
+                1 => Ok(ClauseElement::Constant(values.remove(0))),
+                count => Err(MyDatabaseError::ScalarSubqueryRows(self.text.clone(), count)),
+            },
+            SubqueryKind::In => {
+                values.sort_by(|a, b| a.compare(b).unwrap_or(Ordering::Equal));
+                values.dedup_by(|a, b| a.is_equal_to(b));
+                Ok(ClauseElement::InValues(values))
+            },
+        }
+    }
+}
+impl PartialEq for Subquery {
+    fn eq(&self, other: &Self) -> bool {
+        self.kind == other.kind && self.text == other.text
+    }
+}
+
+/// Runs subqueries of the elements, also those inside CASE, and replaces them with their results
+fn run_subqueries(elements: &mut [ClauseElement], snapshot: &Snapshot) -> Result<(), MyDatabaseError> {
+    for element in elements {
+        match element {
+            ClauseElement::Subquery(subquery) => *element = subquery.run(snapshot)?,
+            ClauseElement::Case(case) => {
+                for (condition, value) in &mut case.branches {
+                    run_subqueries(condition, snapshot)?;
+                    run_subqueries(value, snapshot)?;
+                }
+                run_subqueries(&mut case.otherwise, snapshot)?;
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Tables read by subqueries of the elements, including nested ones
+fn subquery_tables(elements: &[ClauseElement], tables: &mut Vec<String>) {
+    for element in elements {
+        match element {
+            ClauseElement::Subquery(subquery) => tables.extend(subquery.select.tables_read()),
+            ClauseElement::Case(case) => {
+                for (condition, value) in &case.branches {
+                    subquery_tables(condition, tables);
+                    subquery_tables(value, tables);
+                }
+                subquery_tables(&case.otherwise, tables);
+            },
+            _ => {},
+        }
+    }
+}
+
+/// Clause as a tree, rebuilt from the ONP form
+#[derive(Debug)]
+enum ExpressionNode<'a> {
+    Leaf(&'a ClauseElement),
+    Operation(&'a AnyOperator, Box<ExpressionNode<'a>>, Box<ExpressionNode<'a>>),
+    Call(&'a ClauseElement, Vec<ExpressionNode<'a>>), // functions and casts
+    Case(Vec<(&'static str, ExpressionNode<'a>)>), // WHEN, THEN and ELSE parts
+}
+impl ExpressionNode<'_> {
+    /// Fully bracketed infix form
+    fn infix(&self) -> String {
+        match self {
+            ExpressionNode::Leaf(element) => element.to_string(),
+            ExpressionNode::Operation(op, left, right) => format!("({} {} {})", left.infix(), op, right.infix()),
+            ExpressionNode::Call(element, args) => element.describe_call(&args.iter().map(ExpressionNode::infix).collect::<Vec<String>>()),
+            ExpressionNode::Case(parts) => {
+                let parts: Vec<String> = parts.iter().map(|(keyword, node)| format!("{} {}", keyword, node.infix())).collect();
+                format!("CASE {} END", parts.join(" "))
+            },
+        }
+    }
+    fn write_tree(&self, prefix: &str, is_last: bool, out: &mut Vec<String>) {
+        let (branch, continuation) = if is_last { ("└─ ", "   ") } else { ("├─ ", "│  ") };
+        let child_prefix = format!("{}{}", prefix, continuation);
+        match self {
+            ExpressionNode::Leaf(element) => out.push(format!("{}{}{}", prefix, branch, element)),
+            ExpressionNode::Operation(op, left, right) => {
+                out.push(format!("{}{}{}", prefix, branch, op));
+                left.write_tree(&child_prefix, false, out);
+                right.write_tree(&child_prefix, true, out);
+            },
+            ExpressionNode::Call(element, args) => {
+                out.push(format!("{}{}{}", prefix, branch, element));
+                for (index, arg) in args.iter().enumerate() {
+                    arg.write_tree(&child_prefix, index + 1 == args.len(), out);
+                }
+            },
+            ExpressionNode::Case(parts) => {
+                out.push(format!("{}{}CASE", prefix, branch));
+                for (index, (keyword, node)) in parts.iter().enumerate() {
+                    let (part_branch, part_continuation) = if index + 1 == parts.len() { ("└─ ", "   ") } else { ("├─ ", "│  ") };
+                    out.push(format!("{}{}{}", child_prefix, part_branch, keyword));
+                    node.write_tree(&format!("{}{}", child_prefix, part_continuation), true, out);
+                }
+            },
+        }
+    }
+    /// Operands of the top level ANDs, all of them have to hold for the clause to hold
+    fn conjuncts<'n>(&'n self, out: &mut Vec<&'n ExpressionNode<'n>>) {
+        match self {
+            ExpressionNode::Operation(AnyOperator::Logical(LogicalOperator::And), left, right) => {
+                left.conjuncts(out);
+                right.conjuncts(out);
+            },
+            _ => out.push(self),
+        }
+    }
+}
+
+/// Range of keys a clause can be true for, bounds are constants from the clause
+#[derive(Debug, PartialEq, Clone)]
+pub struct KeyRange {
+    pub lower: Bound<Value>,
+    pub upper: Bound<Value>,
+}
+impl KeyRange {
+    /// Narrows the range with `key <op> value`, bounds that can't be compared are left as they were
+    fn restrict(&mut self, op: &ComparisonOperator, value: &Value) {
+        let lower_candidate = match op {
+            ComparisonOperator::Equal | ComparisonOperator::GreaterThanOrEqual => Some(Bound::Included(value.clone())),
+            ComparisonOperator::GreaterThan => Some(Bound::Excluded(value.clone())),
+            _ => None,
+        };
+        let upper_candidate = match op {
+            ComparisonOperator::Equal | ComparisonOperator::LessThanOrEqual => Some(Bound::Included(value.clone())),
+            ComparisonOperator::LessThan => Some(Bound::Excluded(value.clone())),
+            _ => None,
+        };
+        if let Some(candidate) = lower_candidate
+            && is_tighter(&candidate, &self.lower, true) {
+            self.lower = candidate;
+        }
+        if let Some(candidate) = upper_candidate
+            && is_tighter(&candidate, &self.upper, false) {
+            self.upper = candidate;
+        }
+    }
+    pub fn is_single_key(&self) -> bool {
+        matches!((&self.lower, &self.upper), (Bound::Included(l), Bound::Included(u)) if l.is_equal_to(u))
+    }
+    pub fn describe(&self, key_name: &str) -> String {
+        if self.is_single_key()
+            && let Bound::Included(value) = &self.lower {
+            return format!("{} = {}", key_name, literal(value));
+        }
+        let mut parts = Vec::new();
+        match &self.lower {
+            Bound::Included(value) => parts.push(format!("{} >= {}", key_name, literal(value))),
+            Bound::Excluded(value) => parts.push(format!("{} > {}", key_name, literal(value))),
+            Bound::Unbounded => {},
+        }
+        match &self.upper {
+            Bound::Included(value) => parts.push(format!("{} <= {}", key_name, literal(value))),
+            Bound::Excluded(value) => parts.push(format!("{} < {}", key_name, literal(value))),
+            Bound::Unbounded => {},
+        }
+        parts.join(" AND ")
+    }
+}
+
+/// Whether the candidate bound leaves out more keys than the current one
+fn is_tighter(candidate: &Bound<Value>, current: &Bound<Value>, is_lower: bool) -> bool {
+    let (Bound::Included(new) | Bound::Excluded(new)) = candidate else {
+        return false;
+    };
+    let (Bound::Included(old) | Bound::Excluded(old)) = current else {
+        return true;
+    };
+    let further = if is_lower { new.is_bigger_than(old) } else { old.is_bigger_than(new) };
+    match further {
+        Ok(true) => true,
+        Ok(false) => new.is_equal_to(old) && matches!(candidate, Bound::Excluded(_)),
+        Err(_) => false,
+    }
+}
+
+/// Part of the clause during type checking - its ONP elements, type, value if it's constant and text for errors
+struct TypedOperand {
+    elements: Vec<ClauseElement>,
+    value_type: ValueType,
+    constant: Option<Value>,
+    text: String,
+}
+impl TypedOperand {
+    fn leaf(element: ClauseElement, columns: &HashMap<String, ValueType>) -> Result<TypedOperand, MyDatabaseError> {
+        let (value_type, constant) = match &element {
+            ClauseElement::ColumnIdentifier(name) => match columns.get(name) {
+                Some(value_type) => (*value_type, None),
+                None => return Err(MyDatabaseError::WronglyParsedClause(format!("Column {} not found in table", name))),
+            },
+            ClauseElement::Constant(value) => (value.get_type(), Some(value.clone())),
+            ClauseElement::Subquery(subquery) if subquery.kind == SubqueryKind::Exists => (ValueType::Bool, None),
+            ClauseElement::Subquery(subquery) => match subquery.column_type() {
+                Ok(value_type) => (value_type, None),
+                Err(reason) => return Err(MyDatabaseError::ClauseTypeError(element.to_string(), reason)),
+            },
+            _ => return Err(MyDatabaseError::WronglyParsedClause("Expected column identifier or constant".to_string())),
+        };
+        Ok(TypedOperand {
+            text: element.to_string(),
+            elements: vec![element],
+            value_type,
+            constant,
+        })
+    }
+    /// Converts an Int operand to Float when the expression around it expects a Float, others are left as they are
+    fn promoted_to(mut self, value_type: ValueType) -> TypedOperand {
+        if self.value_type != ValueType::Int || value_type != ValueType::Float {
+            return self;
+        }
+        match &mut self.constant {
+            Some(constant) => {
+                *constant = Value::Float(as_float(constant));
+                self.elements = vec![ClauseElement::Constant(constant.clone())];
+            },
+            None => self.elements.push(ClauseElement::Cast(ValueType::Float)),
+        }
+        self.value_type = ValueType::Float;
+        self
+    }
+}
+
+/// Infers types of all sub-expressions from the column types and replaces constant sub-expressions with their values.
+/// Errors name the smallest sub-expression that is wrong
+fn check_and_fold(onp_elements: Vec<ClauseElement>, columns: &HashMap<String, ValueType>) -> Result<TypedOperand, MyDatabaseError> {
+    check_and_fold_with(onp_elements, columns, false)
+}
+
+/// Like check_and_fold, but with `lazy` a constant sub-expression that fails is left unfolded,
+/// so the error only comes when it's evaluated. Used for parts of a CASE that may never be taken
+fn check_and_fold_with(onp_elements: Vec<ClauseElement>, columns: &HashMap<String, ValueType>, lazy: bool) -> Result<TypedOperand, MyDatabaseError> {
+    let mut stack: Vec<TypedOperand> = Vec::new();
+    for element in onp_elements {
+        if let ClauseElement::Case(case) = element {
+            stack.push(check_case(case, columns)?);
+            continue;
+        }
+        let arity = element.arity();
+        if arity == 0 && !matches!(element, ClauseElement::Function(_, _)) {
+            stack.push(TypedOperand::leaf(element, columns)?);
+            continue;
+        }
+        if stack.len() < arity {
+            return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("{} is missing an operand", element)));
+        }
+        let operands = stack.split_off(stack.len() - arity);
+        let text = element.describe_call(&operands.iter().map(|operand| operand.text.clone()).collect::<Vec<String>>());
+        let operand_types: Vec<ValueType> = operands.iter().map(|operand| operand.value_type).collect();
+        let value_type = element.result_type(&operand_types)
+            .map_err(|reason| MyDatabaseError::ClauseTypeError(text.clone(), reason))?;
+
+        let constants: Option<Vec<Cow<Value>>> = match element {
+            ClauseElement::Subquery(_) => None, // subqueries run only when the statement is executed
+            _ => operands.iter().map(|operand| operand.constant.as_ref().map(Cow::Borrowed)).collect(),
+        };
+        let folded = match constants.map(|constants| element.apply(constants)) {
+            Some(Err(_)) if lazy => None,
+            folded => folded,
+        };
+        let operand = match folded {
+            Some(value) => {
+                let value = value.map_err(|e| MyDatabaseError::ConstantEvaluationError(text.clone(), Box::new(e)))?;
+                TypedOperand {
+                    elements: vec![ClauseElement::Constant(value.clone())],
+                    value_type,
+                    constant: Some(value),
+                    text,
+                }
+            },
+            None => {
+                let mut elements: Vec<ClauseElement> = operands.into_iter().flat_map(|operand| operand.elements).collect();
+                elements.push(element);
+                TypedOperand {
+                    elements,
+                    value_type,
+                    constant: None,
+                    text,
+                }
+            },
+        };
+        stack.push(operand);
+    }
+    match (stack.pop(), stack.pop()) {
+        (Some(root), None) => Ok(root),
+        (Some(root), Some(_)) => Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Missing operator before {}", root.text))),
+        (None, _) => Err(MyDatabaseError::InvalidWhereClauseFormat("Empty clause".to_string())),
+    }
+}
+
+/// Conditions have to be Bool and all values of one type, Int values are turned into Float when mixed with Floats.
+/// Branches with a constant false condition are dropped and a constant true one ends the CASE.
+/// A branch may never be taken, so its parts are folded lazily and fail at run time only if it is
+fn check_case(case: CaseExpression, columns: &HashMap<String, ValueType>) -> Result<TypedOperand, MyDatabaseError> {
+    let mut branches = Vec::new();
+    for (condition, value) in case.branches {
+        let condition = check_and_fold_with(condition, columns, true)?;
+        if condition.value_type != ValueType::Bool {
+            return Err(MyDatabaseError::ClauseTypeError(condition.text, format!("WHEN condition has to be Bool, found {}", condition.value_type)));
+        }
+        branches.push((condition, check_and_fold_with(value, columns, true)?));
+    }
+    let otherwise = check_and_fold_with(case.otherwise, columns, true)?;
+
+    let parts: Vec<String> = branches.iter()
+        .map(|(condition, value)| format!("WHEN {} THEN {}", condition.text, value.text))
+        .collect();
+    let text = format!("CASE {} ELSE {} END", parts.join(" "), otherwise.text);
+    let mut value_type = otherwise.value_type;
+    for (_, value) in &branches {
+        value_type = match (value_type, value.value_type) {
+            (left, right) if left == right => left,
+            (ValueType::Int | ValueType::Float, ValueType::Int | ValueType::Float) => ValueType::Float,
+            (left, right) => return Err(MyDatabaseError::ClauseTypeError(text, format!("CASE values have to be of one type, found {} and {}", right, left))),
+        };
+    }
+
+    let mut kept = Vec::new();
+    let mut otherwise = otherwise.promoted_to(value_type);
+    for (condition, value) in branches {
+        match condition.constant {
+            Some(Value::Bool(false)) => continue,
+            Some(Value::Bool(true)) => {
+                otherwise = value.promoted_to(value_type);
+                break;
+            },
+            _ => kept.push((condition.elements, value.promoted_to(value_type).elements)),
+        }
+    }
+    if kept.is_empty() {
+        return Ok(TypedOperand { text, ..otherwise });
+    }
+    Ok(TypedOperand {
+        elements: vec![ClauseElement::Case(CaseExpression { branches: kept, otherwise: otherwise.elements })],
+        value_type,
+        constant: None,
+        text,
+    })
+}
+
+/// Turns tokens into ONP with the shunting yard algorithm, types are checked afterwards.
+/// Subqueries need a parser, without it they are an error
+fn parse_onp(tokens: &[&str], columns: &HashMap<String, ValueType>, database: Option<&dyn SubqueryParser>) -> Result<Vec<ClauseElement>, MyDatabaseError> {
+    let mut elements: Vec<ClauseElement> = Vec::new();
+    let mut depth = 0;
+    // CASTs waiting for their type - index of the element, bracket depth of their arguments and whether AS was seen
+    let mut open_casts: Vec<(usize, usize, bool)> = Vec::new();
+    let mut tokens = tokens.iter().copied().peekable();
+    while let Some(token) = tokens.next() {
+        match token {
+            "(" if tokens.peek().is_some_and(|next| *next == "SELECT" || next.starts_with("SELECT ")) => {
+                elements.push(ClauseElement::Subquery(Subquery::parse(SubqueryKind::Scalar, &mut tokens, database)?));
+            },
+            "IN" | "EXISTS" => {
+                if tokens.next() != Some("(") {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("{} expects a subquery in brackets", token)));
+                }
+                let kind = if token == "IN" { SubqueryKind::In } else { SubqueryKind::Exists };
+                elements.push(ClauseElement::Subquery(Subquery::parse(kind, &mut tokens, database)?));
+            },
+            "(" => {
+                depth += 1;
+                elements.push(ClauseElement::OpeningBracket);
+            },
+            ")" => {
+                if let Some((_, cast_depth, has_type)) = open_casts.last()
+                    && *cast_depth == depth {
+                    if !has_type {
+                        return Err(MyDatabaseError::InvalidWhereClauseFormat("CAST is missing AS <type>".to_string()));
                     }
+                    open_casts.pop();
+                }
+                depth = depth.saturating_sub(1);
+                elements.push(ClauseElement::ClosingBracket);
+            },
+            "," => elements.push(ClauseElement::Comma),
+            "CASE" => elements.push(ClauseElement::Case(CaseExpression::parse(&mut tokens, columns, database)?)),
+            "WHEN" | "THEN" | "ELSE" | "END" => return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("{} outside of CASE", token))),
+            " AS " => {
+                let Some((index, cast_depth, has_type)) = open_casts.last_mut() else {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat("AS outside of CAST".to_string()));
+                };
+                if *cast_depth != depth || *has_type {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat("AS outside of CAST".to_string()));
+                }
+                let Some(to) = tokens.next().and_then(ValueType::from_name) else {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat("CAST expects Bool, String, Int or Float after AS".to_string()));
+                };
+                elements[*index] = ClauseElement::Cast(to);
+                *has_type = true;
+            },
+            "=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::Equal))),
+            "!=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::NotEqual))),
+            ">" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::GreaterThan))),
+            "<" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::LessThan))),
+            ">=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::GreaterThanOrEqual))),
+            "<=" => elements.push(ClauseElement::Operator(AnyOperator::Comparison(ComparisonOperator::LessThanOrEqual))),
+            "AND" => elements.push(ClauseElement::Operator(AnyOperator::Logical(LogicalOperator::And))),
+            "OR" => elements.push(ClauseElement::Operator(AnyOperator::Logical(LogicalOperator::Or))),
+            "+" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Add))),
+            "-" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Subtract))),
+            "*" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Multiply))),
+            "/" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Divide))),
+            "%" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Modulo))),
+            "^" => elements.push(ClauseElement::Operator(AnyOperator::Math(MathOperator::Power))),
+            _ => {
+                let is_call = tokens.peek() == Some(&"(");
+                if is_call && token.eq_ignore_ascii_case("CAST") {
+                    open_casts.push((elements.len(), depth + 1, false));
+                    elements.push(ClauseElement::Cast(ValueType::String)); // the type is set once AS is reached
+                } else if is_call && let Some(function) = functions::find_function(token) {
+                    elements.push(ClauseElement::Function(function, 0));
+                } else if columns.contains_key(token) {
+                    elements.push(ClauseElement::ColumnIdentifier(token.to_string()));
+                } else if let Some(constant) = parse_number(token)? {
+                    elements.push(ClauseElement::Constant(constant));
+                } else if token.eq_ignore_ascii_case("true") {
+                    elements.push(ClauseElement::Constant(Value::Bool(true)));
+                } else if token.eq_ignore_ascii_case("false") {
+                    elements.push(ClauseElement::Constant(Value::Bool(false)));
+                } else if token.starts_with("\"") && token.ends_with("\"") && token.len() >= 2 {
+                    let str_content = &token[1..token.len()-1];
+                    elements.push(ClauseElement::Constant(Value::String(str_content.to_string())));
+                } else if is_call {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Unknown function: {}", token)));
+                } else {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat(format!("Unknown token in WHERE clause: {}", token)));
                 }
             }
         }
+    }
 
-        let mut help_stack = Vec::new();
-        let mut onp_elements = Vec::new();
+    let mut help_stack = Vec::new();
+    let mut onp_elements = Vec::new();
+    // for every open bracket, the number of arguments when it belongs to a function call
+    let mut argument_counts: Vec<Option<usize>> = Vec::new();
+    let mut previous_is_call = false;
+    let mut previous_is_opening = false;
 
-        for element in elements {
-            match element {
-                ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => onp_elements.push(element),
-                ClauseElement::ClosingBracket => {
-                    let mut opening_bracket_found = false;
-                    while let Some(operator) = help_stack.pop() {
-                        if operator != ClauseElement::OpeningBracket {
-                            onp_elements.push(operator);
-                        }
-                        else {
-                            opening_bracket_found = true;
-                            break;
-                        }
+    for element in elements {
+        let is_call = matches!(element, ClauseElement::Function(_, _) | ClauseElement::Cast(_));
+        let is_opening = element == ClauseElement::OpeningBracket;
+        match element {
+            ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) | ClauseElement::Case(_) => onp_elements.push(element),
+            ClauseElement::Function(_, _) | ClauseElement::Cast(_) => help_stack.push(element),
+            ClauseElement::Comma => {
+                while let Some(operator) = help_stack.pop_if(|operator| *operator != ClauseElement::OpeningBracket) {
+                    onp_elements.push(operator);
+                }
+                let Some(Some(count)) = argument_counts.last_mut() else {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat("Comma outside of function arguments".to_string()));
+                };
+                *count += 1;
+            },
+            ClauseElement::ClosingBracket => {
+                let mut opening_bracket_found = false;
+                while let Some(operator) = help_stack.pop() {
+                    if operator != ClauseElement::OpeningBracket {
+                        onp_elements.push(operator);
                     }
-                    if opening_bracket_found != true {
-                        return Err(MyDatabaseError::InvalidWhereClauseFormat("Opening bracket missing".to_string()));
+                    else {
+                        opening_bracket_found = true;
+                        break;
                     }
                 }
-                ClauseElement::OpeningBracket => help_stack.push(element),
-                _ => {
-                    let importance = element.get_importance();
-                    while let Some(operator) = help_stack.last() {
-                        if operator.get_importance() >= importance {
-                            let Some(popped) = help_stack.pop() else {
-                                return Err(MyDatabaseError::InvalidWhereClauseFormat("This error shouldn't happend".to_string()));
-                            };
-                            onp_elements.push(popped);
-                        }
-                        else {
-                            break;
-                        }
+                if !opening_bracket_found {
+                    return Err(MyDatabaseError::InvalidWhereClauseFormat("Opening bracket missing".to_string()));
+                }
+                if let Some(Some(count)) = argument_counts.pop() {
+                    let count = if previous_is_opening { 0 } else { count };
+                    match help_stack.pop() {
+                        Some(ClauseElement::Function(function, _)) => onp_elements.push(ClauseElement::Function(function, count)),
+                        Some(ClauseElement::Cast(to)) if count == 1 => onp_elements.push(ClauseElement::Cast(to)),
+                        Some(ClauseElement::Cast(_)) => return Err(MyDatabaseError::InvalidWhereClauseFormat("CAST takes one value".to_string())),
+                        _ => return Err(MyDatabaseError::InvalidWhereClauseFormat("This error shouldn't happen".to_string())),
+                    }
+                }
+            }
+            ClauseElement::OpeningBracket => {
+                argument_counts.push(if previous_is_call { Some(1) } else { None });
+                help_stack.push(element);
+            },
+            _ => {
+                let importance = element.get_importance();
+                // ^ groups from the right, 2 ^ 3 ^ 2 is 2 ^ 9
+                let is_right_associative = element == ClauseElement::Operator(AnyOperator::Math(MathOperator::Power));
+                while let Some(operator) = help_stack.last() {
+                    if operator.get_importance() > importance || (operator.get_importance() == importance && !is_right_associative) {
+                        let Some(popped) = help_stack.pop() else {
+                            return Err(MyDatabaseError::InvalidWhereClauseFormat("This error shouldn't happend".to_string()));
+                        };
+                        onp_elements.push(popped);
+                    }
+                    else {
+                        break;
                     }
-                    help_stack.push(element);
                 }
+                help_stack.push(element);
             }
         }
-        while let Some(operator) = help_stack.pop() {
-            if operator == ClauseElement::OpeningBracket {
-                return Err(MyDatabaseError::InvalidWhereClauseFormat("Closing bracket missing".to_string()));
+        previous_is_call = is_call;
+        previous_is_opening = is_opening;
+    }
+    while let Some(operator) = help_stack.pop() {
+        if operator == ClauseElement::OpeningBracket {
+            return Err(MyDatabaseError::InvalidWhereClauseFormat("Closing bracket missing".to_string()));
+        }
+        onp_elements.push(operator);
+    }
+    Ok(onp_elements)
+}
+
+/// Operators have to be sorted by length descending, words match only as whole words
+const OPERATORS: [&str; 25] = ["EXISTS", "CASE", "WHEN", "THEN", "ELSE", " AS ", "AND", "END", "!=", ">=", "<=", "OR", "IN", ">", "<", "=", "+", "-", "*", "/", "%", "^", "(", ")", ","];
+
+/// Parses and type checks an expression, returns its folded ONP form with the type of its value
+fn parse_expression(expression: &str, columns: &HashMap<String, ValueType>, database: Option<&dyn SubqueryParser>) -> Result<TypedOperand, MyDatabaseError> {
+    let tokens = split_by_operators_preserving_quotes(expression, &OPERATORS);
+    check_and_fold(parse_onp(&tokens, columns, database)?, columns)
+}
+
+fn evaluate_onp(onp_elements: &[ClauseElement], record: &Record) -> Result<Value, MyDatabaseError> {
+    let mut eval_stack: Vec<Cow<Value>> = Vec::new();
+    for element in onp_elements {
+        match element {
+            ClauseElement::ClosingBracket | ClauseElement::OpeningBracket | ClauseElement::Comma => return Err(MyDatabaseError::WronglyParsedClause("Brackets shouldn't be in ONP".to_string())),
+            ClauseElement::ColumnIdentifier(col_name) => {
+                let Some(value) = record.get_value_for_column(col_name) else {
+                    return Err(MyDatabaseError::WronglyParsedClause(format!("Column {} not found in record", col_name)));
+                };
+                eval_stack.push(value);
+            },
+            ClauseElement::Constant(value) => eval_stack.push(Cow::Borrowed(value)),
+            ClauseElement::Case(case) => eval_stack.push(Cow::Owned(case.evaluate(record)?)),
+            ClauseElement::Operator(_) | ClauseElement::Function(_, _) | ClauseElement::Cast(_) | ClauseElement::Subquery(_) | ClauseElement::InValues(_) => {
+                let arity = element.arity();
+                if eval_stack.len() < arity {
+                    return Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string()));
+                }
+                let operands = eval_stack.split_off(eval_stack.len() - arity);
+                eval_stack.push(Cow::Owned(element.apply(operands)?));
             }
-            onp_elements.push(operator);
         }
-        Ok(WhereClause { onp_elements })
+    }
+    match (eval_stack.pop(), eval_stack.is_empty()) {
+        (Some(final_value), true) => Ok(final_value.into_owned()),
+        _ => Err(MyDatabaseError::WronglyParsedClause("More than one element left on stack after evaluation".to_string())),
+    }
+}
+
+fn build_tree(onp_elements: &[ClauseElement]) -> Result<ExpressionNode<'_>, MyDatabaseError> {
+    let mut stack: Vec<ExpressionNode> = Vec::new();
+    for element in onp_elements {
+        match element {
+            ClauseElement::ClosingBracket | ClauseElement::OpeningBracket | ClauseElement::Comma => return Err(MyDatabaseError::WronglyParsedClause("Brackets shouldn't be in ONP".to_string())),
+            ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => stack.push(ExpressionNode::Leaf(element)),
+            ClauseElement::Case(case) => {
+                let mut parts = Vec::new();
+                for (condition, value) in &case.branches {
+                    parts.push(("WHEN", build_tree(condition)?));
+                    parts.push(("THEN", build_tree(value)?));
+                }
+                parts.push(("ELSE", build_tree(&case.otherwise)?));
+                stack.push(ExpressionNode::Case(parts));
+            },
+            ClauseElement::Operator(op) => {
+                let (Some(right), Some(left)) = (stack.pop(), stack.pop()) else {
+                    return Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string()));
+                };
+                stack.push(ExpressionNode::Operation(op, Box::new(left), Box::new(right)));
+            },
+            ClauseElement::Function(_, _) | ClauseElement::Cast(_) | ClauseElement::Subquery(_) | ClauseElement::InValues(_) => {
+                let arity = element.arity();
+                if stack.len() < arity {
+                    return Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string()));
+                }
+                let args = stack.split_off(stack.len() - arity);
+                stack.push(ExpressionNode::Call(element, args));
+            }
+        }
+    }
+    match (stack.pop(), stack.is_empty()) {
+        (Some(root), true) => Ok(root),
+        _ => Err(MyDatabaseError::WronglyParsedClause("More than one element left on stack after evaluation".to_string())),
+    }
+}
+
+/// Expression computed for every record, e.g. an item of the SELECT list
+#[derive(Debug)]
+pub struct Expression {
+    onp_elements: Vec<ClauseElement>,
+    value_type: ValueType,
+}
+impl Expression {
+    pub fn create_from_string(expression: &str, columns: &HashMap<String, ValueType>) -> Result<Expression, MyDatabaseError> {
+        let root = parse_expression(expression, columns, None)?;
+        Ok(Expression {
+            onp_elements: root.elements,
+            value_type: root.value_type,
+        })
+    }
+    pub fn value_type(&self) -> ValueType {
+        self.value_type
+    }
+    pub fn evaluate_for_record(&self, record: &Record) -> Result<Value, MyDatabaseError> {
+        evaluate_onp(&self.onp_elements, record)
+    }
+}
+
+/// Item of the SELECT list - an expression or a window function with the name of its result column
+#[derive(Debug)]
+pub struct ProjectedColumn {
+    pub name: String,
+    text: String,
+    source: ColumnSource,
+}
+#[derive(Debug)]
+enum ColumnSource {
+    Expression(Expression),
+    Window(WindowColumn),
+}
+impl ProjectedColumn {
+    pub fn column(name: String, value_type: ValueType) -> ProjectedColumn {
+        ProjectedColumn {
+            text: name.clone(),
+            source: ColumnSource::Expression(Expression {
+                onp_elements: vec![ClauseElement::ColumnIdentifier(name.clone())],
+                value_type,
+            }),
+            name,
+        }
+    }
+    /// Parses `<expression> [AS <name>]`, without a name the column is called like the expression
+    pub fn parse(item: &str, columns: &HashMap<String, ValueType>) -> Result<ProjectedColumn, MyDatabaseError> {
+        let (text, name) = match rsplit_once_outside_brackets(item, " AS ") {
+            Some((text, name)) if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '_') => (text, name),
+            Some(_) => return Err(MyDatabaseError::InvalidCommandFormat("SELECT")),
+            None => (item.trim(), item.trim()),
+        };
+        let source = match rsplit_once_outside_brackets(text, " OVER ") {
+            Some((function, window)) => ColumnSource::Window(WindowColumn::parse(function, window, columns)?),
+            None => match Expression::create_from_string(text, columns) {
+                Ok(expression) => ColumnSource::Expression(expression),
+                // a single unknown word is most likely a misspelled column
+                Err(_) if text.chars().all(|c| c.is_alphanumeric() || c == '_') => return Err(MyDatabaseError::InvalidFieldName),
+                Err(e) => return Err(e),
+            },
+        };
+        Ok(ProjectedColumn {
+            name: name.to_string(),
+            text: text.to_string(),
+            source,
+        })
+    }
+    pub fn value_type(&self) -> ValueType {
+        match &self.source {
+            ColumnSource::Expression(expression) => expression.value_type(),
+            ColumnSource::Window(window) => window.value_type(),
+        }
+    }
+    /// Expressions evaluated for every record, finish_rows turns their values into values of the columns
+    pub fn inputs(&self) -> Vec<&Expression> {
+        match &self.source {
+            ColumnSource::Expression(expression) => vec![expression],
+            ColumnSource::Window(window) => window.inputs(),
+        }
+    }
+    /// Computes window columns over all rows of a query, the other columns are their only input
+    pub fn finish_rows(projection: &[ProjectedColumn], rows: Vec<Vec<Value>>) -> Result<Vec<Vec<Value>>, MyDatabaseError> {
+        if projection.iter().all(|column| matches!(column.source, ColumnSource::Expression(_))) {
+            return Ok(rows);
+        }
+        let mut offset = 0;
+        let mut columns = Vec::with_capacity(projection.len());
+        for column in projection {
+            columns.push(match &column.source {
+                ColumnSource::Expression(_) => FinishedColumn::Input(offset),
+                ColumnSource::Window(window) => FinishedColumn::Computed(window.compute(&rows, offset)?),
+            });
+            offset += column.inputs().len();
+        }
+        Ok(rows.iter().enumerate()
+            .map(|(i, row)| columns.iter()
+                .map(|column| match column {
+                    FinishedColumn::Computed(values) => values[i].clone(),
+                    FinishedColumn::Input(offset) => row[*offset].clone(),
+                })
+                .collect())
+            .collect())
+    }
+}
+
+/// Values of a result column - computed for all rows, or taken from the row at an offset of its inputs
+enum FinishedColumn {
+    Computed(Vec<Value>),
+    Input(usize),
+}
+impl fmt::Display for ProjectedColumn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.name == self.text {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{} AS {}", self.text, self.name)
+        }
+    }
+}
+
+/// Typed values of one column for a batch of rows, to evaluate a clause column-at-a-time
+#[derive(Debug)]
+pub enum ColumnBatch<'a> {
+    Bool(Vec<bool>),
+    Int(Vec<i64>),
+    Float(Vec<f64>),
+    String(Vec<&'a str>),
+}
+
+/// Operand of a batch evaluation, a constant stands for every row
+enum BatchOperand<'a> {
+    Column(&'a ColumnBatch<'a>),
+    Constant(&'a Value),
+    Mask(Vec<bool>),
+}
+impl BatchOperand<'_> {
+    /// Value of the operand for one row
+    fn scalar(&self, row: usize) -> ValueRef<'_> {
+        match self {
+            BatchOperand::Column(ColumnBatch::Bool(values)) | BatchOperand::Mask(values) => ValueRef::Bool(values[row]),
+            BatchOperand::Column(ColumnBatch::Int(values)) => ValueRef::Int(values[row]),
+            BatchOperand::Column(ColumnBatch::Float(values)) => ValueRef::Float(values[row]),
+            BatchOperand::Column(ColumnBatch::String(values)) => ValueRef::String(values[row]),
+            BatchOperand::Constant(value) => value.borrowed(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct WhereClause {
+    onp_elements: Vec<ClauseElement>
+}
+impl WhereClause {
+    pub fn create_from_string(clause: String, columns: &HashMap<String, ValueType>) -> Result<WhereClause, MyDatabaseError> {
+        WhereClause::parse(&clause, columns, None)
+    }
+    /// Like create_from_string, but the clause can contain subqueries on tables, parsed by the given parser
+    pub fn create_with_subqueries(clause: String, columns: &HashMap<String, ValueType>, subqueries: &dyn SubqueryParser) -> Result<WhereClause, MyDatabaseError> {
+        WhereClause::parse(&clause, columns, Some(subqueries))
+    }
+    fn parse(clause: &str, columns: &HashMap<String, ValueType>, database: Option<&dyn SubqueryParser>) -> Result<WhereClause, MyDatabaseError> {
+        let root = parse_expression(clause, columns, database)?;
+        if root.value_type != ValueType::Bool {
+            return Err(MyDatabaseError::ClauseTypeError(root.text, format!("clause has to be Bool, found {}", root.value_type)));
+        }
+        Ok(WhereClause { onp_elements: root.elements })
+    }
+
+    pub fn subquery_tables(&self) -> Vec<String> {
+        let mut tables = Vec::new();
+        subquery_tables(&self.onp_elements, &mut tables);
+        tables
+    }
+
+    /// Runs the subqueries once and keeps their results, call before the clause is evaluated
+    pub fn run_subqueries(&mut self, snapshot: &Snapshot) -> Result<(), MyDatabaseError> {
+        run_subqueries(&mut self.onp_elements, snapshot)
     }
 
     pub fn evaluate_for_record(&self, record: &Record) -> Result<bool, MyDatabaseError> {
-        let mut eval_stack: Vec<BoxedOrReferencedElement> = Vec::new();
+        match evaluate_onp(&self.onp_elements, record)? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(MyDatabaseError::WronglyParsedClause("Final element is not a boolean constant".to_string())),
+        }
+    }
+
+    /// Columns the clause reads, when it only compares columns with constants or each other
+    /// and joins comparisons with AND and OR. Other clauses are evaluated row by row
+    pub fn batch_columns(&self) -> Option<Vec<&str>> {
+        let mut columns = Vec::new();
+        for element in &self.onp_elements {
+            match element {
+                ClauseElement::ColumnIdentifier(column) if !columns.contains(&column.as_str()) => columns.push(column.as_str()),
+                ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => {},
+                ClauseElement::Operator(AnyOperator::Comparison(_) | AnyOperator::Logical(_)) => {},
+                _ => return None,
+            }
+        }
+        Some(columns)
+    }
+
+    /// Evaluates the clause for `len` rows one column at a time, `columns` hold values of batch_columns
+    pub fn evaluate_for_columns(&self, columns: &HashMap<&str, ColumnBatch>, len: usize) -> Result<Vec<bool>, MyDatabaseError> {
+        let mut stack: Vec<BatchOperand> = Vec::new();
         for element in &self.onp_elements {
             match element {
-                ClauseElement::ClosingBracket | ClauseElement::OpeningBracket => return Err(MyDatabaseError::WronglyParsedClause("Brackets shouldn't be in ONP".to_string())),
-                ClauseElement::ColumnIdentifier(_) | ClauseElement::Constant(_) => eval_stack.push(BoxedOrReferencedElement::Referenced(element)),
+                ClauseElement::ColumnIdentifier(column) => {
+                    let Some(values) = columns.get(column.as_str()) else {
+                        return Err(MyDatabaseError::WronglyParsedClause(format!("Column {} not found in batch", column)));
+                    };
+                    stack.push(BatchOperand::Column(values));
+                },
+                ClauseElement::Constant(value) => stack.push(BatchOperand::Constant(value)),
                 ClauseElement::Operator(op) => {
-                    let Some(right) = eval_stack.pop() else {
+                    let (Some(right), Some(left)) = (stack.pop(), stack.pop()) else {
                         return Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string()));
                     };
-                    let Some(left) = eval_stack.pop() else {
-                        return Err(MyDatabaseError::WronglyParsedClause("Not enough elements on stack for operation".to_string()));
+                    let mask = match op {
+                        AnyOperator::Comparison(op) => (0..len)
+                            .map(|row| left.scalar(row).compare(right.scalar(row)).map(|ordering| op.holds(ordering)).ok_or(MyDatabaseError::CannotCompareValues))
+                            .collect::<Result<Vec<bool>, MyDatabaseError>>()?,
+                        AnyOperator::Logical(op) => (0..len)
+                            .map(|row| match (left.scalar(row), right.scalar(row), op) {
+                                (ValueRef::Bool(b1), ValueRef::Bool(b2), LogicalOperator::And) => Ok(b1 && b2),
+                                (ValueRef::Bool(b1), ValueRef::Bool(b2), LogicalOperator::Or) => Ok(b1 || b2),
+                                _ => Err(MyDatabaseError::InvalidLogicalOperation),
+                            })
+                            .collect::<Result<Vec<bool>, MyDatabaseError>>()?,
+                        AnyOperator::Math(_) => return Err(MyDatabaseError::WronglyParsedClause("Math can't be evaluated in batches".to_string())),
                     };
+                    stack.push(BatchOperand::Mask(mask));
+                },
+                _ => return Err(MyDatabaseError::WronglyParsedClause(format!("{} can't be evaluated in batches", element))),
+            }
+        }
+        match stack.as_slice() {
+            [result] => (0..len)
+                .map(|row| match result.scalar(row) {
+                    ValueRef::Bool(b) => Ok(b),
+                    _ => Err(MyDatabaseError::WronglyParsedClause("Final element is not a boolean constant".to_string())),
+                })
+                .collect(),
+            _ => Err(MyDatabaseError::WronglyParsedClause("Clause doesn't reduce to one value".to_string())),
+        }
+    }
 
-                    let left_value = match left.get_element_ref() {
-                        ClauseElement::ColumnIdentifier(col_name) => {
-                            let Some(ret) = record.get_value_for_column(col_name) else {
-                                return Err(MyDatabaseError::WronglyParsedClause(format!("Column {} not found in record", col_name)));
-                            };
-                            ret
-                        },
-                        ClauseElement::Constant(c) => {
-                            c
-                        },
-                        _ => return Err(MyDatabaseError::WronglyParsedClause("Expected column identifier or constant".to_string())),
-                    };
+    fn build_tree(&self) -> Result<ExpressionNode<'_>, MyDatabaseError> {
+        build_tree(&self.onp_elements)
+    }
 
-                    let right_value = match right.get_element_ref() {
-                        ClauseElement::ColumnIdentifier(col_name) => {
-                            let Some(ret) = record.get_value_for_column(col_name) else {
-                                return Err(MyDatabaseError::WronglyParsedClause(format!("Column {} not found in record", col_name)));
-                            };
-                            ret
-                        },
-                        ClauseElement::Constant(c) => {
-                            c
-                        },
-                        _ => return Err(MyDatabaseError::WronglyParsedClause("Expected column identifier or constant".to_string())),
-                    };
+    /// Readable form of the clause - bracketed expression followed by its tree, one line per node
+    pub fn explain(&self) -> Result<Vec<String>, MyDatabaseError> {
+        let root = self.build_tree()?;
+        let mut lines = vec![root.infix()];
+        root.write_tree("", true, &mut lines);
+        Ok(lines)
+    }
 
-                    let result = ClauseElement::Constant(op.evaluate(left_value, right_value)?);
-                    eval_stack.push(BoxedOrReferencedElement::Boxed(Box::new(result)));
-                }
+    /// Range of keys the clause limits the query to, None when any key can match
+    pub fn key_range(&self, key_name: &str) -> Option<KeyRange> {
+        let root = self.build_tree().ok()?;
+        let mut conjuncts = Vec::new();
+        root.conjuncts(&mut conjuncts);
+        let mut range = KeyRange {
+            lower: Bound::Unbounded,
+            upper: Bound::Unbounded,
+        };
+        for conjunct in conjuncts {
+            let ExpressionNode::Operation(AnyOperator::Comparison(op), left, right) = conjunct else {
+                continue;
+            };
+            match (left.as_ref(), right.as_ref()) {
+                (ExpressionNode::Leaf(ClauseElement::ColumnIdentifier(column)), ExpressionNode::Leaf(ClauseElement::Constant(value))) if column == key_name => {
+                    range.restrict(op, value);
+                },
+                (ExpressionNode::Leaf(ClauseElement::Constant(value)), ExpressionNode::Leaf(ClauseElement::ColumnIdentifier(column))) if column == key_name => {
+                    range.restrict(&op.flipped(), value);
+                },
+                _ => {},
             }
         }
-        if eval_stack.len() != 1 {
-            // println!("Eval stack: {:?}", eval_stack);
-            return Err(MyDatabaseError::WronglyParsedClause("More than one element left on stack after evaluation".to_string()));
+        if range.lower == Bound::Unbounded && range.upper == Bound::Unbounded {
+            return None;
         }
-        let Some(final_element) = eval_stack.pop() else {
-            return Err(MyDatabaseError::WronglyParsedClause("This error shouldn't happen".to_string()));
+        Some(range)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clause(s: &str) -> WhereClause {
+        let columns = HashMap::from([("id".to_string(), ValueType::Int), ("name".to_string(), ValueType::String)]);
+        WhereClause::create_from_string(s.to_string(), &columns).unwrap()
+    }
+
+    #[test]
+    fn key_range_comes_from_top_level_ands() {
+        let range = clause("id > 3 AND 10 >= id AND id >= 2 AND name = \"x\"").key_range("id").unwrap();
+        assert_eq!(range.lower, Bound::Excluded(Value::Int(3)));
+        assert_eq!(range.upper, Bound::Included(Value::Int(10)));
+        assert_eq!(range.describe("id"), "id > 3 AND id <= 10");
+        assert!(clause("id = 5 AND id < 7").key_range("id").unwrap().is_single_key());
+        assert_eq!(clause("id > 3 OR id < 1").key_range("id"), None);
+        assert_eq!(clause("id + 1 > 3").key_range("id"), None);
+    }
+
+    #[test]
+    fn explain_rebuilds_the_tree() {
+        let lines = clause("(id + 2) * 3 > 10 AND name != \"a b\"").explain().unwrap();
+        assert_eq!(lines, vec![
+            "((((id + 2) * 3) > 10) AND (name != \"a b\"))",
+            "└─ AND",
+            "   ├─ >",
+            "   │  ├─ *",
+            "   │  │  ├─ +",
+            "   │  │  │  ├─ id",
+            "   │  │  │  └─ 2",
+            "   │  │  └─ 3",
+            "   │  └─ 10",
+            "   └─ !=",
+            "      ├─ name",
+            "      └─ \"a b\"",
+        ]);
+    }
+
+    #[test]
+    fn ill_typed_clauses_are_rejected_and_constants_folded() {
+        let columns = HashMap::from([("id".to_string(), ValueType::Int), ("name".to_string(), ValueType::String), ("active".to_string(), ValueType::Bool)]);
+        let error = |s: &str| WhereClause::create_from_string(s.to_string(), &columns).unwrap_err().to_string();
+        assert_eq!(error("id = 1 AND name > 5"), "Type error in '(name > 5)': cannot compare String with Int using >");
+        assert_eq!(error("active + 1 = 2"), "Type error in '(active + 1)': + expects numeric operands, found Bool and Int");
+        assert_eq!(error("id + 1"), "Type error in '(id + 1)': clause has to be Bool, found Int");
+        assert_eq!(error("id = 1 / (2 - 2)"), "Cannot evaluate '(1 / (2 - 2))': Cannot divide by zero");
+
+        let folded = clause("id = (1 + 2) * 2 AND 1 < 2");
+        assert_eq!(folded.explain().unwrap()[0], "((id = 6) AND true)");
+        assert!(folded.key_range("id").unwrap().is_single_key());
+    }
+
+    #[test]
+    fn numeric_literals_keep_their_type() {
+        assert_eq!(clause("id = 7 / 2").explain().unwrap()[0], "(id = 3)");
+        assert_eq!(clause("id = 7 / 2.0 OR id = 1e1").explain().unwrap()[0], "((id = 3.5) OR (id = 10))");
+        assert_eq!(clause("id = 9007199254740993 AND 9007199254740993 > 9007199254740992.0").explain().unwrap()[0], "((id = 9007199254740993) AND true)");
+        assert_eq!(clause("id = 1").key_range("id").unwrap().lower, Bound::Included(Value::Int(1)));
+        let columns = HashMap::from([("id".to_string(), ValueType::Int)]);
+        assert!(WhereClause::create_from_string("id = 99999999999999999999".to_string(), &columns).is_err());
+    }
+
+    #[test]
+    fn integer_overflow_is_an_error_and_nan_is_ordered() {
+        let columns = HashMap::from([("id".to_string(), ValueType::Int)]);
+        let error = |s: &str| WhereClause::create_from_string(s.to_string(), &columns).unwrap_err().to_string();
+        assert_eq!(error("id = 9223372036854775807 + 1"), "Cannot evaluate '(9223372036854775807 + 1)': Integer overflow in 9223372036854775807 + 1");
+        assert_eq!(error("id = 2 ^ 64"), "Cannot evaluate '(2 ^ 64)': Integer overflow in 2 ^ 64");
+        assert_eq!(error("id = 5 % 0"), "Cannot evaluate '(5 % 0)': Cannot divide by zero");
+        assert_eq!(clause("id = 2 ^ 3 ^ 2 + 7 % 3 * 2").explain().unwrap()[0], "(id = 514)");
+        assert_eq!(clause("id = 2.0 ^ (0 - 1)").explain().unwrap()[0], "(id = 0.5)");
+        assert_eq!(clause("id = 1 ^ 5000000000 + (0 - 1) ^ 5000000001 + 0 ^ 5000000000").explain().unwrap()[0], "(id = 0)");
+        assert_eq!(error("id = 2 ^ (0 - 1)"), "Cannot evaluate '(2 ^ (0 - 1))': Negative exponent -1 for an Int power, use a Float base instead");
+
+        // inf - inf is NaN, which equals itself and sorts above infinity
+        let nan = "(1e308 * 10 - 1e308 * 10)";
+        assert_eq!(clause(&format!("{0} = {0} AND {0} > 1e308 * 10 AND id < {0}", nan)).explain().unwrap()[0], "(true AND (id < NaN))");
+    }
+
+    #[test]
+    fn case_branches_are_typed_and_folded() {
+        assert_eq!(clause("CASE WHEN 1 > 2 THEN id = 1 WHEN 2 > 1 THEN id = 2 ELSE false END").explain().unwrap()[0], "(id = 2)");
+        assert_eq!(clause("CASE WHEN id > 1 THEN name ELSE \"x\" END = \"y\"").explain().unwrap()[0], "(CASE WHEN (id > 1) THEN name ELSE \"x\" END = \"y\")");
+
+        let columns = HashMap::from([("id".to_string(), ValueType::Int), ("name".to_string(), ValueType::String)]);
+        let mixed = Expression::create_from_string("CASE WHEN id < 10 THEN 1 WHEN id < 100 THEN 2.5 ELSE id END", &columns).unwrap();
+        assert_eq!(mixed.value_type(), ValueType::Float);
+        // branches that are never taken don't fail, a failing one that is taken fails when it's evaluated
+        assert_eq!(clause("CASE WHEN false THEN 1 / 0 ELSE 1 END = 1").explain().unwrap()[0], "true");
+        assert_eq!(clause("CASE WHEN true THEN 1 WHEN 1 / 0 > 1 THEN 2 ELSE 1 / 0 END = 1").explain().unwrap()[0], "true");
+        let lazy = clause("CASE WHEN id > 1 THEN 1 / 0 ELSE 1 END = 1");
+        let record = HashMap::from([("id".to_string(), Value::Int(1)), ("name".to_string(), Value::String("a".to_string()))]);
+        assert!(lazy.evaluate_for_record(&Record::Named(&record)).unwrap());
+        let record = HashMap::from([("id".to_string(), Value::Int(2)), ("name".to_string(), Value::String("a".to_string()))]);
+        assert!(lazy.evaluate_for_record(&Record::Named(&record)).is_err());
+        assert_eq!(ProjectedColumn::parse("CASE WHEN id > 1 THEN CAST(id AS String) ELSE name END AS label", &columns).unwrap().to_string(),
+            "CASE WHEN id > 1 THEN CAST(id AS String) ELSE name END AS label");
+
+        let error = |s: &str| WhereClause::create_from_string(s.to_string(), &columns).unwrap_err().to_string();
+        assert_eq!(error("CASE WHEN id THEN true ELSE false END"), "Type error in 'id': WHEN condition has to be Bool, found Int");
+        assert_eq!(error("CASE WHEN id > 1 THEN 1 ELSE name END = 1"), "Type error in 'CASE WHEN (id > 1) THEN 1 ELSE name END': CASE values have to be of one type, found Int and String");
+        assert_eq!(error("CASE WHEN id > 1 THEN true END"), "Error parsing where clause: CASE expects WHEN <condition> THEN <value> ... ELSE <value> END");
+        assert_eq!(error("id > 1 ELSE true"), "Error parsing where clause: ELSE outside of CASE");
+    }
+
+    #[test]
+    fn projection_evaluates_only_the_chosen_case_branch() {
+        use crate::models::db_structure::{AnyDatabase, Database};
+        use crate::models::test_utils::run;
+
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE t KEY id FIELDS id: Int, price: Float").unwrap();
+        run(&db, "INSERT id = 0, price = 5.0 INTO t").unwrap();
+        run(&db, "INSERT id = 4, price = 50.0 INTO t").unwrap();
+
+        let result = run(&db, "SELECT id, CASE WHEN id = 0 THEN 0 ELSE 10 / id END AS ratio, CASE WHEN price < 10 THEN \"low\" ELSE \"high\" END AS bucket FROM t WHERE CASE WHEN id > 0 THEN price / id > 1 ELSE true END").unwrap().unwrap();
+        assert_eq!(result.columns(), ["id", "ratio", "bucket"]);
+        assert_eq!(result.column_types(), [ValueType::Int, ValueType::Int, ValueType::String]);
+        assert_eq!(result.rows(), [
+            vec![Value::Int(0), Value::Int(0), Value::String("low".to_string())],
+            vec![Value::Int(4), Value::Int(2), Value::String("high".to_string())],
+        ]);
+    }
+
+    #[test]
+    fn subqueries_run_once_and_filter_by_other_tables() {
+        use crate::models::db_structure::{AnyDatabase, Database};
+        use crate::models::test_utils;
+
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        let run = |input: &str| test_utils::run(&db, input);
+        let ids = |input: &str| -> Vec<Value> {
+            run(input).unwrap().unwrap().into_rows().into_iter().map(|mut row| row.remove(0)).collect()
         };
-        match final_element.get_element_ref() {
-            ClauseElement::Constant(Value::Bool(b)) => Ok(*b),
-            _ => Err(MyDatabaseError::WronglyParsedClause("Final element is not a boolean constant".to_string())),
+        run("CREATE items KEY id FIELDS id: Int, price: Float").unwrap();
+        run("CREATE orders KEY oid FIELDS oid: Int, item: Int, qty: Int").unwrap();
+        for (id, price) in [(1, 5.0), (2, 15.0), (3, 150.0)] {
+            run(&format!("INSERT id = {}, price = {} INTO items", id, price)).unwrap();
+        }
+        for (oid, item, qty) in [(10, 2, 5), (11, 3, 1), (12, 2, 7)] {
+            run(&format!("INSERT oid = {}, item = {}, qty = {} INTO orders", oid, item, qty)).unwrap();
         }
+
+        assert_eq!(ids("SELECT id FROM items WHERE id IN (SELECT item FROM orders WHERE qty > 2)"), [Value::Int(2)]);
+        assert_eq!(ids("SELECT id FROM items WHERE EXISTS (SELECT oid FROM orders WHERE qty > 6) AND id < 3"), [Value::Int(1), Value::Int(2)]);
+        assert_eq!(ids("SELECT id FROM items WHERE EXISTS (SELECT oid FROM orders WHERE qty > 7)"), []);
+        assert_eq!(ids("SELECT id FROM items WHERE id = (SELECT item FROM orders WHERE oid = 11)"), [Value::Int(3)]);
+        assert_eq!(ids("SELECT oid FROM orders WHERE item IN (SELECT id FROM items WHERE price > (SELECT qty FROM orders WHERE oid = 12))"), [Value::Int(10), Value::Int(11), Value::Int(12)]);
+
+        let error = |input: &str| run(input).unwrap_err().to_string();
+        assert_eq!(error("SELECT id FROM items WHERE id = (SELECT item FROM orders)"), "Error executing command: Subquery 'SELECT item FROM orders' has to return exactly one row, returned 3");
+        assert_eq!(error("SELECT id FROM items WHERE price IN (SELECT oid, qty FROM orders)"), "Error parsing command: Type error in '(price IN (SELECT oid, qty FROM orders))': subquery has to return one column, returns 2");
+        let columns = HashMap::from([("id".to_string(), ValueType::Int)]);
+        assert!(matches!(WhereClause::create_from_string("EXISTS (SELECT id FROM items)".to_string(), &columns), Err(MyDatabaseError::SubqueryNotAllowed)));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,211 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Range;
+use crate::db_errors::MyDatabaseError;
+use crate::models::db_structure::{Value, ValueType};
+use crate::models::utilities::{split_at_keywords_outside_brackets, split_outside_brackets};
+use crate::models::where_parsing::Expression;
+
+#[derive(Debug)]
+enum WindowFunction {
+    RowNumber,
+    Rank,
+    Sum(Expression),
+}
+
+/// `<function> OVER (PARTITION BY ... ORDER BY ...)` - a value computed from the rows of the partition of a row
+#[derive(Debug)]
+pub struct WindowColumn {
+    text: String,
+    function: WindowFunction,
+    partition_by: Vec<Expression>,
+    order_by: Vec<(Expression, bool)>, // bool - descending
+}
+impl WindowColumn {
+    /// Parses the function before OVER and the window definition after it
+    pub fn parse(function: &str, window: &str, columns: &HashMap<String, ValueType>) -> Result<WindowColumn, MyDatabaseError> {
+        let text = format!("{} OVER {}", function.trim(), window.trim());
+        let error = |message: &str| MyDatabaseError::InvalidWindowFunction(text.clone(), message.to_string());
+        let (name, argument) = function.trim().strip_suffix(')')
+            .and_then(|call| call.split_once('('))
+            .ok_or_else(|| error("expected ROW_NUMBER(), RANK() or SUM(<expression>)"))?;
+        let function = match (name.trim().to_uppercase().as_str(), argument.trim()) {
+            ("ROW_NUMBER", "") => WindowFunction::RowNumber,
+            ("RANK", "") => WindowFunction::Rank,
+            ("SUM", argument) if !argument.is_empty() => {
+                let argument = Expression::create_from_string(argument, columns)?;
+                if !matches!(argument.value_type(), ValueType::Int | ValueType::Float) {
+                    return Err(error(&format!("SUM needs a numeric argument, found {}", argument.value_type())));
+                }
+                WindowFunction::Sum(argument)
+            }
+            ("ROW_NUMBER" | "RANK", _) => return Err(error("ROW_NUMBER and RANK take no arguments")),
+            _ => return Err(error("expected ROW_NUMBER(), RANK() or SUM(<expression>)")),
+        };
+        let definition = window.trim().strip_prefix('(').and_then(|window| window.strip_suffix(')'))
+            .ok_or_else(|| error("window has to be given in brackets after OVER"))?;
+        let mut partition_by = Vec::new();
+        let mut order_by = Vec::new();
+        for (keyword, part) in split_at_keywords_outside_brackets(definition, &["PARTITION BY", "ORDER BY"]) {
+            let items = || split_outside_brackets(part, ',').into_iter().map(str::trim);
+            match keyword {
+                None if part.trim().is_empty() => {}
+                Some("PARTITION BY") if partition_by.is_empty() && order_by.is_empty() => {
+                    for item in items() {
+                        partition_by.push(Expression::create_from_string(item, columns)?);
+                    }
+                }
+                Some("ORDER BY") if order_by.is_empty() => {
+                    for item in items() {
+                        let (item, descending) = match item.rsplit_once(' ') {
+                            Some((item, order)) if order.eq_ignore_ascii_case("DESC") => (item, true),
+                            Some((item, order)) if order.eq_ignore_ascii_case("ASC") => (item, false),
+                            _ => (item, false),
+                        };
+                        order_by.push((Expression::create_from_string(item, columns)?, descending));
+                    }
+                }
+                _ => return Err(error("expected OVER ([PARTITION BY <expressions>] [ORDER BY <expressions> [ASC|DESC]])")),
+            }
+        }
+        Ok(WindowColumn { text, function, partition_by, order_by })
+    }
+    pub fn value_type(&self) -> ValueType {
+        match &self.function {
+            WindowFunction::RowNumber | WindowFunction::Rank => ValueType::Int,
+            WindowFunction::Sum(argument) => argument.value_type(),
+        }
+    }
+    /// Expressions evaluated for every row: the argument, then partition and order values
+    pub fn inputs(&self) -> Vec<&Expression> {
+        let argument = match &self.function {
+            WindowFunction::Sum(argument) => Some(argument),
+            _ => None,
+        };
+        argument.into_iter()
+            .chain(self.partition_by.iter())
+            .chain(self.order_by.iter().map(|(expression, _)| expression))
+            .collect()
+    }
+    /// Values of the window for all rows, `rows` hold values of the inputs starting at `offset`.
+    /// Rows with equal order values are peers - they share the rank and running sum
+    pub fn compute(&self, rows: &[Vec<Value>], offset: usize) -> Result<Vec<Value>, MyDatabaseError> {
+        let argument = offset;
+        let partition = offset + usize::from(matches!(self.function, WindowFunction::Sum(_)));
+        let partition = partition..partition + self.partition_by.len();
+        let order = partition.end..partition.end + self.order_by.len();
+        let mut indices: Vec<usize> = (0..rows.len()).collect();
+        // stable, so peers stay in the order they were scanned
+        indices.sort_by(|a, b| compare_values(&rows[*a], &rows[*b], &partition, &[])
+            .then_with(|| compare_values(&rows[*a], &rows[*b], &order, &self.order_by)));
+        let mut values = vec![Value::Int(0); rows.len()];
+        let mut start = 0;
+        while start < indices.len() {
+            let same_partition = |index: &usize| compare_values(&rows[indices[start]], &rows[*index], &partition, &[]) == Ordering::Equal;
+            let end = start + indices[start..].iter().take_while(|index| same_partition(index)).count();
+            let mut total = match self.value_type() {
+                ValueType::Float => Value::Float(0.0),
+                _ => Value::Int(0),
+            };
+            let mut peers_start = start;
+            while peers_start < end {
+                let is_peer = |index: &usize| compare_values(&rows[indices[peers_start]], &rows[*index], &order, &self.order_by) == Ordering::Equal;
+                let peers_end = peers_start + indices[peers_start..end].iter().take_while(|index| is_peer(index)).count();
+                if let WindowFunction::Sum(_) = self.function {
+                    for index in &indices[peers_start..peers_end] {
+                        total = self.add(total, &rows[*index][argument])?;
+                    }
+                }
+                for (position, index) in indices[peers_start..peers_end].iter().enumerate() {
+                    values[*index] = match self.function {
+                        WindowFunction::RowNumber => Value::Int((peers_start + position - start + 1) as i64),
+                        WindowFunction::Rank => Value::Int((peers_start - start + 1) as i64),
+                        WindowFunction::Sum(_) => total.clone(),
+                    };
+                }
+                peers_start = peers_end;
+            }
+            start = end;
+        }
+        Ok(values)
+    }
+    fn add(&self, total: Value, value: &Value) -> Result<Value, MyDatabaseError> {
+        match (total, value) {
+            (Value::Int(total), Value::Int(value)) => total.checked_add(*value)
+                .map(Value::Int)
+                .ok_or_else(|| MyDatabaseError::IntegerOverflow(self.text.clone())),
+            (Value::Float(total), Value::Float(value)) => Ok(Value::Float(total + value)),
+            _ => Err(MyDatabaseError::InvalidMathOperation),
+        }
+    }
+}
+
+/// Compares values in `range` of two rows, `order` tells which of them are sorted descending
+fn compare_values(a: &[Value], b: &[Value], range: &Range<usize>, order: &[(Expression, bool)]) -> Ordering {
+    range.clone().enumerate()
+        .map(|(i, column)| {
+            // values of one expression have one type, so they always compare
+            let ordering = a[column].compare(&b[column]).unwrap_or(Ordering::Equal);
+            if order.get(i).is_some_and(|(_, descending)| *descending) { ordering.reverse() } else { ordering }
+        })
+        .find(|ordering| *ordering != Ordering::Equal)
+        .unwrap_or(Ordering::Equal)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::db_structure::{AnyDatabase, Database};
+    use crate::models::test_utils::run;
+
+    fn window(function: &str, definition: &str) -> WindowColumn {
+        let columns = HashMap::from([("g".to_string(), ValueType::Int), ("v".to_string(), ValueType::Int), ("q".to_string(), ValueType::Int)]);
+        WindowColumn::parse(function, definition, &columns).unwrap()
+    }
+    fn ints(values: &[i64]) -> Vec<Vec<Value>> {
+        values.iter().map(|value| vec![Value::Int(*value)]).collect()
+    }
+
+    #[test]
+    fn window_functions_run_over_filtered_partitions() {
+        let db = AnyDatabase::IntDatabase(Database::<i64>::new());
+        run(&db, "CREATE sales KEY id FIELDS id: Int, cat: String, price: Float, qty: Int").unwrap();
+        for (id, cat, price, qty) in [(1, "a", "5.0", 2), (2, "b", "3.0", 1), (3, "a", "5.0", 4), (4, "a", "1.5", 3), (5, "b", "7.0", 6)] {
+            run(&db, &format!("INSERT id = {}, cat = \"{}\", price = {}, qty = {} INTO sales", id, cat, price, qty)).unwrap();
+        }
+        let result = run(&db, "SELECT id, ROW_NUMBER() OVER (PARTITION BY cat ORDER BY price DESC) AS rn, RANK() OVER (PARTITION BY cat ORDER BY price DESC) AS r, SUM(qty) OVER (PARTITION BY cat ORDER BY price) AS running FROM sales WHERE id != 2").unwrap().unwrap();
+        assert_eq!(result.column_types(), [ValueType::Int, ValueType::Int, ValueType::Int, ValueType::Int]);
+        let rows: Vec<Vec<i64>> = result.rows().iter()
+            .map(|row| row.iter().map(|value| match value { Value::Int(i) => *i, _ => panic!("expected Int") }).collect())
+            .collect();
+        assert_eq!(rows, [[1, 1, 1, 9], [3, 2, 1, 9], [4, 3, 3, 3], [5, 1, 1, 6]]);
+        let total = run(&db, "SELECT SUM(price) OVER () AS total FROM sales WHERE cat = \"b\"").unwrap().unwrap();
+        assert_eq!(total.rows(), [vec![Value::Float(10.0)], vec![Value::Float(10.0)]]);
+    }
+
+    #[test]
+    fn peers_share_rank_and_running_sum() {
+        let v = ints(&[1, 3, 3, 2]);
+        assert_eq!(window("RANK()", "(ORDER BY v DESC)").compute(&v, 0).unwrap(), [Value::Int(4), Value::Int(1), Value::Int(1), Value::Int(3)]);
+        // peers are numbered in the order they were scanned
+        assert_eq!(window("ROW_NUMBER()", "(ORDER BY v DESC)").compute(&v, 0).unwrap(), [Value::Int(4), Value::Int(1), Value::Int(2), Value::Int(3)]);
+        let q_and_v: Vec<Vec<Value>> = [(1, 1), (2, 3), (4, 3), (8, 2)].iter().map(|(q, v)| vec![Value::Int(*q), Value::Int(*v)]).collect();
+        assert_eq!(window("SUM(q)", "(ORDER BY v)").compute(&q_and_v, 0).unwrap(), [Value::Int(1), Value::Int(15), Value::Int(15), Value::Int(9)]);
+    }
+
+    #[test]
+    fn window_without_order_by_treats_the_partition_as_peers() {
+        let q_and_g: Vec<Vec<Value>> = [(1, 1), (2, 2), (4, 1), (8, 2)].iter().map(|(q, g)| vec![Value::Int(*q), Value::Int(*g)]).collect();
+        assert_eq!(window("SUM(q)", "(PARTITION BY g)").compute(&q_and_g, 0).unwrap(), [Value::Int(5), Value::Int(10), Value::Int(5), Value::Int(10)]);
+        let no_inputs = vec![Vec::new(); 3];
+        assert_eq!(window("RANK()", "()").compute(&no_inputs, 0).unwrap(), [Value::Int(1), Value::Int(1), Value::Int(1)]);
+        assert_eq!(window("ROW_NUMBER()", "()").compute(&no_inputs, 0).unwrap(), [Value::Int(1), Value::Int(2), Value::Int(3)]);
+    }
+
+    #[test]
+    fn sum_overflow_is_an_error() {
+        let sum = window("SUM(q)", "()");
+        assert!(matches!(sum.compute(&ints(&[i64::MAX, 1]), 0), Err(MyDatabaseError::IntegerOverflow(_))));
+        assert_eq!(sum.compute(&ints(&[i64::MAX, -1]), 0).unwrap(), [Value::Int(i64::MAX - 1), Value::Int(i64::MAX - 1)]);
+    }
+}
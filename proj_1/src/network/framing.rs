@@ -0,0 +1,132 @@
+use std::io::{self, Read, Write};
+
+/// Biggest frame accepted from the other side, protects against bogus length prefixes
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// Bit of the length prefix set on every frame of a message except the last one
+const CONTINUED: u32 = 1 << 31;
+
+/// Sends a frame - its length as big endian u32 followed by the payload
+pub fn write_frame<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too long"));
+    }
+    write_part(stream, payload, false)
+}
+
+/// Sends a payload of any length, longer ones are split into several frames read back by read_message
+pub fn write_message<W: Write>(stream: &mut W, payload: &[u8]) -> io::Result<()> {
+    let mut parts = payload.chunks(MAX_FRAME_LEN).peekable();
+    if parts.peek().is_none() {
+        return write_part(stream, b"", false);
+    }
+    while let Some(part) = parts.next() {
+        write_part(stream, part, parts.peek().is_some())?;
+    }
+    Ok(())
+}
+
+fn write_part<W: Write>(stream: &mut W, payload: &[u8], continued: bool) -> io::Result<()> {
+    let prefix = payload.len() as u32 | if continued { CONTINUED } else { 0 };
+    // one write for the whole frame, so Nagle's algorithm doesn't hold back the payload
+    let mut frame = Vec::with_capacity(4 + payload.len());
+    frame.extend_from_slice(&prefix.to_be_bytes());
+    frame.extend_from_slice(payload);
+    stream.write_all(&frame)?;
+    stream.flush()
+}
+
+/// Reads a single frame. Returns None when the other side closed the connection between frames
+pub fn read_frame<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    match read_part(stream)? {
+        Some((_, true)) => Err(io::Error::new(io::ErrorKind::InvalidData, format!("message longer than {} bytes", MAX_FRAME_LEN))),
+        part => Ok(part.map(|(payload, _)| payload)),
+    }
+}
+
+/// Reads a message sent by write_message, joining its frames
+pub fn read_message<R: Read>(stream: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let Some((mut message, mut continued)) = read_part(stream)? else {
+        return Ok(None);
+    };
+    while continued {
+        let Some((payload, more)) = read_part(stream)? else {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a message"));
+        };
+        message.extend_from_slice(&payload);
+        continued = more;
+    }
+    Ok(Some(message))
+}
+
+/// Payload of a frame and whether the message continues in the next one
+fn read_part<R: Read>(stream: &mut R) -> io::Result<Option<(Vec<u8>, bool)>> {
+    let mut len_buf = [0u8; 4];
+    let mut read = 0;
+    while read < len_buf.len() {
+        match stream.read(&mut len_buf[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed in the middle of a frame length")),
+            Ok(n) => read += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    let prefix = u32::from_be_bytes(len_buf);
+    let len = (prefix & !CONTINUED) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too long", len)));
+    }
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((payload, prefix & CONTINUED != 0)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn frames_survive_a_stream() {
+        let long = "x".repeat(100_000);
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"SELECT * FROM t").unwrap();
+        write_frame(&mut buf, long.as_bytes()).unwrap();
+        write_frame(&mut buf, b"").unwrap();
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_frame(&mut cursor).unwrap().unwrap(), b"SELECT * FROM t");
+        assert_eq!(read_frame(&mut cursor).unwrap().unwrap(), long.as_bytes());
+        assert_eq!(read_frame(&mut cursor).unwrap().unwrap(), b"");
+        assert!(read_frame(&mut cursor).unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_frame_is_an_error() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, b"abcdef").unwrap();
+        buf.truncate(7);
+        assert!(read_frame(&mut Cursor::new(buf)).is_err());
+        assert!(read_frame(&mut Cursor::new(u32::MAX.to_be_bytes().to_vec())).is_err());
+        let truncated_length = read_frame(&mut Cursor::new(vec![0, 0])).unwrap_err();
+        assert_eq!(truncated_length.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn long_messages_take_several_frames() {
+        let long: Vec<u8> = (0..MAX_FRAME_LEN * 2 + 5).map(|i| (i % 251) as u8).collect();
+        let mut buf = Vec::new();
+        write_message(&mut buf, &long).unwrap();
+        write_message(&mut buf, b"").unwrap();
+        assert!(write_frame(&mut Vec::new(), &long).is_err());
+
+        let mut cursor = Cursor::new(buf);
+        assert_eq!(read_message(&mut cursor).unwrap().unwrap(), long);
+        assert_eq!(read_message(&mut cursor).unwrap().unwrap(), b"");
+        assert!(read_message(&mut cursor).unwrap().is_none());
+        // requests have to fit in one frame
+        let mut buf = Vec::new();
+        write_message(&mut buf, &long).unwrap();
+        assert!(read_frame(&mut Cursor::new(buf)).is_err());
+    }
+}
@@ -0,0 +1,347 @@
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::db_errors::MyDatabaseError;
+
+// Every datagram is: kind (u8), request id (u64), sequence number (u32), fragment count (u32), payload.
+// Requests and responses are split into fragments that fit into a single datagram.
+// Resend packets carry the sequence numbers (u32 each) of response fragments the client is missing.
+// After LOGIN every request starts with a "SESSION <token>" line, the session isn't tied to the source address.
+
+/// Datagrams are kept below the usual MTU so they are not fragmented on the IP level
+pub const MAX_DATAGRAM_LEN: usize = 1200;
+const HEADER_LEN: usize = 17;
+pub const MAX_FRAGMENT_PAYLOAD: usize = MAX_DATAGRAM_LEN - HEADER_LEN;
+/// Limits the memory a single message can take, around 19 MB
+pub const MAX_FRAGMENTS: u32 = 16 * 1024;
+/// Requests are commands, much shorter than responses can be, around 75 KB
+pub const MAX_REQUEST_FRAGMENTS: u32 = 64;
+
+fn max_fragments(kind: PacketKind) -> u32 {
+    match kind {
+        PacketKind::Request => MAX_REQUEST_FRAGMENTS,
+        _ => MAX_FRAGMENTS,
+    }
+}
+
+const SESSION_PREFIX: &str = "SESSION ";
+/// Line of a LOGIN response that carries the token of the new session
+pub const SESSION_TOKEN_LINE: &str = "Session token: ";
+
+/// Request text carrying the session token in front of the command
+pub fn attach_session_token(token: &str, command: &str) -> String {
+    format!("{}{}\n{}", SESSION_PREFIX, token, command)
+}
+/// Session token of a request, if it has one, and the command
+pub fn split_session_token(message: &str) -> (Option<&str>, &str) {
+    match message.strip_prefix(SESSION_PREFIX).and_then(|rest| rest.split_once('\n')) {
+        Some((token, command)) => (Some(token.trim()), command),
+        None => (None, message),
+    }
+}
+/// Token given in a LOGIN response
+pub fn find_session_token(response: &str) -> Option<&str> {
+    response.lines().find_map(|line| line.strip_prefix(SESSION_TOKEN_LINE)).map(str::trim)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketKind {
+    Request = 1,
+    Response = 2,
+    Resend = 3,
+}
+impl PacketKind {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(PacketKind::Request),
+            2 => Some(PacketKind::Response),
+            3 => Some(PacketKind::Resend),
+            _ => None,
+        }
+    }
+}
+
+/// Tells apart protocol packets from plain text commands sent e.g. with netcat
+pub fn is_protocol_datagram(buf: &[u8]) -> bool {
+    buf.first().and_then(|b| PacketKind::from_byte(*b)).is_some()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    pub kind: PacketKind,
+    pub request_id: u64,
+    pub seq: u32,
+    pub total: u32,
+    pub payload: Vec<u8>,
+}
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind as u8);
+        buf.extend_from_slice(&self.request_id.to_be_bytes());
+        buf.extend_from_slice(&self.seq.to_be_bytes());
+        buf.extend_from_slice(&self.total.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+    pub fn decode(buf: &[u8]) -> Result<Packet, MyDatabaseError> {
+        if buf.len() < HEADER_LEN {
+            return Err(MyDatabaseError::InvalidPacket("datagram shorter than header".to_string()));
+        }
+        let Some(kind) = PacketKind::from_byte(buf[0]) else {
+            return Err(MyDatabaseError::InvalidPacket(format!("unknown packet kind {}", buf[0])));
+        };
+        let read_u32 = |from: usize| u32::from_be_bytes([buf[from], buf[from + 1], buf[from + 2], buf[from + 3]]);
+        let mut id_bytes = [0u8; 8];
+        id_bytes.copy_from_slice(&buf[1..9]);
+        let packet = Packet {
+            kind,
+            request_id: u64::from_be_bytes(id_bytes),
+            seq: read_u32(9),
+            total: read_u32(13),
+            payload: buf[HEADER_LEN..].to_vec(),
+        };
+        if packet.kind != PacketKind::Resend && (packet.total == 0 || packet.total > max_fragments(packet.kind) || packet.seq >= packet.total) {
+            return Err(MyDatabaseError::InvalidPacket(format!("fragment {} of {} is out of range", packet.seq, packet.total)));
+        }
+        Ok(packet)
+    }
+
+    /// Builds resend packets for the given sequence numbers, as many as needed to fit them all
+    pub fn resend_requests(request_id: u64, missing: &[u32]) -> Vec<Packet> {
+        missing.chunks(MAX_FRAGMENT_PAYLOAD / 4)
+            .map(|chunk| Packet {
+                kind: PacketKind::Resend,
+                request_id,
+                seq: 0,
+                total: 0,
+                payload: chunk.iter().flat_map(|seq| seq.to_be_bytes()).collect(),
+            })
+            .collect()
+    }
+    pub fn missing_sequence_numbers(&self) -> Vec<u32> {
+        self.payload.chunks_exact(4)
+            .map(|c| u32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+            .collect()
+    }
+}
+
+/// Splits the message into numbered fragments, an empty message still takes one fragment
+pub fn fragment(kind: PacketKind, request_id: u64, data: &[u8]) -> Result<Vec<Packet>, MyDatabaseError> {
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![data]
+    } else {
+        data.chunks(MAX_FRAGMENT_PAYLOAD).collect()
+    };
+    if chunks.len() > max_fragments(kind) as usize {
+        return Err(MyDatabaseError::InvalidPacket(format!("message of {} bytes is too long", data.len())));
+    }
+    let total = chunks.len() as u32;
+    Ok(chunks.into_iter().enumerate()
+        .map(|(seq, chunk)| Packet {
+            kind,
+            request_id,
+            seq: seq as u32,
+            total,
+            payload: chunk.to_vec(),
+        })
+        .collect())
+}
+
+/// Collects fragments of one message, duplicates are ignored.
+/// Only received fragments take memory, the announced total doesn't
+#[derive(Debug)]
+pub struct Reassembler {
+    fragments: BTreeMap<u32, Vec<u8>>, // by sequence number
+    total: u32,
+    bytes: usize, // payload received so far
+}
+impl Reassembler {
+    pub fn new(total: u32) -> Self {
+        Reassembler {
+            fragments: BTreeMap::new(),
+            total,
+            bytes: 0,
+        }
+    }
+    /// Returns true once all fragments are there
+    pub fn add(&mut self, packet: Packet) -> bool {
+        if packet.total == self.total && packet.seq < self.total
+            && let Entry::Vacant(entry) = self.fragments.entry(packet.seq) {
+            self.bytes += packet.payload.len();
+            entry.insert(packet.payload);
+        }
+        self.is_complete()
+    }
+    /// Bytes of the fragments received so far
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+    pub fn is_complete(&self) -> bool {
+        self.fragments.len() == self.total as usize
+    }
+    pub fn missing(&self) -> Vec<u32> {
+        (0..self.total).filter(|seq| !self.fragments.contains_key(seq)).collect()
+    }
+    pub fn assemble(self) -> Vec<u8> {
+        self.fragments.into_values().flatten().collect()
+    }
+}
+
+/// Client side of the protocol - sends a command and waits for the whole response,
+/// asking for missing fragments and repeating the request (under the same id) when nothing arrives.
+/// The session token from a LOGIN response is sent with every later request
+#[derive(Debug)]
+pub struct UdpRequester {
+    socket: UdpSocket,
+    server: SocketAddr,
+    next_request_id: u64,
+    retries: u32,
+    session_token: Option<String>,
+}
+impl UdpRequester {
+    pub fn new(socket: UdpSocket, server: SocketAddr, timeout: Duration, retries: u32) -> Result<Self, MyDatabaseError> {
+        socket.set_read_timeout(Some(timeout))?;
+        // ids start from the clock, so a restarted client on the same port doesn't get old cached responses
+        let next_request_id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(1);
+        Ok(UdpRequester {
+            socket,
+            server,
+            next_request_id,
+            retries,
+            session_token: None,
+        })
+    }
+
+    pub fn request(&mut self, command: &str) -> Result<String, MyDatabaseError> {
+        let message = match &self.session_token {
+            Some(token) => attach_session_token(token, command),
+            None => command.to_string(),
+        };
+        let response = self.send(&message)?;
+        if let Some(token) = find_session_token(&response) {
+            self.session_token = Some(token.to_string());
+        } else if command.trim() == "LOGOUT" {
+            self.session_token = None;
+        }
+        Ok(response)
+    }
+
+    fn send(&mut self, command: &str) -> Result<String, MyDatabaseError> {
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let request_packets: Vec<Vec<u8>> = fragment(PacketKind::Request, request_id, command.as_bytes())?
+            .iter().map(Packet::encode).collect();
+        for datagram in &request_packets {
+            self.socket.send_to(datagram, self.server)?;
+        }
+
+        let mut response: Option<Reassembler> = None;
+        let mut failed_attempts = 0;
+        let mut buf = vec![0u8; 65536];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((len, src)) => {
+                    if src != self.server {
+                        continue;
+                    }
+                    let Ok(packet) = Packet::decode(&buf[..len]) else {
+                        continue;
+                    };
+                    if packet.kind != PacketKind::Response || packet.request_id != request_id {
+                        continue; // late answer to an earlier request
+                    }
+                    let reassembler = response.get_or_insert_with(|| Reassembler::new(packet.total));
+                    if reassembler.add(packet) {
+                        let Some(complete) = response.take() else {
+                            continue;
+                        };
+                        return Ok(String::from_utf8_lossy(&complete.assemble()).into_owned());
+                    }
+                },
+                Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                    failed_attempts += 1;
+                    if failed_attempts > self.retries {
+                        return Err(MyDatabaseError::RequestTimedOut(request_id));
+                    }
+                    match &response {
+                        // the server caches responses by id, so repeating the request doesn't execute it again
+                        None => for datagram in &request_packets {
+                            self.socket.send_to(datagram, self.server)?;
+                        },
+                        Some(reassembler) => for packet in Packet::resend_requests(request_id, &reassembler.missing()) {
+                            self.socket.send_to(&packet.encode(), self.server)?;
+                        },
+                    }
+                },
+                Err(e) => return Err(MyDatabaseError::IoError(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragments_reassemble_in_any_order() {
+        let data: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let mut packets = fragment(PacketKind::Response, 7, &data).unwrap();
+        assert_eq!(packets.len(), 5000usize.div_ceil(MAX_FRAGMENT_PAYLOAD));
+        assert!(packets.iter().all(|p| p.encode().len() <= MAX_DATAGRAM_LEN));
+        packets.reverse();
+
+        let mut reassembler = Reassembler::new(packets[0].total);
+        let first = packets.remove(0);
+        assert!(!reassembler.add(Packet::decode(&first.encode()).unwrap()));
+        assert!(!reassembler.add(first)); // duplicate doesn't count twice
+        assert_eq!(reassembler.bytes(), 5000 % MAX_FRAGMENT_PAYLOAD);
+        assert_eq!(reassembler.missing(), (0..packets.len() as u32).collect::<Vec<u32>>());
+        let mut complete = false;
+        for packet in packets {
+            complete = reassembler.add(packet);
+        }
+        assert!(complete);
+        assert_eq!(reassembler.bytes(), data.len());
+        assert_eq!(reassembler.assemble(), data);
+
+        // requests may take far fewer fragments than responses
+        let long = vec![b'x'; MAX_FRAGMENT_PAYLOAD * MAX_REQUEST_FRAGMENTS as usize + 1];
+        assert!(fragment(PacketKind::Request, 8, &long).is_err());
+        let mut packet = fragment(PacketKind::Response, 8, &long).unwrap().remove(0);
+        assert!(Packet::decode(&packet.encode()).is_ok());
+        packet.kind = PacketKind::Request;
+        assert!(Packet::decode(&packet.encode()).is_err());
+    }
+
+    #[test]
+    fn resend_requests_round_trip() {
+        let missing: Vec<u32> = (0..1000).collect();
+        let packets = Packet::resend_requests(3, &missing);
+        assert!(packets.len() > 1);
+        let decoded: Vec<u32> = packets.iter()
+            .flat_map(|p| Packet::decode(&p.encode()).unwrap().missing_sequence_numbers())
+            .collect();
+        assert_eq!(decoded, missing);
+    }
+
+    #[test]
+    fn session_tokens_travel_in_front_of_commands() {
+        let message = attach_session_token("abc", "SELECT * FROM t\nWHERE id = 1");
+        assert_eq!(split_session_token(&message), (Some("abc"), "SELECT * FROM t\nWHERE id = 1"));
+        assert_eq!(split_session_token("LOGIN admin pw"), (None, "LOGIN admin pw"));
+        assert_eq!(find_session_token(&format!("Logged in as admin\n{}abc\n", SESSION_TOKEN_LINE)), Some("abc"));
+    }
+
+    #[test]
+    fn plain_text_is_not_a_packet() {
+        assert!(!is_protocol_datagram(b"SELECT * FROM t"));
+        assert!(Packet::decode(&[1, 0, 0]).is_err());
+        let mut bad = fragment(PacketKind::Request, 1, b"x").unwrap()[0].encode();
+        bad[12] = 5; // sequence number past the fragment count
+        assert!(Packet::decode(&bad).is_err());
+    }
+}